@@ -1,10 +1,93 @@
+use crate::completion::Helper;
+use crate::config::Format;
+use crate::connection::{self, Mode};
+use crate::dsp::{Ducking, Filter, Protection};
+use crate::patchbay::Patchbay;
+use crate::processor::ProcessorConfig;
 use crate::Action;
+use crate::{ConnectOptions, GateOptions};
 
 use anyhow::{anyhow, Result};
-use clap::Arg;
+use clap::{Arg, ArgAction};
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::Editor;
 
 use std::ffi::OsString;
-use std::io::Write;
+use std::path::PathBuf;
+
+/// Simple routing between audio devices
+#[derive(clap::Parser)]
+#[command(name = "patchbay", version)]
+pub struct Args {
+    /// Host used for new connections [default: system default host]
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+
+    /// Path to the configuration file loaded at startup
+    #[arg(short, long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Configuration file format (json, toml, yaml, routes) [default: from file extension]
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub format: Option<Format>,
+
+    /// Save the configuration file after every command that changes the routing
+    #[arg(long, global = true, requires = "config")]
+    pub autosave: bool,
+
+    /// Ring buffer latency of new connections in milliseconds
+    #[arg(long, global = true, value_name = "MS")]
+    pub latency: Option<u64>,
+
+    /// Log level (off, error, warn, info, debug, trace)
+    #[arg(long, global = true, value_name = "LEVEL", default_value = "warn")]
+    pub log_level: log::LevelFilter,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, clap::Subcommand)]
+pub enum Command {
+    /// Run interactively (default)
+    Run {
+        /// Run full-screen terminal UI instead of the command prompt
+        #[arg(short, long)]
+        tui: bool,
+
+        /// Execute commands from a script file before starting
+        #[arg(long, value_name = "PATH")]
+        script: Option<PathBuf>,
+    },
+    /// Run non-interactively, accepting commands from `patchbay ctl`
+    Daemon {
+        /// Execute commands from a script file before starting
+        #[arg(long, value_name = "PATH")]
+        script: Option<PathBuf>,
+    },
+    /// List hosts and devices available on system
+    List {
+        /// Show every supported stream configuration of each device
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Send a command to the running daemon
+    Ctl {
+        /// Interactive command and its arguments, e.g. `connect mic 0 phones 1`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Check a configuration file without opening audio streams
+    Validate {
+        /// Path to the configuration file
+        path: PathBuf,
+    },
+}
 
 pub struct Parser {
     command: clap::Command,
@@ -35,13 +118,20 @@ impl Parser {
                 .subcommand(
                     clap::Command::new("list")
                         .alias("ls")
+                        .arg(
+                            Arg::new("verbose")
+                                .short('v')
+                                .long("verbose")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(Arg::new("json").long("json").action(ArgAction::SetTrue))
                         .about("List hosts and devices available on system.")
                         .help_template(CMD_TEMPLATE),
                 )
                 .subcommand(
                     clap::Command::new("host")
                         .arg(Arg::new("name").required(true))
-                        .about("Select host.")
+                        .about("Select default host for new connections.")
                         .help_template(CMD_TEMPLATE),
                 )
                 .subcommand(
@@ -53,7 +143,22 @@ impl Parser {
                         .arg(Arg::new("source channel").required(true))
                         .arg(Arg::new("sink name").required(true))
                         .arg(Arg::new("sink channel").required(true))
-                        .about("Create connection between two channels on a source device and a sink device.")
+                        .arg(
+                            Arg::new("pan")
+                                .long("pan")
+                                .allow_hyphen_values(true)
+                                .conflicts_with("sum"),
+                        )
+                        .arg(Arg::new("law").long("law").requires("pan"))
+                        .arg(Arg::new("sum").long("sum").action(ArgAction::SetTrue))
+                        .arg(
+                            Arg::new("link")
+                                .long("link")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with_all(["pan", "sum"]),
+                        )
+                        .arg(Arg::new("force").long("force").action(ArgAction::SetTrue))
+                        .about("Create connection between two channels on a source device and a sink device (--pan to pan across two sink channels, --sum to sum two source channels, --link to link two JACK ports directly, --force to allow feedback loops).")
                         .help_template(CMD_TEMPLATE),
                 )
                 .subcommand(
@@ -63,12 +168,166 @@ impl Parser {
                         .about("Delete connection.")
                         .help_template(CMD_TEMPLATE),
                 )
+                .subcommand(
+                    clap::Command::new("pan")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("position").required(true).allow_hyphen_values(true))
+                        .about("Set position of connection in pan mode from -1 (left) to 1 (right).")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("delay")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("delay").required(true))
+                        .about("Set delay of connection in milliseconds (e.g. 12.5 or 12.5ms) or samples (e.g. 600smp).")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("filter")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("kind").required(true))
+                        .arg(Arg::new("frequency").required(true))
+                        .arg(Arg::new("q").short('q').long("q"))
+                        .arg(
+                            Arg::new("gain")
+                                .short('g')
+                                .long("gain")
+                                .allow_hyphen_values(true),
+                        )
+                        .about("Add filter (highpass, lowpass, lowshelf, highshelf, peaking) to connection.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("unfilter")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("index"))
+                        .about("Remove filter of connection by position, or all filters.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("gate")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("threshold").long("threshold").allow_hyphen_values(true))
+                        .arg(Arg::new("hysteresis").long("hysteresis"))
+                        .arg(Arg::new("attack").long("attack"))
+                        .arg(Arg::new("hold").long("hold"))
+                        .arg(Arg::new("release").long("release"))
+                        .about("Add noise gate to connection or change its --threshold, --hysteresis, --attack, --hold and --release.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("ungate")
+                        .arg(Arg::new("id").required(true))
+                        .about("Remove noise gate of connection.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("insert")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("processor").required(true))
+                        .arg(Arg::new("params").num_args(0..).allow_hyphen_values(true))
+                        .about("Add processor (e.g. gain, delay, filter, limiter) with <param>=<value> parameters to connection.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("uninsert")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("index"))
+                        .about("Remove processor of connection by position, or all processors.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("duck")
+                        .arg(Arg::new("id").required(true))
+                        .arg(Arg::new("trigger").required(true))
+                        .arg(Arg::new("threshold").long("threshold").allow_hyphen_values(true))
+                        .arg(Arg::new("depth").long("depth"))
+                        .arg(Arg::new("attack").long("attack"))
+                        .arg(Arg::new("release").long("release"))
+                        .about("Duck connection by --depth dB while the source of the trigger connection is above --threshold, with --attack and --release times.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("unduck")
+                        .arg(Arg::new("id").required(true))
+                        .about("Stop ducking connection.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("protect")
+                        .arg(Arg::new("sink name").required(true))
+                        .arg(Arg::new("sink channel").required(true))
+                        .arg(Arg::new("ceiling").long("ceiling").allow_hyphen_values(true))
+                        .arg(Arg::new("trip").long("trip").allow_hyphen_values(true))
+                        .arg(Arg::new("trip time").long("trip-time"))
+                        .about("Protect sink channel with a limiter (--ceiling) and mute it when too loud for too long (--trip, --trip-time).")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("unprotect")
+                        .arg(Arg::new("sink name").required(true))
+                        .arg(Arg::new("sink channel").required(true))
+                        .about("Remove protection of sink channel.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("rearm")
+                        .arg(Arg::new("id"))
+                        .about(
+                            "Unmute sink channel of connection muted by its protection, or all \
+                             connections.",
+                        )
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("alias")
+                        .arg(Arg::new("name"))
+                        .arg(Arg::new("device"))
+                        .about("Define a device alias, or list aliases without arguments.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("unalias")
+                        .arg(Arg::new("name").required(true))
+                        .about("Delete device alias.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("virtual")
+                        .arg(Arg::new("name"))
+                        .arg(Arg::new("host").long("host"))
+                        .about("Add a virtual device backed by a free loopback device, or list virtual devices without arguments.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("unvirtual")
+                        .arg(Arg::new("name").required(true))
+                        .about("Delete virtual device.")
+                        .help_template(CMD_TEMPLATE),
+                )
                 .subcommand(
                     clap::Command::new("print")
                         .alias("p")
                         .about("Print patchbay state.")
                         .help_template(CMD_TEMPLATE),
                 )
+                .subcommand(
+                    clap::Command::new("matrix")
+                        .alias("m")
+                        .about("Print routing matrix of source channels against sink channels.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("undo")
+                        .alias("u")
+                        .about("Revert last routing change.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("redo")
+                        .about("Reapply last reverted routing change.")
+                        .help_template(CMD_TEMPLATE),
+                )
                 .subcommand(
                     clap::Command::new("start")
                         .about("Start audio loop.")
@@ -82,13 +341,28 @@ impl Parser {
                 .subcommand(
                     clap::Command::new("save")
                         .arg(Arg::new("path").required(true))
-                        .about("Save patchbay state to JSON configuration file.")
+                        .arg(Arg::new("format").long("format"))
+                        .about("Save patchbay state to configuration file (JSON, TOML, YAML or route list).")
                         .help_template(CMD_TEMPLATE),
                 )
                 .subcommand(
                     clap::Command::new("load")
                         .arg(Arg::new("path").required(true))
-                        .about("Load patchbay state from JSON configuration file.")
+                        .arg(Arg::new("format").long("format"))
+                        .about("Load patchbay state from configuration file (JSON, TOML, YAML or route list).")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("validate")
+                        .arg(Arg::new("path").required(true))
+                        .arg(Arg::new("format").long("format"))
+                        .about("Check configuration file without opening audio streams.")
+                        .help_template(CMD_TEMPLATE),
+                )
+                .subcommand(
+                    clap::Command::new("source")
+                        .arg(Arg::new("path").required(true))
+                        .about("Execute commands from a script file.")
                         .help_template(CMD_TEMPLATE),
                 )
                 .subcommand(
@@ -101,6 +375,10 @@ impl Parser {
         }
     }
 
+    pub fn command(&self) -> &clap::Command {
+        &self.command
+    }
+
     pub fn parse<I, T>(&mut self, tokens: I) -> Result<Action>
     where
         I: IntoIterator<Item = T>,
@@ -108,7 +386,10 @@ impl Parser {
     {
         let matches = self.command.try_get_matches_from_mut(tokens)?;
         match matches.subcommand() {
-            Some(("list", _)) => Ok(Action::List),
+            Some(("list", sub_matches)) => Ok(Action::List {
+                verbose: sub_matches.get_flag("verbose"),
+                json: sub_matches.get_flag("json"),
+            }),
             Some(("host", sub_matches)) => Ok(Action::Host(
                 sub_matches
                     .get_one::<String>("name")
@@ -134,6 +415,7 @@ impl Parser {
                     .ok_or(anyhow!("Sink channel missing"))?
                     .to_owned()
                     .parse()?,
+                connect_options(sub_matches)?,
             )),
             Some(("disconnect", sub_matches)) => Ok(Action::Disconnect(
                 sub_matches
@@ -141,7 +423,153 @@ impl Parser {
                     .ok_or(anyhow!("Connection id missing"))?
                     .to_owned(),
             )),
+            Some(("pan", sub_matches)) => Ok(Action::Pan(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                sub_matches
+                    .get_one::<String>("position")
+                    .ok_or(anyhow!("Pan position missing"))?
+                    .parse()?,
+            )),
+            Some(("delay", sub_matches)) => Ok(Action::Delay(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                parse_delay(
+                    sub_matches
+                        .get_one::<String>("delay")
+                        .ok_or(anyhow!("Delay missing"))?,
+                )?,
+            )),
+            Some(("filter", sub_matches)) => Ok(Action::Filter(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                filter(sub_matches)?,
+            )),
+            Some(("unfilter", sub_matches)) => Ok(Action::Unfilter(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                sub_matches
+                    .get_one::<String>("index")
+                    .map(|index| index.parse())
+                    .transpose()?,
+            )),
+            Some(("gate", sub_matches)) => Ok(Action::Gate(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                gate_options(sub_matches)?,
+            )),
+            Some(("ungate", sub_matches)) => Ok(Action::Ungate(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+            )),
+            Some(("insert", sub_matches)) => Ok(Action::Insert(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                processor(sub_matches)?,
+            )),
+            Some(("uninsert", sub_matches)) => Ok(Action::Uninsert(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                sub_matches
+                    .get_one::<String>("index")
+                    .map(|index| index.parse())
+                    .transpose()?,
+            )),
+            Some(("duck", sub_matches)) => Ok(Action::Duck(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+                sub_matches
+                    .get_one::<String>("trigger")
+                    .ok_or(anyhow!("Trigger connection id missing"))?
+                    .to_owned(),
+                ducking(sub_matches)?,
+            )),
+            Some(("unduck", sub_matches)) => Ok(Action::Unduck(
+                sub_matches
+                    .get_one::<String>("id")
+                    .ok_or(anyhow!("Connection id missing"))?
+                    .to_owned(),
+            )),
+            Some(("protect", sub_matches)) => Ok(Action::Protect(
+                sub_matches
+                    .get_one::<String>("sink name")
+                    .ok_or(anyhow!("Sink name missing"))?
+                    .to_owned(),
+                sub_matches
+                    .get_one::<String>("sink channel")
+                    .ok_or(anyhow!("Sink channel missing"))?
+                    .parse()?,
+                protection(sub_matches)?,
+            )),
+            Some(("unprotect", sub_matches)) => Ok(Action::Unprotect(
+                sub_matches
+                    .get_one::<String>("sink name")
+                    .ok_or(anyhow!("Sink name missing"))?
+                    .to_owned(),
+                sub_matches
+                    .get_one::<String>("sink channel")
+                    .ok_or(anyhow!("Sink channel missing"))?
+                    .parse()?,
+            )),
+            Some(("rearm", sub_matches)) => {
+                Ok(Action::Rearm(sub_matches.get_one::<String>("id").cloned()))
+            }
+            Some(("alias", sub_matches)) => {
+                match (
+                    sub_matches.get_one::<String>("name"),
+                    sub_matches.get_one::<String>("device"),
+                ) {
+                    (None, _) => Ok(Action::Aliases),
+                    (Some(_), None) => Err(anyhow!("Aliased device missing")),
+                    (Some(name), Some(device)) => {
+                        Ok(Action::Alias(name.to_owned(), device.to_owned()))
+                    }
+                }
+            }
+            Some(("unalias", sub_matches)) => Ok(Action::Unalias(
+                sub_matches
+                    .get_one::<String>("name")
+                    .ok_or(anyhow!("Alias name missing"))?
+                    .to_owned(),
+            )),
+            Some(("virtual", sub_matches)) => match sub_matches.get_one::<String>("name") {
+                None if sub_matches.contains_id("host") => {
+                    Err(anyhow!("Virtual device name missing"))
+                }
+                None => Ok(Action::VirtualDevices),
+                Some(name) => Ok(Action::Virtual(
+                    name.to_owned(),
+                    sub_matches.get_one::<String>("host").cloned(),
+                )),
+            },
+            Some(("unvirtual", sub_matches)) => Ok(Action::Unvirtual(
+                sub_matches
+                    .get_one::<String>("name")
+                    .ok_or(anyhow!("Virtual device name missing"))?
+                    .to_owned(),
+            )),
             Some(("print", _)) => Ok(Action::Print),
+            Some(("matrix", _)) => Ok(Action::Matrix),
+            Some(("undo", _)) => Ok(Action::Undo),
+            Some(("redo", _)) => Ok(Action::Redo),
             Some(("start", _)) => Ok(Action::Start),
             Some(("stop", _)) => Ok(Action::Stop),
             Some(("save", sub_matches)) => Ok(Action::Save(
@@ -149,12 +577,27 @@ impl Parser {
                     .get_one::<String>("path")
                     .ok_or(anyhow!("Save file path missing"))?
                     .to_owned(),
+                format(sub_matches)?,
             )),
             Some(("load", sub_matches)) => Ok(Action::Load(
                 sub_matches
                     .get_one::<String>("path")
                     .ok_or(anyhow!("Load file path missing"))?
                     .to_owned(),
+                format(sub_matches)?,
+            )),
+            Some(("validate", sub_matches)) => Ok(Action::Validate(
+                sub_matches
+                    .get_one::<String>("path")
+                    .ok_or(anyhow!("Configuration file path missing"))?
+                    .to_owned(),
+                format(sub_matches)?,
+            )),
+            Some(("source", sub_matches)) => Ok(Action::Source(
+                sub_matches
+                    .get_one::<String>("path")
+                    .ok_or(anyhow!("Script file path missing"))?
+                    .to_owned(),
             )),
             Some(("quit", _)) => Ok(Action::Quit),
             _ => panic!(),
@@ -162,19 +605,221 @@ impl Parser {
     }
 }
 
-pub fn prompt(
-    prefix: &str,
-    stdin: &std::io::Stdin,
-    stdout: &mut std::io::Stdout,
-) -> Result<String> {
-    // TODO: handle arrow keys
-    // TODO: provide history functionality
-    // need to used termion
-    let mut buf = String::new();
-    print!("{}", prefix);
-    stdout.flush()?;
-    stdin.read_line(&mut buf)?;
-    Ok(buf.trim().to_string())
+/// Parse a delay in milliseconds, or in samples with the `smp` suffix.
+fn parse_delay(s: &str) -> Result<f32> {
+    let invalid = || anyhow!("Invalid delay '{}'", s);
+    match s.strip_suffix("smp") {
+        Some(samples) => Ok(connection::samples_to_ms(
+            samples.parse().map_err(|_| invalid())?,
+        )),
+        None => s
+            .strip_suffix("ms")
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| invalid()),
+    }
+}
+
+fn connect_options(matches: &clap::ArgMatches) -> Result<ConnectOptions> {
+    let mut options = ConnectOptions {
+        force: matches.get_flag("force"),
+        ..ConnectOptions::default()
+    };
+    if let Some(pan) = matches.get_one::<String>("pan") {
+        options.mode = Mode::Pan;
+        options.pan = pan.parse().map_err(|_| anyhow!("Invalid pan '{}'", pan))?;
+    }
+    if let Some(law) = matches.get_one::<String>("law") {
+        options.pan_law = law.parse()?;
+    }
+    if matches.get_flag("sum") {
+        options.mode = Mode::Sum;
+    }
+    if matches.get_flag("link") {
+        options.mode = Mode::Link;
+    }
+    Ok(options)
+}
+
+fn filter(matches: &clap::ArgMatches) -> Result<Filter> {
+    let frequency = matches
+        .get_one::<String>("frequency")
+        .ok_or(anyhow!("Filter frequency missing"))?;
+    let mut filter = Filter::new(
+        matches
+            .get_one::<String>("kind")
+            .ok_or(anyhow!("Filter kind missing"))?
+            .parse()?,
+        frequency
+            .strip_suffix("Hz")
+            .unwrap_or(frequency)
+            .parse()
+            .map_err(|_| anyhow!("Invalid frequency '{}'", frequency))?,
+    );
+    if let Some(q) = matches.get_one::<String>("q") {
+        filter.q = q.parse().map_err(|_| anyhow!("Invalid Q '{}'", q))?;
+    }
+    if let Some(gain) = matches.get_one::<String>("gain") {
+        filter.gain_db = gain
+            .strip_suffix("dB")
+            .unwrap_or(gain)
+            .parse()
+            .map_err(|_| anyhow!("Invalid gain '{}'", gain))?;
+    }
+    Ok(filter)
+}
+
+fn processor(matches: &clap::ArgMatches) -> Result<ProcessorConfig> {
+    let name = matches
+        .get_one::<String>("processor")
+        .ok_or(anyhow!("Processor name missing"))?;
+    matches
+        .get_many::<String>("params")
+        .into_iter()
+        .flatten()
+        .try_fold(ProcessorConfig::new(name), |processor, param| {
+            let (name, value) = param
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected '<param>=<value>', found '{}'", param))?;
+            Ok(processor.with_param(name, value))
+        })
+}
+
+fn protection(matches: &clap::ArgMatches) -> Result<Protection> {
+    let number = |name: &str, suffix: &str| -> Result<Option<f32>> {
+        matches
+            .get_one::<String>(name)
+            .map(|value| {
+                value
+                    .strip_suffix(suffix)
+                    .unwrap_or(value)
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid {} '{}'", name, value))
+            })
+            .transpose()
+    };
+    let mut protection = Protection {
+        ceiling_db: number("ceiling", "dB")?,
+        trip_db: number("trip", "dB")?,
+        ..Protection::default()
+    };
+    if let Some(trip_ms) = number("trip time", "ms")? {
+        protection.trip_ms = trip_ms;
+    }
+    Ok(protection)
+}
+
+fn gate_options(matches: &clap::ArgMatches) -> Result<GateOptions> {
+    let number = |name: &str, suffix: &str| -> Result<Option<f32>> {
+        matches
+            .get_one::<String>(name)
+            .map(|value| {
+                value
+                    .strip_suffix(suffix)
+                    .unwrap_or(value)
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid {} '{}'", name, value))
+            })
+            .transpose()
+    };
+    Ok(GateOptions {
+        threshold_db: number("threshold", "dB")?,
+        hysteresis_db: number("hysteresis", "dB")?,
+        attack_ms: number("attack", "ms")?,
+        hold_ms: number("hold", "ms")?,
+        release_ms: number("release", "ms")?,
+    })
+}
+
+fn ducking(matches: &clap::ArgMatches) -> Result<Ducking> {
+    let mut ducking = Ducking::default();
+    for (name, suffix, value) in [
+        ("threshold", "dB", &mut ducking.threshold_db),
+        ("depth", "dB", &mut ducking.depth_db),
+        ("attack", "ms", &mut ducking.attack_ms),
+        ("release", "ms", &mut ducking.release_ms),
+    ] {
+        if let Some(s) = matches.get_one::<String>(name) {
+            *value = s
+                .strip_suffix(suffix)
+                .unwrap_or(s)
+                .parse()
+                .map_err(|_| anyhow!("Invalid {} '{}'", name, s))?;
+        }
+    }
+    Ok(ducking)
+}
+
+/// Explicit configuration format given with `--format`, if any.
+fn format(matches: &clap::ArgMatches) -> Result<Option<Format>> {
+    matches
+        .get_one::<String>("format")
+        .map(|format| format.parse())
+        .transpose()
+}
+
+pub struct Prompt {
+    editor: Editor<Helper, FileHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl Prompt {
+    pub fn new(parser: &Parser) -> Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(Helper::new(parser.command().clone())));
+
+        let history_path = dirs::config_dir().map(|dir| dir.join("patchbay").join("history"));
+        if let Some(path) = &history_path {
+            // there is no history on first run
+            let _ = editor.load_history(path);
+        }
+
+        Ok(Prompt {
+            editor,
+            history_path,
+        })
+    }
+
+    /// Update the patchbay state used for tab completion.
+    pub fn update(&mut self, patchbay: &Patchbay) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.update(patchbay);
+        }
+    }
+
+    /// Scan devices for tab completion again, e.g. after they were listed.
+    pub fn rescan(&mut self) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.rescan();
+        }
+    }
+
+    /// Read a line of input, returns `None` at end of input.
+    pub fn read(&mut self, prefix: &str) -> Result<Option<String>> {
+        match self.editor.readline(prefix) {
+            Ok(line) => {
+                let line = line.trim().to_string();
+                if !line.is_empty() {
+                    self.editor.add_history_entry(&line)?;
+                    self.save_history()?;
+                }
+                Ok(Some(line))
+            }
+            Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_history(&mut self) -> Result<()> {
+        if let Some(path) = &self.history_path {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            self.editor.save_history(path)?;
+        }
+        Ok(())
+    }
 }
 
 pub fn split_args(input: &str) -> Vec<&str> {
@@ -194,6 +839,8 @@ pub fn split_args(input: &str) -> Vec<&str> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dsp::{FilterKind, PanLaw};
+    use clap::Parser as _;
 
     fn check_action(r: Result<Action>, action: Action) {
         assert!(r.is_ok());
@@ -203,7 +850,22 @@ mod tests {
     #[test]
     fn list() {
         let mut p = Parser::new();
-        check_action(p.parse(vec!["list"]), Action::List);
+        for alias in ["list", "ls"] {
+            check_action(
+                p.parse(vec![alias]),
+                Action::List {
+                    verbose: false,
+                    json: false,
+                },
+            );
+        }
+        check_action(
+            p.parse(vec!["list", "-v", "--json"]),
+            Action::List {
+                verbose: true,
+                json: true,
+            },
+        );
     }
 
     #[test]
@@ -212,9 +874,80 @@ mod tests {
         for alias in ["connect", "c", "con", "conn"] {
             check_action(
                 p.parse(vec![alias, "d1", "3", "d2", "2"]),
-                Action::Connect("d1".to_string(), 3, "d2".to_string(), 2),
+                Action::Connect(
+                    "d1".to_string(),
+                    3,
+                    "d2".to_string(),
+                    2,
+                    ConnectOptions::default(),
+                ),
             );
         }
+        check_action(
+            p.parse(vec!["connect", "d1", "3", "d2", "2", "--force"]),
+            Action::Connect(
+                "d1".to_string(),
+                3,
+                "d2".to_string(),
+                2,
+                ConnectOptions {
+                    force: true,
+                    ..ConnectOptions::default()
+                },
+            ),
+        );
+        check_action(
+            p.parse(vec![
+                "connect", "d1", "0", "d2", "0", "--pan", "-0.5", "--law", "linear",
+            ]),
+            Action::Connect(
+                "d1".to_string(),
+                0,
+                "d2".to_string(),
+                0,
+                ConnectOptions {
+                    mode: Mode::Pan,
+                    pan: -0.5,
+                    pan_law: PanLaw::Linear,
+                    force: false,
+                },
+            ),
+        );
+        check_action(
+            p.parse(vec!["connect", "d1", "0", "d2", "0", "--sum"]),
+            Action::Connect(
+                "d1".to_string(),
+                0,
+                "d2".to_string(),
+                0,
+                ConnectOptions {
+                    mode: Mode::Sum,
+                    ..ConnectOptions::default()
+                },
+            ),
+        );
+        check_action(
+            p.parse(vec!["connect", "d1", "0", "d2", "0", "--link"]),
+            Action::Connect(
+                "d1".to_string(),
+                0,
+                "d2".to_string(),
+                0,
+                ConnectOptions {
+                    mode: Mode::Link,
+                    ..ConnectOptions::default()
+                },
+            ),
+        );
+        assert!(p
+            .parse(vec!["connect", "d1", "0", "d2", "0", "--sum", "--pan", "0"])
+            .is_err());
+        assert!(p
+            .parse(vec!["connect", "d1", "0", "d2", "0", "--link", "--sum"])
+            .is_err());
+        assert!(p
+            .parse(vec!["connect", "d1", "0", "d2", "0", "--law", "linear"])
+            .is_err());
     }
 
     #[test]
@@ -228,6 +961,243 @@ mod tests {
         }
     }
 
+    #[test]
+    fn pan() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["pan", "uuid", "-1"]),
+            Action::Pan("uuid".to_string(), -1.0),
+        );
+        assert!(p.parse(vec!["pan", "uuid", "left"]).is_err());
+    }
+
+    #[test]
+    fn delay() {
+        let mut p = Parser::new();
+        for (delay, ms) in [("12.5", 12.5), ("12.5ms", 12.5), ("600smp", 12.5)] {
+            check_action(
+                p.parse(vec!["delay", "uuid", delay]),
+                Action::Delay("uuid".to_string(), ms),
+            );
+        }
+        assert!(p.parse(vec!["delay", "uuid", "soon"]).is_err());
+        assert!(p.parse(vec!["delay", "uuid", "1.5smp"]).is_err());
+    }
+
+    #[test]
+    fn filter() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["filter", "uuid", "highpass", "80Hz"]),
+            Action::Filter("uuid".to_string(), Filter::new(FilterKind::HighPass, 80.0)),
+        );
+        check_action(
+            p.parse(vec![
+                "filter", "uuid", "peaking", "1000", "-q", "2", "--gain", "-3dB",
+            ]),
+            Action::Filter(
+                "uuid".to_string(),
+                Filter {
+                    q: 2.0,
+                    gain_db: -3.0,
+                    ..Filter::new(FilterKind::Peaking, 1000.0)
+                },
+            ),
+        );
+        assert!(p.parse(vec!["filter", "uuid", "bandpass", "80"]).is_err());
+        assert!(p.parse(vec!["filter", "uuid", "lowpass"]).is_err());
+
+        check_action(
+            p.parse(vec!["unfilter", "uuid", "1"]),
+            Action::Unfilter("uuid".to_string(), Some(1)),
+        );
+        check_action(
+            p.parse(vec!["unfilter", "uuid"]),
+            Action::Unfilter("uuid".to_string(), None),
+        );
+    }
+
+    #[test]
+    fn insert() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["insert", "uuid", "limiter"]),
+            Action::Insert("uuid".to_string(), ProcessorConfig::new("limiter")),
+        );
+        check_action(
+            p.parse(vec![
+                "insert",
+                "uuid",
+                "filter",
+                "kind=lowpass",
+                "frequency=120",
+            ]),
+            Action::Insert(
+                "uuid".to_string(),
+                ProcessorConfig::new("filter")
+                    .with_param("kind", "lowpass")
+                    .with_param("frequency", "120"),
+            ),
+        );
+        assert!(p.parse(vec!["insert", "uuid", "gain", "-3"]).is_err());
+
+        check_action(
+            p.parse(vec!["uninsert", "uuid"]),
+            Action::Uninsert("uuid".to_string(), None),
+        );
+    }
+
+    #[test]
+    fn gate() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["gate", "uuid"]),
+            Action::Gate("uuid".to_string(), GateOptions::default()),
+        );
+        check_action(
+            p.parse(vec![
+                "gate",
+                "uuid",
+                "--threshold",
+                "-45dB",
+                "--hold",
+                "200ms",
+            ]),
+            Action::Gate(
+                "uuid".to_string(),
+                GateOptions {
+                    threshold_db: Some(-45.0),
+                    hold_ms: Some(200.0),
+                    ..GateOptions::default()
+                },
+            ),
+        );
+        assert!(p.parse(vec!["gate", "uuid", "--release", "slow"]).is_err());
+        check_action(
+            p.parse(vec!["ungate", "uuid"]),
+            Action::Ungate("uuid".to_string()),
+        );
+    }
+
+    #[test]
+    fn duck() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["duck", "music", "mic"]),
+            Action::Duck("music".to_string(), "mic".to_string(), Ducking::default()),
+        );
+        check_action(
+            p.parse(vec![
+                "duck",
+                "music",
+                "mic",
+                "--threshold",
+                "-30dB",
+                "--depth",
+                "18",
+                "--release",
+                "1000ms",
+            ]),
+            Action::Duck(
+                "music".to_string(),
+                "mic".to_string(),
+                Ducking {
+                    threshold_db: -30.0,
+                    depth_db: 18.0,
+                    release_ms: 1000.0,
+                    ..Ducking::default()
+                },
+            ),
+        );
+        assert!(p
+            .parse(vec!["duck", "music", "mic", "--attack", "fast"])
+            .is_err());
+
+        check_action(
+            p.parse(vec!["unduck", "music"]),
+            Action::Unduck("music".to_string()),
+        );
+    }
+
+    #[test]
+    fn protect() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["protect", "phones", "1"]),
+            Action::Protect("phones".to_string(), 1, Protection::default()),
+        );
+        check_action(
+            p.parse(vec![
+                "protect",
+                "phones",
+                "1",
+                "--ceiling",
+                "-1dB",
+                "--trip",
+                "-0.5",
+                "--trip-time",
+                "200ms",
+            ]),
+            Action::Protect(
+                "phones".to_string(),
+                1,
+                Protection {
+                    ceiling_db: Some(-1.0),
+                    trip_db: Some(-0.5),
+                    trip_ms: 200.0,
+                },
+            ),
+        );
+        assert!(p
+            .parse(vec!["protect", "phones", "1", "--ceiling", "loud"])
+            .is_err());
+
+        check_action(
+            p.parse(vec!["unprotect", "phones", "1"]),
+            Action::Unprotect("phones".to_string(), 1),
+        );
+        check_action(p.parse(vec!["rearm"]), Action::Rearm(None));
+        check_action(
+            p.parse(vec!["rearm", "uuid"]),
+            Action::Rearm(Some("uuid".to_string())),
+        );
+    }
+
+    #[test]
+    fn alias() {
+        let mut p = Parser::new();
+        check_action(p.parse(vec!["alias"]), Action::Aliases);
+        check_action(
+            p.parse(vec!["alias", "mic", "Scarlett 2i2 USB"]),
+            Action::Alias("mic".to_string(), "Scarlett 2i2 USB".to_string()),
+        );
+        assert!(p.parse(vec!["alias", "mic"]).is_err());
+        check_action(
+            p.parse(vec!["unalias", "mic"]),
+            Action::Unalias("mic".to_string()),
+        );
+    }
+
+    #[test]
+    fn virtual_devices() {
+        let mut p = Parser::new();
+        check_action(p.parse(vec!["virtual"]), Action::VirtualDevices);
+        check_action(
+            p.parse(vec!["virtual", "browser"]),
+            Action::Virtual("browser".to_string(), None),
+        );
+        check_action(
+            p.parse(vec!["virtual", "browser", "--host", "ALSA"]),
+            Action::Virtual("browser".to_string(), Some("ALSA".to_string())),
+        );
+        assert!(p.parse(vec!["virtual", "--host", "ALSA"]).is_err());
+        check_action(
+            p.parse(vec!["unvirtual", "browser"]),
+            Action::Unvirtual("browser".to_string()),
+        );
+        assert!(p.parse(vec!["unvirtual"]).is_err());
+    }
+
     #[test]
     fn print() {
         let mut p = Parser::new();
@@ -236,6 +1206,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn matrix() {
+        let mut p = Parser::new();
+        for alias in ["matrix", "m"] {
+            check_action(p.parse(vec![alias]), Action::Matrix);
+        }
+    }
+
+    #[test]
+    fn undo() {
+        let mut p = Parser::new();
+        for alias in ["undo", "u"] {
+            check_action(p.parse(vec![alias]), Action::Undo);
+        }
+    }
+
+    #[test]
+    fn redo() {
+        let mut p = Parser::new();
+        check_action(p.parse(vec!["redo"]), Action::Redo);
+    }
+
     #[test]
     fn start() {
         let mut p = Parser::new();
@@ -253,7 +1245,11 @@ mod tests {
         let mut p = Parser::new();
         check_action(
             p.parse(vec!["save", "foo/bar"]),
-            Action::Save("foo/bar".to_string()),
+            Action::Save("foo/bar".to_string(), None),
+        );
+        check_action(
+            p.parse(vec!["save", "foo/bar", "--format", "toml"]),
+            Action::Save("foo/bar".to_string(), Some(Format::Toml)),
         );
     }
 
@@ -262,7 +1258,33 @@ mod tests {
         let mut p = Parser::new();
         check_action(
             p.parse(vec!["load", "foo/bar"]),
-            Action::Load("foo/bar".to_string()),
+            Action::Load("foo/bar".to_string(), None),
+        );
+        check_action(
+            p.parse(vec!["load", "foo/bar", "--format", "toml"]),
+            Action::Load("foo/bar".to_string(), Some(Format::Toml)),
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["validate", "foo/bar"]),
+            Action::Validate("foo/bar".to_string(), None),
+        );
+        check_action(
+            p.parse(vec!["validate", "foo/bar", "--format", "toml"]),
+            Action::Validate("foo/bar".to_string(), Some(Format::Toml)),
+        );
+    }
+
+    #[test]
+    fn source() {
+        let mut p = Parser::new();
+        check_action(
+            p.parse(vec!["source", "foo/bar.pb"]),
+            Action::Source("foo/bar.pb".to_string()),
         );
     }
 
@@ -274,6 +1296,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn args() {
+        let args = Args::try_parse_from(["patchbay"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.log_level, log::LevelFilter::Warn);
+
+        let args = Args::try_parse_from([
+            "patchbay",
+            "daemon",
+            "--config",
+            "foo.json",
+            "--latency",
+            "5",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Daemon { script: None })
+        ));
+        assert_eq!(args.config, Some(PathBuf::from("foo.json")));
+        assert_eq!(args.latency, Some(5));
+
+        let args = Args::try_parse_from(["patchbay", "ctl", "disconnect", "*"]).unwrap();
+        match args.command {
+            Some(Command::Ctl { command }) => assert_eq!(command, ["disconnect", "*"]),
+            _ => panic!(),
+        }
+
+        assert!(Args::try_parse_from(["patchbay", "ctl"]).is_err());
+        assert!(Args::try_parse_from(["patchbay", "--autosave"]).is_err());
+        assert!(
+            Args::try_parse_from(["patchbay", "--autosave", "-c", "foo.json"])
+                .unwrap()
+                .autosave
+        );
+        assert!(Args::try_parse_from(["patchbay", "--log-level", "loud"]).is_err());
+
+        let args =
+            Args::try_parse_from(["patchbay", "validate", "foo", "--format", "yaml"]).unwrap();
+        assert_eq!(args.format, Some(Format::Yaml));
+        assert!(Args::try_parse_from(["patchbay", "--format", "xml"]).is_err());
+    }
+
     #[test]
     fn split() {
         let s =
@@ -0,0 +1,316 @@
+use crate::patchbay::Patchbay;
+use crate::processor;
+use crate::system;
+
+use rustyline::completion::Pair;
+use rustyline::Context;
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+type DeviceList = fn(&str) -> anyhow::Result<Vec<(String, u16)>>;
+
+/// How long scanned devices are offered for completion before they are scanned again.
+const DEVICES_TTL: Duration = Duration::from_secs(30);
+
+/// Devices of every host, as offered for completion. Scanning takes long on some hosts,
+/// so it is only done when the list is first needed, after `list`, after the default
+/// host changes and once the list is older than [`DEVICES_TTL`].
+struct Devices {
+    scanned: Instant,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+}
+
+/// Line editor helper providing context-aware tab completion for REPL commands.
+pub struct Helper {
+    command: clap::Command,
+    host: String,
+    ids: Vec<String>,
+    aliases: Vec<String>,
+    virtual_devices: Vec<String>,
+    devices: RefCell<Option<Devices>>,
+}
+
+impl Helper {
+    pub fn new(command: clap::Command) -> Self {
+        Helper {
+            command,
+            host: String::new(),
+            ids: Vec::new(),
+            aliases: Vec::new(),
+            virtual_devices: Vec::new(),
+            devices: RefCell::new(None),
+        }
+    }
+
+    /// Refresh the patchbay state used for completion.
+    pub fn update(&mut self, patchbay: &Patchbay) {
+        if self.host != patchbay.host() {
+            self.rescan();
+        }
+        self.host = patchbay.host().to_owned();
+        self.ids = patchbay
+            .connections()
+            .map(|(id, _)| id.to_string())
+            .collect();
+        self.aliases = patchbay.aliases().keys().cloned().collect();
+        self.virtual_devices = patchbay
+            .virtual_devices()
+            .iter()
+            .map(|v| v.name.clone())
+            .collect();
+    }
+
+    /// Scan devices again the next time they are completed.
+    pub fn rescan(&mut self) {
+        self.devices = RefCell::new(None);
+    }
+
+    /// Cached input or output devices, scanning them first if needed.
+    fn devices(&self, inputs: bool) -> Vec<String> {
+        let mut devices = self.devices.borrow_mut();
+        if devices
+            .as_ref()
+            .is_none_or(|d| d.scanned.elapsed() > DEVICES_TTL)
+        {
+            *devices = Some(Devices {
+                scanned: Instant::now(),
+                inputs: self.scan(system::input_devices),
+                outputs: self.scan(system::output_devices),
+            });
+        }
+        let devices = devices.as_ref().unwrap();
+        if inputs {
+            devices.inputs.clone()
+        } else {
+            devices.outputs.clone()
+        }
+    }
+
+    /// Devices of every host, where devices on other hosts than the default are
+    /// qualified with their host.
+    fn scan(&self, devices: DeviceList) -> Vec<String> {
+        system::host_names()
+            .into_iter()
+            .flat_map(|host_name| {
+                devices(&host_name)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |(name, _)| {
+                        if host_name == self.host {
+                            name
+                        } else {
+                            format!("{}:{}", host_name, name)
+                        }
+                    })
+            })
+            .collect()
+    }
+
+    fn commands(&self) -> Vec<String> {
+        self.command
+            .get_subcommands()
+            .map(|c| c.get_name().to_string())
+            .collect()
+    }
+
+    fn candidates(&self, tokens: &[String], prefix: &str) -> Vec<String> {
+        let Some(first) = tokens.first() else {
+            return self.commands();
+        };
+
+        // resolve aliases to the subcommand name
+        let command = self
+            .command
+            .find_subcommand(first)
+            .map(|c| c.get_name().to_string())
+            .unwrap_or_default();
+
+        match (command.as_str(), tokens.len()) {
+            ("help", 1) => self.commands(),
+            ("host", 1) => system::host_names(),
+            ("connect", 1) => [
+                self.aliases.clone(),
+                self.virtual_devices.clone(),
+                self.devices(true),
+            ]
+            .concat(),
+            ("connect", 3) | ("protect" | "unprotect", 1) => [
+                self.aliases.clone(),
+                self.virtual_devices.clone(),
+                self.devices(false),
+            ]
+            .concat(),
+            ("unalias", 1) => self.aliases.clone(),
+            ("unvirtual", 1) => self.virtual_devices.clone(),
+            ("rearm", 1) | ("duck", 2) => self.ids.clone(),
+            (
+                "pan" | "delay" | "filter" | "unfilter" | "gate" | "ungate" | "insert" | "uninsert"
+                | "duck" | "unduck",
+                1,
+            ) => self.ids.clone(),
+            ("insert", 2) => processor::names(),
+            ("filter", 2) => ["highpass", "lowpass", "lowshelf", "highshelf", "peaking"]
+                .map(String::from)
+                .to_vec(),
+            ("disconnect", 1) => std::iter::once("*".to_string())
+                .chain(self.ids.iter().cloned())
+                .collect(),
+            ("save" | "load" | "validate" | "source", 1) => paths(prefix),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl rustyline::completion::Completer for Helper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (tokens, start, prefix) = tokenize(&line[..pos]);
+
+        let mut candidates: Vec<Pair> = self
+            .candidates(&tokens, &prefix)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&prefix))
+            .map(|candidate| Pair {
+                replacement: quote(&candidate),
+                display: candidate,
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.display.cmp(&b.display));
+        candidates.dedup_by(|a, b| a.display == b.display);
+
+        Ok((start, candidates))
+    }
+}
+
+impl rustyline::hint::Hinter for Helper {
+    type Hint = String;
+}
+
+impl rustyline::highlight::Highlighter for Helper {}
+
+impl rustyline::validate::Validator for Helper {}
+
+impl rustyline::Helper for Helper {}
+
+/// Split the input the same way as `cli::split_args`, returning the completed tokens
+/// along with the start offset and unquoted text of the token being typed.
+fn tokenize(input: &str) -> (Vec<String>, usize, String) {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut start = input.len();
+    let mut in_token = false;
+    let mut quoted = false;
+
+    for (i, c) in input.char_indices() {
+        let is_quote = c == '"' || c == '\'';
+        if is_quote {
+            quoted = !quoted;
+        } else if !quoted && c.is_whitespace() {
+            if in_token {
+                tokens.push(std::mem::take(&mut current));
+                in_token = false;
+                start = input.len();
+            }
+            continue;
+        }
+
+        if !in_token {
+            in_token = true;
+            start = i;
+        }
+        if !is_quote {
+            current.push(c);
+        }
+    }
+
+    (tokens, start, current)
+}
+
+fn quote(s: &str) -> String {
+    if s.contains(char::is_whitespace) {
+        format!("\"{}\"", s)
+    } else {
+        s.to_string()
+    }
+}
+
+fn paths(prefix: &str) -> Vec<String> {
+    let (dir, listed) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..=i], &prefix[..=i]),
+        None => ("", "."),
+    };
+
+    let Ok(entries) = Path::new(listed).read_dir() else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let suffix = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, suffix))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        assert_eq!(tokenize(""), (vec![], 0, "".to_string()));
+        assert_eq!(tokenize("con"), (vec![], 0, "con".to_string()));
+        assert_eq!(
+            tokenize("connect "),
+            (vec!["connect".to_string()], 8, "".to_string())
+        );
+        assert_eq!(
+            tokenize("connect \"device f"),
+            (vec!["connect".to_string()], 8, "device f".to_string())
+        );
+        assert_eq!(
+            tokenize("connect 'device foo' 1 d"),
+            (
+                vec![
+                    "connect".to_string(),
+                    "device foo".to_string(),
+                    "1".to_string()
+                ],
+                23,
+                "d".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn cached_devices() {
+        let mut helper = Helper::new(clap::Command::new("patchbay"));
+        *helper.devices.borrow_mut() = Some(Devices {
+            scanned: Instant::now(),
+            inputs: vec!["mic".to_string()],
+            outputs: vec!["phones".to_string()],
+        });
+        assert_eq!(helper.devices(true), ["mic"]);
+        assert_eq!(helper.devices(false), ["phones"]);
+
+        helper.rescan();
+        assert!(helper.devices.borrow().is_none());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("device"), "device");
+        assert_eq!(quote("device foo"), "\"device foo\"");
+    }
+}
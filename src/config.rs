@@ -0,0 +1,1128 @@
+use crate::connection::{ConnectionMetadata, Mode, MAX_DELAY_MS, SAMPLE_RATE};
+use crate::dsp::Protection;
+use crate::loopback::VirtualDevice;
+use crate::processor::Context;
+use crate::routes;
+use crate::system;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// Upgrades from each configuration version to the next, indexed by the version they
+/// upgrade from. Configurations saved before versioning was introduced are version 0.
+const MIGRATIONS: &[Migration] = &[
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9,
+    v9_to_v10, v10_to_v11,
+];
+
+/// Configuration version written by this build.
+pub const VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// Configuration file format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Routes,
+}
+
+impl Format {
+    /// Guess the format from the file extension, defaulting to JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("routes") => Format::Routes,
+            _ => Format::Json,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "routes" => Ok(Format::Routes),
+            _ => Err(anyhow!(
+                "Unknown configuration format '{}' (expected json, toml, yaml or routes)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "JSON"),
+            Format::Toml => write!(f, "TOML"),
+            Format::Yaml => write!(f, "YAML"),
+            Format::Routes => write!(f, "route list"),
+        }
+    }
+}
+
+/// Patchbay state as stored in configuration files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    pub host: String,
+    pub connections: BTreeMap<Uuid, ConnectionMetadata>,
+    /// Device selectors by user-defined name, resolved when connections are created.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protection: Vec<SinkProtection>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub virtual_devices: Vec<VirtualDevice>,
+}
+
+/// Protection of a sink channel, applying to every connection to it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SinkProtection {
+    pub host_name: String,
+    pub sink_name: String,
+    pub sink_channel: u16,
+    #[serde(flatten)]
+    pub protection: Protection,
+}
+
+impl SinkProtection {
+    /// Whether the protection applies to a connection.
+    pub fn covers(&self, m: &ConnectionMetadata) -> bool {
+        self.host_name == m.host_name
+            && self.sink_name == m.sink_name
+            && self.sink_channel == m.sink_channel
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u64,
+    #[serde(flatten)]
+    config: &'a Config,
+}
+
+/// Configuration with the connections left unparsed, so each can be checked separately.
+#[derive(Deserialize)]
+struct Outline {
+    #[allow(dead_code)]
+    host: String,
+    connections: BTreeMap<String, Value>,
+    #[serde(default)]
+    protection: Vec<SinkProtection>,
+    #[serde(default)]
+    virtual_devices: Vec<VirtualDevice>,
+}
+
+/// Problem found in a configuration, located by its JSON path.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(path: &str, message: impl fmt::Display) -> Self {
+        Problem {
+            path: path.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+pub fn to_string(config: &Config, format: Format) -> Result<String> {
+    let document = Document {
+        version: VERSION,
+        config,
+    };
+    Ok(match format {
+        Format::Json => serde_json::to_string_pretty(&document)?,
+        Format::Toml => toml::to_string_pretty(&document)?,
+        Format::Yaml => serde_yaml::to_string(&document)?,
+        Format::Routes => routes::to_string(config),
+    })
+}
+
+/// Write a configuration file atomically, keeping the previous version as a backup.
+///
+/// The contents go to a temporary file next to the configuration, which then replaces it,
+/// so a crash or full disk never leaves a partially written configuration behind. The new
+/// file keeps the permissions of the one it replaces, and is never more accessible while
+/// it is written.
+pub fn write(path: &Path, contents: &str) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("Invalid configuration path {}", path.display()))?;
+    // unique, so that concurrent writers never share a temporary file
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        Uuid::new_v4().simple()
+    ));
+    let permissions = fs::metadata(path).ok().map(|m| m.permissions());
+
+    let result = (|| -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if let Some(permissions) = &permissions {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(permissions.mode());
+        }
+        let mut f = options.open(&tmp)?;
+        f.write_all(contents.as_bytes())?;
+        f.sync_all()?;
+
+        if let Some(permissions) = permissions {
+            // the mode given on creation is narrowed by the umask
+            fs::set_permissions(&tmp, permissions)?;
+            fs::copy(path, backup_path(path))?;
+        }
+        fs::rename(&tmp, path)?;
+
+        // the rename only survives a crash once the directory is synced as well
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Path of the backup holding the previous version of a configuration file.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+/// Parse a configuration without instantiating any connections.
+pub fn parse(s: &str, format: Format) -> Result<Config> {
+    let document = migrate(read(s, format)?)?;
+    Ok(serde_json::from_value(document)?)
+}
+
+/// Check a configuration against the devices available on the system without opening
+/// any audio streams, reporting every problem found.
+pub fn validate(s: &str, format: Format) -> Vec<Problem> {
+    let (mut problems, connections) = check(s, format);
+    problems.extend(check_devices(&connections, &aliases(s, format)));
+    problems
+}
+
+/// Aliases of a configuration along with the devices behind its virtual devices,
+/// regardless of problems elsewhere in it.
+fn aliases(s: &str, format: Format) -> BTreeMap<String, String> {
+    let (mut aliases, virtual_devices): (BTreeMap<String, String>, Vec<VirtualDevice>) =
+        if format == Format::Routes {
+            routes::read(s)
+                .map(|r| {
+                    (
+                        r.aliases,
+                        r.virtual_devices.into_iter().map(|(_, v)| v).collect(),
+                    )
+                })
+                .unwrap_or_default()
+        } else {
+            let mut document = read(s, format).unwrap_or_default();
+            (
+                serde_json::from_value(document["aliases"].take()).unwrap_or_default(),
+                serde_json::from_value(document["virtual_devices"].take()).unwrap_or_default(),
+            )
+        };
+    aliases.extend(virtual_devices.into_iter().map(|v| (v.name, v.device)));
+    aliases
+}
+
+/// Differences between two configurations, matching connections by their route rather
+/// than their id, as route lists generate new ids on every load.
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub added: Vec<(Uuid, ConnectionMetadata)>,
+    pub removed: Vec<Uuid>,
+    /// Connections whose route is unchanged but whose parameters differ, keyed by their
+    /// existing id.
+    pub changed: Vec<(Uuid, ConnectionMetadata)>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+pub fn diff(old: &Config, new: &Config) -> Diff {
+    let route = |m: &ConnectionMetadata| {
+        (
+            m.host_name.clone(),
+            m.source_name.clone(),
+            m.source_channel,
+            m.sink_name.clone(),
+            m.sink_channel,
+            // changing the mode requires reopening the streams
+            m.mode,
+        )
+    };
+
+    let mut unmatched: HashMap<_, Vec<Uuid>> = HashMap::new();
+    for (id, m) in &old.connections {
+        unmatched.entry(route(m)).or_default().push(*id);
+    }
+
+    let mut diff = Diff::default();
+    let mut kept = Vec::new();
+    for (id, m) in &new.connections {
+        match unmatched.get_mut(&route(m)).and_then(Vec::pop) {
+            Some(old_id) => {
+                if old.connections[&old_id] != *m {
+                    diff.changed.push((old_id, m.clone()));
+                }
+                kept.push(old_id);
+            }
+            None => diff.added.push((*id, m.clone())),
+        }
+    }
+
+    // keep the ids of new connections unless an existing connection already uses them
+    for (id, _) in &mut diff.added {
+        if kept.contains(id) {
+            *id = Uuid::new_v4();
+        }
+    }
+
+    diff.removed = unmatched.into_values().flatten().collect();
+    diff.removed.sort();
+    diff
+}
+
+/// Read any format into a JSON document, so that migrations only deal with one representation.
+fn read(s: &str, format: Format) -> Result<Value> {
+    Ok(match format {
+        Format::Json => serde_json::from_str(s)?,
+        Format::Toml => toml::from_str(s)?,
+        Format::Yaml => serde_yaml::from_str(s)?,
+        // route lists are not versioned, they are always read as the current version
+        Format::Routes => serde_json::to_value(Document {
+            version: VERSION,
+            config: &routes::parse(s)?,
+        })?,
+    })
+}
+
+/// Check syntax, schema and duplicate routes, returning the problems found along with the
+/// connections that were parsed successfully.
+fn check(s: &str, format: Format) -> (Vec<Problem>, Vec<(String, ConnectionMetadata)>) {
+    if format == Format::Routes {
+        return check_routes(s);
+    }
+
+    let document = match read(s, format).and_then(migrate) {
+        Ok(document) => document,
+        Err(e) => return (vec![Problem::new("$", e)], Vec::new()),
+    };
+
+    let outline: Outline = match serde_path_to_error::deserialize(document) {
+        Ok(outline) => outline,
+        Err(e) => {
+            return (
+                vec![Problem::new(&join("$", e.path()), e.inner())],
+                Vec::new(),
+            )
+        }
+    };
+
+    let mut problems = Vec::new();
+    let mut connections = Vec::new();
+
+    for (id, value) in outline.connections {
+        let path = format!("$.connections.{}", id);
+
+        if Uuid::parse_str(&id).is_err() {
+            problems.push(Problem::new(&path, "Invalid connection id"));
+        }
+
+        match serde_path_to_error::deserialize::<_, ConnectionMetadata>(value.clone()) {
+            Ok(metadata) => {
+                // serde ignores unknown fields, which are most likely typos
+                let known = serde_json::to_value(&metadata).unwrap_or_default();
+                if let (Some(fields), Some(known)) = (value.as_object(), known.as_object()) {
+                    problems.extend(
+                        fields
+                            .keys()
+                            .filter(|field| !known.contains_key(*field))
+                            .map(|field| {
+                                Problem::new(&format!("{}.{}", path, field), "Unknown field")
+                            }),
+                    );
+                }
+                problems.extend(check_parameters(&path, &metadata));
+                connections.push((path, metadata));
+            }
+            Err(e) => problems.push(Problem::new(&join(&path, e.path()), e.inner())),
+        }
+    }
+
+    for (i, sink) in outline.protection.iter().enumerate() {
+        problems.extend(check_protection(
+            &format!("$.protection[{}]", i),
+            &sink.protection,
+        ));
+    }
+    problems.extend(check_virtual_devices(
+        outline
+            .virtual_devices
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("$.virtual_devices[{}]", i), v)),
+    ));
+
+    problems.extend(duplicates(&connections));
+    problems.extend(sidechains(&connections));
+    (problems, connections)
+}
+
+/// Route lists are located by line instead of JSON path, as they have no connection ids.
+fn check_routes(s: &str) -> (Vec<Problem>, Vec<(String, ConnectionMetadata)>) {
+    let routes = match routes::read(s) {
+        Ok(routes) => routes,
+        Err(e) => {
+            return (
+                vec![Problem::new(&format!("line {}", e.line), e.message)],
+                Vec::new(),
+            )
+        }
+    };
+
+    let connections: Vec<_> = routes
+        .routes
+        .into_iter()
+        .map(|(line, metadata)| (format!("line {}", line), metadata))
+        .collect();
+
+    let mut problems: Vec<Problem> = connections
+        .iter()
+        .flat_map(|(path, metadata)| check_parameters(path, metadata))
+        .collect();
+    for (line, sink) in &routes.protection {
+        problems.extend(check_protection(
+            &format!("line {}", line),
+            &sink.protection,
+        ));
+    }
+    problems.extend(check_virtual_devices(
+        routes
+            .virtual_devices
+            .iter()
+            .map(|(line, v)| (format!("line {}", line), v)),
+    ));
+    problems.extend(duplicates(&connections));
+    problems.extend(sidechains(&connections));
+    (problems, connections)
+}
+
+/// Virtual devices are selected by name, so names must be unique, and two virtual devices
+/// cannot share a loopback device.
+fn check_virtual_devices<'a>(
+    devices: impl Iterator<Item = (String, &'a VirtualDevice)>,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut seen: Vec<(String, &VirtualDevice)> = Vec::new();
+    for (path, v) in devices {
+        if let Some((first, _)) = seen.iter().find(|(_, other)| other.name == v.name) {
+            problems.push(Problem::new(
+                &format!("{}.name", path),
+                format!("Duplicate of {}", first),
+            ));
+        } else if let Some((first, _)) = seen
+            .iter()
+            .find(|(_, other)| other.host_name == v.host_name && other.device == v.device)
+        {
+            problems.push(Problem::new(
+                &format!("{}.device", path),
+                format!("Device already used by {}", first),
+            ));
+        }
+        seen.push((path, v));
+    }
+    problems
+}
+
+fn check_protection(path: &str, protection: &Protection) -> Option<Problem> {
+    protection
+        .check()
+        .err()
+        .map(|(field, message)| Problem::new(&format!("{}.{}", path, field), message))
+}
+
+/// Check connection parameters that serde accepts but connections reject.
+fn check_parameters(path: &str, m: &ConnectionMetadata) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if let Err((field, message)) = m.check_link() {
+        problems.push(Problem::new(&format!("{}.{}", path, field), message));
+    }
+    if !(-1.0..=1.0).contains(&m.pan) {
+        problems.push(Problem::new(
+            &format!("{}.pan", path),
+            format!("Pan {} out of range (-1 to 1)", m.pan),
+        ));
+    }
+    if !(0.0..=MAX_DELAY_MS).contains(&m.delay_ms) {
+        problems.push(Problem::new(
+            &format!("{}.delay_ms", path),
+            format!(
+                "Delay {}ms out of range (0 to {}ms)",
+                m.delay_ms, MAX_DELAY_MS
+            ),
+        ));
+    }
+    for (i, filter) in m.filters.iter().enumerate() {
+        if let Err((field, message)) = filter.check(SAMPLE_RATE) {
+            problems.push(Problem::new(
+                &format!("{}.filters[{}].{}", path, i, field),
+                message,
+            ));
+        }
+    }
+    if let Some(gate) = &m.gate {
+        if let Err((field, message)) = gate.check() {
+            problems.push(Problem::new(&format!("{}.gate.{}", path, field), message));
+        }
+    }
+    for (i, processor) in m.processors.iter().enumerate() {
+        if let Err(e) = processor.build(&Context::new(SAMPLE_RATE)) {
+            problems.push(Problem::new(&format!("{}.processors[{}]", path, i), e));
+        }
+    }
+    if let Some(sidechain) = &m.sidechain {
+        if let Err((field, message)) = sidechain.ducking.check() {
+            problems.push(Problem::new(
+                &format!("{}.sidechain.{}", path, field),
+                message,
+            ));
+        }
+    }
+    problems
+}
+
+/// Sidechain levels are measured by the connections from the sidechain source, so ducked
+/// connections need one.
+fn sidechains(connections: &[(String, ConnectionMetadata)]) -> Vec<Problem> {
+    connections
+        .iter()
+        .filter_map(|(path, m)| {
+            let sidechain = m.sidechain.as_ref()?;
+            let connected = connections.iter().any(|(_, other)| {
+                other.mode != Mode::Link
+                    && other.host_name == m.host_name
+                    && other.source_name == sidechain.source_name
+                    && other.source_channel == sidechain.source_channel
+            });
+            (!connected).then(|| {
+                Problem::new(
+                    &format!("{}.sidechain", path),
+                    format!(
+                        "No connection from {}({}) to measure the sidechain level",
+                        sidechain.source_name, sidechain.source_channel
+                    ),
+                )
+            })
+        })
+        .collect()
+}
+
+fn duplicates(connections: &[(String, ConnectionMetadata)]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut routes: HashMap<_, &str> = HashMap::new();
+    for (path, m) in connections {
+        let route = (
+            &m.host_name,
+            &m.source_name,
+            m.source_channel,
+            &m.sink_name,
+            m.sink_channel,
+        );
+        match routes.get(&route) {
+            Some(first) => problems.push(Problem::new(path, format!("Duplicate of {}", first))),
+            None => {
+                routes.insert(route, path);
+            }
+        }
+    }
+
+    problems
+}
+
+fn check_devices(
+    connections: &[(String, ConnectionMetadata)],
+    aliases: &BTreeMap<String, String>,
+) -> Vec<Problem> {
+    let mut hosts: HashMap<&str, Result<system::Devices, String>> = HashMap::new();
+    let mut problems = Vec::new();
+
+    for (path, m) in connections {
+        let devices = hosts
+            .entry(&m.host_name)
+            .or_insert_with(|| system::Devices::list(&m.host_name).map_err(|e| e.to_string()));
+
+        let devices = match devices {
+            Ok(devices) => devices,
+            Err(e) => {
+                problems.push(Problem::new(&format!("{}.host_name", path), e.clone()));
+                continue;
+            }
+        };
+
+        // panned and summed connections use the channel after the given one as well
+        let offsets = m.mode.offsets();
+        let source_offset = offsets.iter().map(|(source, _)| *source).max().unwrap_or(0);
+        let sink_offset = offsets.iter().map(|(_, sink)| *sink).max().unwrap_or(0);
+
+        for (field, selector, channel, kind) in [
+            (
+                "source",
+                &m.source_name,
+                m.source_channel + source_offset,
+                "input",
+            ),
+            ("sink", &m.sink_name, m.sink_channel + sink_offset, "output"),
+        ] {
+            // only aliases select devices, other saved names match exactly
+            let device = match (aliases.get(selector), kind) {
+                (Some(alias), "input") => devices.input(&system::split_qualified(alias).1),
+                (Some(alias), _) => devices.output(&system::split_qualified(alias).1),
+                (None, "input") => devices.input_named(selector),
+                (None, _) => devices.output_named(selector),
+            };
+            match device {
+                Err(e) => problems.push(Problem::new(&format!("{}.{}_name", path, field), e)),
+                Ok((name, channels)) if channel >= *channels => problems.push(Problem::new(
+                    &format!("{}.{}_channel", path, field),
+                    format!(
+                        "Channel {} out of range, '{}' has {} {} channels",
+                        channel, name, channels, kind
+                    ),
+                )),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    problems
+}
+
+fn join(base: &str, path: &serde_path_to_error::Path) -> String {
+    if path.iter().next().is_none() {
+        base.to_string()
+    } else {
+        format!("{}.{}", base, path)
+    }
+}
+
+/// Upgrade a configuration document to the current version.
+pub fn migrate(document: Value) -> Result<Value> {
+    let Value::Object(mut document) = document else {
+        return Err(anyhow!("Configuration must be an object"));
+    };
+
+    let version = match document.get("version") {
+        Some(v) => v
+            .as_u64()
+            .ok_or(anyhow!("Configuration version must be a positive integer"))?,
+        None => 0,
+    };
+
+    if version > VERSION {
+        return Err(anyhow!(
+            "Configuration version {} is newer than the supported version {}, please upgrade patchbay",
+            version,
+            VERSION
+        ));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut document)?;
+    }
+    document.insert("version".to_string(), VERSION.into());
+
+    Ok(Value::Object(document))
+}
+
+fn connections(document: &mut Map<String, Value>) -> impl Iterator<Item = &mut Value> {
+    document
+        .get_mut("connections")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|connections| connections.values_mut())
+}
+
+/// Version 1 added gain and mute to connections.
+fn v0_to_v1(document: &mut Map<String, Value>) -> Result<()> {
+    for connection in connections(document) {
+        let connection = connection
+            .as_object_mut()
+            .ok_or(anyhow!("Connection must be an object"))?;
+        connection.entry("gain_db").or_insert(0.0.into());
+        connection.entry("muted").or_insert(false.into());
+    }
+    Ok(())
+}
+
+/// Version 2 added aliases, which are left out when there are none.
+fn v1_to_v2(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// Version 3 added delay to connections.
+fn v2_to_v3(document: &mut Map<String, Value>) -> Result<()> {
+    for connection in connections(document) {
+        let connection = connection
+            .as_object_mut()
+            .ok_or(anyhow!("Connection must be an object"))?;
+        connection.entry("delay_ms").or_insert(0.0.into());
+    }
+    Ok(())
+}
+
+/// Version 4 added filter chains to connections.
+fn v3_to_v4(document: &mut Map<String, Value>) -> Result<()> {
+    for connection in connections(document) {
+        let connection = connection
+            .as_object_mut()
+            .ok_or(anyhow!("Connection must be an object"))?;
+        connection
+            .entry("filters")
+            .or_insert(Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// Version 5 added processor chains to connections.
+fn v4_to_v5(document: &mut Map<String, Value>) -> Result<()> {
+    for connection in connections(document) {
+        let connection = connection
+            .as_object_mut()
+            .ok_or(anyhow!("Connection must be an object"))?;
+        connection
+            .entry("processors")
+            .or_insert(Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// Version 6 added sink protection, which is left out when no sink is protected.
+fn v5_to_v6(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// Version 7 added the channel mode, pan and pan law to connections.
+fn v6_to_v7(document: &mut Map<String, Value>) -> Result<()> {
+    for connection in connections(document) {
+        let connection = connection
+            .as_object_mut()
+            .ok_or(anyhow!("Connection must be an object"))?;
+        connection.entry("mode").or_insert("single".into());
+        connection.entry("pan").or_insert(0.0.into());
+        connection.entry("pan_law").or_insert("power".into());
+    }
+    Ok(())
+}
+
+/// Version 8 added ducking to connections, which is left out when they are not ducked.
+fn v7_to_v8(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// Version 9 added noise gates to connections, which are left out when they have none.
+fn v8_to_v9(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// Version 10 added virtual devices, which are left out when there are none.
+fn v9_to_v10(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// Version 11 added direct links between JACK ports as a connection mode.
+fn v10_to_v11(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::{Filter, FilterKind};
+    use crate::processor::ProcessorConfig;
+    use serde_json::json;
+
+    #[test]
+    fn unversioned() {
+        let document = json!({
+            "host": "CoreAudio",
+            "connections": {
+                "2b1b6a3c-0b8e-4a5e-9d7e-6a8d6b1b7c0e": {
+                    "host_name": "CoreAudio",
+                    "source_name": "mic",
+                    "sink_name": "phones",
+                    "source_channel": 0,
+                    "sink_channel": 1
+                }
+            }
+        });
+
+        let migrated = migrate(document).unwrap();
+        assert_eq!(migrated["version"], VERSION);
+        let connection = &migrated["connections"]["2b1b6a3c-0b8e-4a5e-9d7e-6a8d6b1b7c0e"];
+        assert_eq!(connection["gain_db"], 0.0);
+        assert_eq!(connection["muted"], false);
+        assert_eq!(connection["delay_ms"], 0.0);
+        assert_eq!(connection["filters"], json!([]));
+        assert_eq!(connection["processors"], json!([]));
+        assert_eq!(connection["mode"], "single");
+    }
+
+    #[test]
+    fn current() {
+        let document = json!({ "version": VERSION, "host": "CoreAudio", "connections": {} });
+        assert_eq!(migrate(document.clone()).unwrap(), document);
+    }
+
+    #[test]
+    fn newer() {
+        let document = json!({ "version": VERSION + 1, "host": "CoreAudio", "connections": {} });
+        assert!(migrate(document).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(migrate(json!([])).is_err());
+        assert!(migrate(json!({ "version": "1" })).is_err());
+    }
+
+    #[test]
+    fn formats() {
+        let config = Config {
+            host: "CoreAudio".to_string(),
+            connections: BTreeMap::from([(
+                Uuid::nil(),
+                ConnectionMetadata {
+                    gain_db: -3.0,
+                    muted: true,
+                    delay_ms: 12.5,
+                    filters: vec![
+                        Filter::new(FilterKind::HighPass, 80.0),
+                        Filter {
+                            q: 1.4,
+                            gain_db: -3.5,
+                            ..Filter::new(FilterKind::Peaking, 2500.0)
+                        },
+                    ],
+                    processors: vec![
+                        ProcessorConfig::new("limiter").with_param("ceiling_db", "-1.5"),
+                        ProcessorConfig::new("gain").with_param("gain_db", "2"),
+                    ],
+                    ..ConnectionMetadata::new(
+                        "CoreAudio".to_string(),
+                        "Mic Pre".to_string(),
+                        "phones".to_string(),
+                        0,
+                        1,
+                    )
+                },
+            )]),
+            aliases: BTreeMap::from([("mic".to_string(), "Mic Pre".to_string())]),
+            protection: vec![SinkProtection {
+                host_name: "CoreAudio".to_string(),
+                sink_name: "phones".to_string(),
+                sink_channel: 1,
+                protection: Protection {
+                    ceiling_db: Some(-1.0),
+                    ..Protection::default()
+                },
+            }],
+            virtual_devices: vec![VirtualDevice {
+                name: "app".to_string(),
+                host_name: "CoreAudio".to_string(),
+                device: "BlackHole 2ch".to_string(),
+                peer: "BlackHole 2ch".to_string(),
+            }],
+        };
+
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let s = to_string(&config, format).unwrap();
+            assert_eq!(parse(&s, format).unwrap(), config);
+        }
+
+        // route lists generate new ids
+        let s = to_string(&config, Format::Routes).unwrap();
+        let parsed = parse(&s, Format::Routes).unwrap();
+        assert_eq!(parsed.host, config.host);
+        assert_eq!(parsed.aliases, config.aliases);
+        assert_eq!(parsed.protection, config.protection);
+        assert_eq!(parsed.virtual_devices, config.virtual_devices);
+        assert!(parsed.connections.values().eq(config.connections.values()));
+    }
+
+    #[test]
+    fn differences() {
+        let metadata = |source: &str, gain_db: f32| ConnectionMetadata {
+            gain_db,
+            ..ConnectionMetadata::new(
+                "CoreAudio".to_string(),
+                source.to_string(),
+                "phones".to_string(),
+                0,
+                1,
+            )
+        };
+        let id = |n: u128| Uuid::from_u128(n);
+
+        let old = Config {
+            host: "CoreAudio".to_string(),
+            connections: BTreeMap::from([
+                (id(1), metadata("mic", 0.0)),
+                (id(2), metadata("synth", 0.0)),
+                (id(3), metadata("drums", 0.0)),
+            ]),
+            aliases: BTreeMap::new(),
+            protection: Vec::new(),
+            virtual_devices: Vec::new(),
+        };
+        let new = Config {
+            host: "CoreAudio".to_string(),
+            connections: BTreeMap::from([
+                (id(1), metadata("bass", 0.0)),
+                (id(4), metadata("mic", 0.0)),
+                (id(5), metadata("synth", -6.0)),
+            ]),
+            aliases: BTreeMap::new(),
+            protection: Vec::new(),
+            virtual_devices: Vec::new(),
+        };
+
+        let diff = diff(&old, &new);
+        assert_eq!(diff.removed, [id(3)]);
+        assert_eq!(diff.changed, [(id(2), metadata("synth", -6.0))]);
+        assert_eq!(diff.added.len(), 1);
+        // id 1 is still used by the mic connection
+        assert_ne!(diff.added[0].0, id(1));
+        assert_eq!(diff.added[0].1, metadata("bass", 0.0));
+
+        assert!(super::diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn atomic_write() {
+        let dir = std::env::temp_dir().join(format!("patchbay-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("config.json");
+
+        write(&path, "first").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        assert!(!backup_path(&path).exists());
+
+        write(&path, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "first");
+
+        // nothing but the configuration and its backup is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+            write(&path, "third").unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!(Format::from_path(Path::new("a.toml")), Format::Toml);
+        assert_eq!(Format::from_path(Path::new("a.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("a.yaml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("a.json")), Format::Json);
+        assert_eq!(Format::from_path(Path::new("a")), Format::Json);
+        assert_eq!(Format::from_path(Path::new("a.routes")), Format::Routes);
+        assert_eq!("TOML".parse::<Format>().unwrap(), Format::Toml);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    fn paths(s: &str) -> Vec<String> {
+        check(s, Format::Json)
+            .0
+            .into_iter()
+            .map(|p| p.path)
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let (problems, connections) = check(
+            r#"{
+                "version": 1,
+                "host": "CoreAudio",
+                "connections": {
+                    "2b1b6a3c-0b8e-4a5e-9d7e-6a8d6b1b7c0e": {
+                        "host_name": "CoreAudio",
+                        "source_name": "mic",
+                        "sink_name": "phones",
+                        "source_channel": 0,
+                        "sink_channel": 1
+                    }
+                }
+            }"#,
+            Format::Json,
+        );
+        assert!(problems.is_empty());
+        assert_eq!(connections.len(), 1);
+    }
+
+    #[test]
+    fn route_problems() {
+        let paths = |s: &str| -> Vec<String> {
+            check(s, Format::Routes)
+                .0
+                .into_iter()
+                .map(|p| p.path)
+                .collect()
+        };
+        assert_eq!(paths("host a\nmic:x -> phones:1"), ["line 2"]);
+        assert_eq!(
+            paths("host a\nmic:0 -> phones:1\n\nmic:0 -> phones:1 gain=-3"),
+            ["line 4"]
+        );
+        assert_eq!(paths("host a\nmic:0 -> phones:0 pan=-2"), ["line 2.pan"]);
+        assert_eq!(
+            paths("host a\nmic:0 -> phones:1 delay=-1"),
+            ["line 2.delay_ms"]
+        );
+        assert_eq!(
+            paths("host a\nmic:0 -> phones:1 filter=lowpass:80 filter=highpass:0"),
+            ["line 2.filters[1].frequency"]
+        );
+        assert_eq!(
+            paths("host a\nmic:0 -> phones:1 insert=gain:gain_db=-3 insert=reverb"),
+            ["line 2.processors[1]"]
+        );
+        assert_eq!(
+            paths("host a\nmic:0 -> phones:1 gate=-40,hold=-5"),
+            ["line 2.gate.hold_ms"]
+        );
+        assert_eq!(
+            paths("host a\nmusic:0 -> phones:1 duck=mic:0,depth=0\nmic:0 -> phones:0"),
+            ["line 2.sidechain.depth_db"]
+        );
+        assert_eq!(
+            paths("host a\nmusic:0 -> phones:1 duck=mic:1\nmic:0 -> phones:0"),
+            ["line 2.sidechain"]
+        );
+        assert_eq!(
+            paths("host a\nmic:0 -> phones:1 link gain=-3"),
+            ["line 2.gain_db"]
+        );
+        assert_eq!(
+            paths("host a\nmusic:0 -> phones:1 duck=mic:0\nmic:0 -> phones:0 link"),
+            ["line 2.sidechain"]
+        );
+        assert_eq!(
+            paths("host a\nvirtual app lo lo\nvirtual app lo2 lo2\nvirtual game lo lo"),
+            ["line 3.name", "line 4.device"]
+        );
+    }
+
+    #[test]
+    fn problems() {
+        assert_eq!(paths("{"), ["$"]);
+        assert_eq!(paths(r#"{ "host": 1, "connections": {} }"#), ["$.host"]);
+        assert_eq!(
+            paths(
+                r#"{
+                    "host": "CoreAudio",
+                    "connections": {
+                        "not-a-uuid": {
+                            "host_name": "CoreAudio",
+                            "source_name": "mic",
+                            "sink_name": "phones",
+                            "source_channel": 0,
+                            "sink_channel": 1,
+                            "sink_chanel": 1
+                        },
+                        "2b1b6a3c-0b8e-4a5e-9d7e-6a8d6b1b7c0e": {
+                            "host_name": "CoreAudio",
+                            "source_name": "mic",
+                            "sink_name": "phones",
+                            "source_channel": -1,
+                            "sink_channel": 1
+                        },
+                        "7d3c1d0e-8a0f-4d5b-b1f3-1f6c2b0e4a9d": {
+                            "host_name": "CoreAudio",
+                            "source_name": "mic",
+                            "sink_name": "phones",
+                            "sink_channel": 1
+                        },
+                        "9e8f7a6b-5c4d-4e3f-8a1b-2c3d4e5f6a7b": {
+                            "host_name": "CoreAudio",
+                            "source_name": "mic",
+                            "sink_name": "phones",
+                            "source_channel": 0,
+                            "sink_channel": 1
+                        }
+                    }
+                }"#
+            ),
+            [
+                "$.connections.2b1b6a3c-0b8e-4a5e-9d7e-6a8d6b1b7c0e.source_channel",
+                "$.connections.7d3c1d0e-8a0f-4d5b-b1f3-1f6c2b0e4a9d",
+                "$.connections.not-a-uuid",
+                "$.connections.not-a-uuid.sink_chanel",
+                "$.connections.not-a-uuid",
+            ]
+        );
+    }
+}
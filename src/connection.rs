@@ -1,30 +1,337 @@
+use crate::dsp::{self, Ducking, Filter, Gate, Guard, PanLaw, Protection, SinkGuard, Status};
+#[cfg(feature = "jack")]
+use crate::jack_client;
+use crate::processor::{self, Context, Processor, ProcessorConfig};
 use crate::system;
 
 use anyhow::{anyhow, Result};
 use cpal::traits::{DeviceTrait, StreamTrait};
 use ringbuf::{HeapRb, Rb};
-use serde::de::Error;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
 
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Once, Weak};
+use std::thread;
 use std::time::Duration;
 
-const LATENCY: Duration = Duration::from_millis(2);
-const SAMPLE_RATE: u32 = 48000;
+pub const SAMPLE_RATE: u32 = 48000;
 
-#[derive(Serialize, Deserialize)]
-struct ConnectionMetadata {
-    host_name: String,
-    source_name: String,
-    sink_name: String,
-    source_channel: u16,
-    sink_channel: u16,
+/// Samples per channel that the audio callback processes without allocating.
+const BLOCK_CAPACITY: usize = 8192;
+
+/// Replaced processor chains and guards the sink callback can hand back before the
+/// control thread frees them.
+const RETIRED: usize = 16;
+
+/// Longest per-connection delay, in milliseconds.
+pub const MAX_DELAY_MS: f32 = 2000.0;
+
+static LATENCY_MICROS: AtomicU64 = AtomicU64::new(2000);
+
+/// How often the status of sink channels is checked for problems to log.
+const MONITOR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set the ring buffer latency used by connections created from now on.
+pub fn set_latency(latency: Duration) {
+    LATENCY_MICROS.store(latency.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub fn latency() -> Duration {
+    Duration::from_micros(LATENCY_MICROS.load(Ordering::Relaxed))
+}
+
+/// How the channels of a connection are mapped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Source channel to sink channel.
+    #[default]
+    Single,
+    /// Source channel panned across the sink channel and the one after it.
+    Pan,
+    /// Source channel and the one after it summed into the sink channel.
+    Sum,
+    /// Port of a JACK client linked directly to the port of another, without patchbay's
+    /// ports in between and so without gain, mute or processing.
+    Link,
+}
+
+impl Mode {
+    /// Source and sink channels used by a connection, as offsets from its channels.
+    pub fn offsets(&self) -> &'static [(u16, u16)] {
+        match self {
+            Mode::Single | Mode::Link => &[(0, 0)],
+            Mode::Pan => &[(0, 0), (0, 1)],
+            Mode::Sum => &[(0, 0), (1, 0)],
+        }
+    }
+
+    /// Number of consecutive source and sink channels used by a connection.
+    pub fn span(&self) -> (u16, u16) {
+        self.offsets()
+            .iter()
+            .fold((1, 1), |(source, sink), (s, k)| {
+                (source.max(s + 1), sink.max(k + 1))
+            })
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::Single => "single",
+            Mode::Pan => "pan",
+            Mode::Sum => "sum",
+            Mode::Link => "link",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Ducking of a connection by the level of a source channel, measured by the connections
+/// from that channel on the same host.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sidechain {
+    pub source_name: String,
+    pub source_channel: u16,
+    #[serde(flatten)]
+    pub ducking: Ducking,
+}
+
+/// Description of a connection as stored in configuration files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionMetadata {
+    pub host_name: String,
+    pub source_name: String,
+    pub sink_name: String,
+    pub source_channel: u16,
+    pub sink_channel: u16,
+    #[serde(default)]
+    pub gain_db: f32,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub delay_ms: f32,
+    /// Filters applied in order after the delay.
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    /// Noise gate applied after the filters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gate: Option<Gate>,
+    /// Processors applied in order after the noise gate.
+    #[serde(default)]
+    pub processors: Vec<ProcessorConfig>,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Position from -1 (left) to 1 (right) in pan mode.
+    #[serde(default)]
+    pub pan: f32,
+    #[serde(default)]
+    pub pan_law: PanLaw,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sidechain: Option<Sidechain>,
+}
+
+impl ConnectionMetadata {
+    /// Source and sink channel pairs carried by the route.
+    pub fn channels(&self) -> Vec<(u16, u16)> {
+        self.mode
+            .offsets()
+            .iter()
+            .map(|(source, sink)| (self.source_channel + source, self.sink_channel + sink))
+            .collect()
+    }
+
+    /// Route between two channels with every parameter at its default.
+    pub fn new(
+        host_name: String,
+        source_name: String,
+        sink_name: String,
+        source_channel: u16,
+        sink_channel: u16,
+    ) -> Self {
+        ConnectionMetadata {
+            host_name,
+            source_name,
+            sink_name,
+            source_channel,
+            sink_channel,
+            gain_db: 0.0,
+            muted: false,
+            delay_ms: 0.0,
+            filters: Vec::new(),
+            gate: None,
+            processors: Vec::new(),
+            mode: Mode::Single,
+            pan: 0.0,
+            pan_law: PanLaw::Power,
+            sidechain: None,
+        }
+    }
+
+    /// Check that a direct link has nothing to process, returning the name of the
+    /// offending field along with the problem.
+    pub fn check_link(&self) -> Result<(), (&'static str, String)> {
+        if self.mode != Mode::Link {
+            return Ok(());
+        }
+        let processing = [
+            ("gain_db", self.gain_db != 0.0, "gain"),
+            ("muted", self.muted, "mute"),
+            ("delay_ms", self.delay_ms != 0.0, "delay"),
+            ("filters", !self.filters.is_empty(), "filters"),
+            ("gate", self.gate.is_some(), "noise gate"),
+            ("processors", !self.processors.is_empty(), "processors"),
+            ("sidechain", self.sidechain.is_some(), "ducking"),
+        ];
+        match processing.into_iter().find(|(_, set, _)| *set) {
+            Some((field, _, name)) => Err((field, format!("Direct links have no {}", name))),
+            None => Ok(()),
+        }
+    }
+
+    /// Processing stages of the route in order, built from the processor registry: the
+    /// delay, the filters, the noise gate, the inserted processors and the ducking.
+    pub fn chain(&self) -> Vec<ProcessorConfig> {
+        #[derive(Serialize)]
+        struct Delay {
+            delay_ms: f32,
+        }
+
+        // the delay is always there, so that changing it crossfades from the current one
+        let mut chain = vec![ProcessorConfig::with_settings(
+            "delay",
+            &Delay {
+                delay_ms: self.delay_ms,
+            },
+        )];
+        chain.extend(
+            self.filters
+                .iter()
+                .map(|filter| ProcessorConfig::with_settings("filter", filter)),
+        );
+        chain.extend(
+            self.gate
+                .iter()
+                .map(|gate| ProcessorConfig::with_settings("gate", gate)),
+        );
+        chain.extend(self.processors.iter().cloned());
+        chain.extend(
+            self.sidechain
+                .iter()
+                .map(|sidechain| ProcessorConfig::with_settings("duck", &sidechain.ducking)),
+        );
+        chain
+    }
+}
+
+/// Parameters and meters shared between the control thread and the audio callbacks.
+struct Controls {
+    gain: AtomicU32,
+    muted: AtomicBool,
+    /// Left and right gains in pan mode, packed so that they always change together.
+    pan: AtomicU64,
+    /// Processor chain of a new connection, until its sink callback takes it over.
+    chain: Mutex<Vec<Box<dyn Processor>>>,
+    /// The processor chain is owned by the sink callback, so that no stage is ever
+    /// skipped, and replaced over a channel. Not set for connections without streams.
+    chains: Mutex<Option<ChainUpdates>>,
+    gate_open: Arc<AtomicBool>,
+    /// The guard is owned by the sink callback, so that it never runs without one, and
+    /// receives its updates over a channel. Not set for connections without streams.
+    guard: Mutex<Option<GuardUpdates>>,
+    /// Peak of the source channel in the last source callback, for sidechains.
+    level: Arc<AtomicU32>,
+    duck_gain: Arc<AtomicU32>,
+    clipping: AtomicBool,
+    dc_offset: AtomicBool,
+    tripped: AtomicBool,
+    source_peak: AtomicU32,
+    sink_peak: AtomicU32,
+    overruns: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+impl Controls {
+    /// Controls of a new connection running the given stages, built in the context.
+    fn new(context: &Context, chain: Vec<Box<dyn Processor>>) -> Self {
+        Controls {
+            gain: AtomicU32::new(1_f32.to_bits()),
+            muted: AtomicBool::new(false),
+            pan: AtomicU64::new(pack(dsp::pan_gains(0.0, PanLaw::Power))),
+            chain: Mutex::new(chain),
+            chains: Mutex::new(None),
+            gate_open: Arc::clone(&context.gate_open),
+            guard: Mutex::new(None),
+            level: Arc::new(AtomicU32::new(0)),
+            duck_gain: Arc::clone(&context.duck_gain),
+            clipping: AtomicBool::new(false),
+            dc_offset: AtomicBool::new(false),
+            tripped: AtomicBool::new(false),
+            source_peak: AtomicU32::new(0),
+            sink_peak: AtomicU32::new(0),
+            overruns: AtomicUsize::new(0),
+            underruns: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Level of the source channel of a connection, as seen by the connections it ducks.
+#[derive(Clone)]
+pub struct Level(Arc<AtomicU32>);
+
+impl Level {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Levels are equal when they are the level of the same source channel.
+impl PartialEq for Level {
+    fn eq(&self, other: &Level) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Snapshot of connection meters. Peaks are reset every time stats are read.
+pub struct Stats {
+    pub source_peak: f32,
+    pub sink_peak: f32,
+    pub overruns: usize,
+    pub underruns: usize,
+}
+
+/// Audio streams carrying a connection.
+enum Streams {
+    Cpal {
+        source: cpal::Stream,
+        sink: cpal::Stream,
+    },
+    #[cfg(feature = "jack")]
+    Jack(jack_client::Route),
+    #[cfg(feature = "jack")]
+    Link(jack_client::Link),
+    /// Connections on the test host carry no audio.
+    #[cfg(test)]
+    Test,
 }
 
 pub struct Connection {
-    source_stream: cpal::Stream,
-    sink_stream: cpal::Stream,
+    streams: Streams,
+    controls: Arc<Controls>,
+    latency: Duration,
+    /// Names of the source and sink devices. The metadata keeps the names they were
+    /// selected by, which may be aliases or virtual devices.
+    devices: (String, String),
     metadata: ConnectionMetadata,
+    /// Protection of the sink channel, set by the patchbay rather than stored with the
+    /// connection, as it is shared by every connection to the channel.
+    protection: Option<Arc<SinkGuard>>,
+    /// Stages of the running processor chain.
+    chain: Vec<ProcessorConfig>,
+    /// Level of the source channel that ducks the connection.
+    trigger: Option<Level>,
 }
 
 impl Connection {
@@ -34,106 +341,641 @@ impl Connection {
         sink_name: String,
         source_channel: u16,
         sink_channel: u16,
+        mode: Mode,
     ) -> Result<Self> {
+        let devices = (source_name.clone(), sink_name.clone());
+        let metadata = ConnectionMetadata {
+            mode,
+            ..ConnectionMetadata::new(
+                host_name,
+                source_name,
+                sink_name,
+                source_channel,
+                sink_channel,
+            )
+        };
+        Self::from_metadata(metadata, devices)
+    }
+
+    /// Connection between the given devices, described by metadata that may name them by
+    /// aliases or virtual devices. The processor chain is in place before the streams
+    /// open, so that the delay starts out at its length instead of fading in.
+    pub fn from_metadata(
+        metadata: ConnectionMetadata,
+        (source_device, sink_device): (String, String),
+    ) -> Result<Self> {
+        metadata
+            .check_link()
+            .map_err(|(_, message)| anyhow!(message))?;
+        let context = Context::new(SAMPLE_RATE);
+        let chain = metadata.chain();
+        let stages = chain
+            .iter()
+            .map(|stage| stage.build(&context))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut connection = Self::open(
+            metadata.host_name.clone(),
+            source_device,
+            sink_device,
+            metadata.source_channel,
+            metadata.sink_channel,
+            metadata.mode,
+            Controls::new(&context, stages),
+        )?;
+        connection.chain = chain;
+        connection.set_pan(metadata.pan, metadata.pan_law)?;
+        connection.set_gain_db(metadata.gain_db);
+        connection.set_muted(metadata.muted);
+        connection.metadata = metadata;
+        Ok(connection)
+    }
+
+    fn open(
+        host_name: String,
+        source_name: String,
+        sink_name: String,
+        source_channel: u16,
+        sink_channel: u16,
+        mode: Mode,
+        controls: Controls,
+    ) -> Result<Self> {
+        let controls = Arc::new(controls);
+
+        #[cfg(feature = "jack")]
+        if host_name == jack_client::HOST_NAME && mode == Mode::Link {
+            return Self::new_link(
+                host_name,
+                source_name,
+                sink_name,
+                source_channel,
+                sink_channel,
+                controls,
+            );
+        }
+        #[cfg(feature = "jack")]
+        if host_name == jack_client::HOST_NAME {
+            return Self::new_jack(
+                host_name,
+                source_name,
+                sink_name,
+                source_channel,
+                sink_channel,
+                mode,
+                controls,
+            );
+        }
+
+        #[cfg(test)]
+        if host_name == system::TEST_HOST {
+            return Ok(Connection {
+                streams: Streams::Test,
+                controls,
+                latency: Duration::ZERO,
+                devices: (source_name.clone(), sink_name.clone()),
+                metadata: ConnectionMetadata::new(
+                    host_name,
+                    source_name,
+                    sink_name,
+                    source_channel,
+                    sink_channel,
+                ),
+                protection: None,
+                chain: Vec::new(),
+                trigger: None,
+            }
+            .with_mode(mode));
+        }
+
+        if mode == Mode::Link {
+            return Err(anyhow!(
+                "Only the ports of JACK clients can be linked directly, not devices on {}",
+                host_name
+            ));
+        }
+
         let source_device = system::find_input_device(&host_name, &source_name)?;
         let sink_device = system::find_output_device(&host_name, &sink_name)?;
 
+        let (source_channels, sink_channels) = mode.span();
         let (source_config, sink_config) = Self::find_matching_configs(
             &source_device,
             &sink_device,
-            source_channel,
-            sink_channel,
+            source_channel + source_channels,
+            sink_channel + sink_channels,
         )?;
 
         let max_channels = std::cmp::max(source_config.channels, sink_config.channels);
-        let ringbuf = Self::create_ringbuf(SAMPLE_RATE, &LATENCY, max_channels);
-        let capacity = ringbuf.capacity();
-        let (mut producer, mut consumer) = ringbuf.split();
-
-        for _ in 0..capacity {
-            producer.push(0.0).unwrap();
-        }
+        let latency = latency();
+        let ringbuf = Self::create_ringbuf(SAMPLE_RATE, &latency, max_channels);
+        let prefill = ringbuf.capacity();
+        let (mut source_cb, mut sink_cb) = callbacks(
+            mode,
+            (source_channel as usize, source_config.channels as usize),
+            (sink_channel as usize, sink_config.channels as usize),
+            ringbuf,
+            prefill,
+            &controls,
+            format!("{}({})", sink_name, sink_channel),
+        );
 
-        let source_cb = move |samples: &[f32], _: &cpal::InputCallbackInfo| {
-            producer.push_iter(
-                &mut samples
-                    .iter()
-                    .cloned()
-                    .skip(source_channel as usize)
-                    .step_by(source_config.channels as usize),
-            );
+        let err_cb = |err: cpal::StreamError| {
+            log::error!("Streaming error: {}", err);
         };
 
-        let sink_cb = move |samples: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            samples
-                .iter_mut()
-                .skip(sink_channel as usize)
-                .step_by(sink_config.channels as usize)
-                .for_each(|sample| *sample = consumer.pop().unwrap_or(0_f32));
-        };
+        Ok(Connection {
+            streams: Streams::Cpal {
+                source: source_device.build_input_stream(
+                    &source_config,
+                    move |samples: &[f32], _: &cpal::InputCallbackInfo| source_cb(samples),
+                    err_cb,
+                    None,
+                )?,
+                sink: sink_device.build_output_stream(
+                    &sink_config,
+                    move |samples: &mut [f32], _: &cpal::OutputCallbackInfo| sink_cb(samples),
+                    err_cb,
+                    None,
+                )?,
+            },
+            controls,
+            latency,
+            devices: (source_name.clone(), sink_name.clone()),
+            metadata: ConnectionMetadata::new(
+                host_name,
+                source_name,
+                sink_name,
+                source_channel,
+                sink_channel,
+            ),
+            protection: None,
+            chain: Vec::new(),
+            trigger: None,
+        }
+        .with_mode(mode))
+    }
 
-        let err_cb = |err: cpal::StreamError| {
-            eprintln!("Streaming error: {}", err);
-        };
+    /// Connection through ports of the patchbay JACK client. Both callbacks run in the
+    /// same process cycle, the source one first, so the ring buffer needs no headroom and
+    /// adds no latency.
+    #[cfg(feature = "jack")]
+    fn new_jack(
+        host_name: String,
+        source_name: String,
+        sink_name: String,
+        source_channel: u16,
+        sink_channel: u16,
+        mode: Mode,
+        controls: Arc<Controls>,
+    ) -> Result<Self> {
+        let (source_channels, sink_channels) = mode.span();
+        let (source_cb, sink_cb) = callbacks(
+            mode,
+            (0, source_channels as usize),
+            (0, sink_channels as usize),
+            HeapRb::new(BLOCK_CAPACITY),
+            0,
+            &controls,
+            format!("{}({})", sink_name, sink_channel),
+        );
+        let route = jack_client::Route::new(
+            (
+                &source_name,
+                source_channel..source_channel + source_channels,
+            ),
+            (&sink_name, sink_channel..sink_channel + sink_channels),
+            source_cb,
+            sink_cb,
+        )?;
 
         Ok(Connection {
-            source_stream: source_device.build_input_stream(
-                &source_config,
-                source_cb,
-                err_cb,
-                None,
-            )?,
-            sink_stream: sink_device.build_output_stream(&sink_config, sink_cb, err_cb, None)?,
-            metadata: ConnectionMetadata {
+            streams: Streams::Jack(route),
+            controls,
+            latency: Duration::ZERO,
+            devices: (source_name.clone(), sink_name.clone()),
+            metadata: ConnectionMetadata::new(
                 host_name,
                 source_name,
+                sink_name,
                 source_channel,
+                sink_channel,
+            ),
+            protection: None,
+            chain: Vec::new(),
+            trigger: None,
+        }
+        .with_mode(mode))
+    }
+
+    /// Direct link between ports of two JACK clients, which only needs the ports to be
+    /// connected while it runs.
+    #[cfg(feature = "jack")]
+    fn new_link(
+        host_name: String,
+        source_name: String,
+        sink_name: String,
+        source_channel: u16,
+        sink_channel: u16,
+        controls: Arc<Controls>,
+    ) -> Result<Self> {
+        let link =
+            jack_client::Link::new((&source_name, source_channel), (&sink_name, sink_channel))?;
+
+        Ok(Connection {
+            streams: Streams::Link(link),
+            controls,
+            latency: Duration::ZERO,
+            devices: (source_name.clone(), sink_name.clone()),
+            metadata: ConnectionMetadata::new(
+                host_name,
+                source_name,
                 sink_name,
+                source_channel,
                 sink_channel,
-            },
-        })
+            ),
+            protection: None,
+            chain: Vec::new(),
+            trigger: None,
+        }
+        .with_mode(Mode::Link))
     }
 
     pub fn run(&self) -> Result<()> {
-        self.source_stream.play()?;
-        self.sink_stream.play()?;
+        match &self.streams {
+            Streams::Cpal { source, sink } => {
+                source.play()?;
+                sink.play()?;
+            }
+            #[cfg(feature = "jack")]
+            Streams::Jack(route) => route.run(),
+            #[cfg(feature = "jack")]
+            Streams::Link(link) => link.run()?,
+            #[cfg(test)]
+            Streams::Test => {}
+        }
         Ok(())
     }
 
     pub fn halt(&self) -> Result<()> {
-        self.sink_stream.pause()?;
-        self.source_stream.pause()?;
+        match &self.streams {
+            Streams::Cpal { source, sink } => {
+                sink.pause()?;
+                source.pause()?;
+            }
+            #[cfg(feature = "jack")]
+            Streams::Jack(route) => route.halt(),
+            #[cfg(feature = "jack")]
+            Streams::Link(link) => link.halt()?,
+            #[cfg(test)]
+            Streams::Test => {}
+        }
         Ok(())
     }
 
-    fn from_metadata(metadata: ConnectionMetadata) -> Result<Self> {
-        Self::new(
-            metadata.host_name,
-            metadata.source_name,
-            metadata.sink_name,
-            metadata.source_channel,
-            metadata.sink_channel,
-        )
+    pub fn metadata(&self) -> &ConnectionMetadata {
+        &self.metadata
+    }
+
+    /// Metadata naming the devices themselves, for comparing the routes of connections
+    /// selected by different names.
+    pub fn route(&self) -> ConnectionMetadata {
+        ConnectionMetadata {
+            source_name: self.devices.0.clone(),
+            sink_name: self.devices.1.clone(),
+            ..self.metadata.clone()
+        }
+    }
+
+    /// Keep the names the devices were selected by, so that they are saved instead of
+    /// the device names.
+    pub fn with_names(mut self, source_name: String, sink_name: String) -> Self {
+        self.metadata.source_name = source_name;
+        self.metadata.sink_name = sink_name;
+        self
+    }
+
+    pub fn host_name(&self) -> &str {
+        &self.metadata.host_name
+    }
+
+    pub fn source_name(&self) -> &str {
+        &self.metadata.source_name
+    }
+
+    pub fn source_device(&self) -> &str {
+        &self.devices.0
+    }
+
+    pub fn source_channel(&self) -> u16 {
+        self.metadata.source_channel
+    }
+
+    pub fn sink_name(&self) -> &str {
+        &self.metadata.sink_name
+    }
+
+    pub fn sink_device(&self) -> &str {
+        &self.devices.1
+    }
+
+    pub fn sink_channel(&self) -> u16 {
+        self.metadata.sink_channel
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.metadata.mode
+    }
+
+    fn with_mode(mut self, mode: Mode) -> Self {
+        self.metadata.mode = mode;
+        self
+    }
+
+    /// Source and sink channel pairs carried by the connection.
+    pub fn channels(&self) -> Vec<(u16, u16)> {
+        self.metadata.channels()
+    }
+
+    pub fn pan(&self) -> f32 {
+        self.metadata.pan
+    }
+
+    pub fn pan_law(&self) -> PanLaw {
+        self.metadata.pan_law
+    }
+
+    /// Set the position of a connection in pan mode, from -1 (left) to 1 (right).
+    pub fn set_pan(&mut self, pan: f32, pan_law: PanLaw) -> Result<()> {
+        if !(-1.0..=1.0).contains(&pan) {
+            return Err(anyhow!("Pan {} out of range (-1 to 1)", pan));
+        }
+        if self.metadata.mode != Mode::Pan && (pan != 0.0 || pan_law != PanLaw::Power) {
+            return Err(anyhow!("Connection is not in pan mode"));
+        }
+        self.metadata.pan = pan;
+        self.metadata.pan_law = pan_law;
+        self.controls
+            .pan
+            .store(pack(dsp::pan_gains(pan, pan_law)), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.metadata.gain_db
+    }
+
+    pub fn set_gain_db(&mut self, gain_db: f32) {
+        self.metadata.gain_db = gain_db;
+        self.controls
+            .gain
+            .store(db_to_linear(gain_db).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.metadata.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.metadata.muted = muted;
+        self.controls.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn delay_ms(&self) -> f32 {
+        self.metadata.delay_ms
+    }
+
+    pub fn set_delay_ms(&mut self, delay_ms: f32) -> Result<()> {
+        if !(0.0..=MAX_DELAY_MS).contains(&delay_ms) {
+            return Err(anyhow!(
+                "Delay {}ms out of range (0 to {}ms)",
+                delay_ms,
+                MAX_DELAY_MS
+            ));
+        }
+        self.change(|m| m.delay_ms = delay_ms)
+    }
+
+    pub fn filters(&self) -> &[Filter] {
+        &self.metadata.filters
+    }
+
+    /// Replace the filters. Filters keep their state when retuned, so adjusting a filter
+    /// of a running connection does not interrupt it.
+    pub fn set_filters(&mut self, filters: Vec<Filter>) -> Result<()> {
+        for filter in &filters {
+            filter
+                .check(SAMPLE_RATE)
+                .map_err(|(_, message)| anyhow!(message))?;
+        }
+        self.change(|m| m.filters = filters)
+    }
+
+    pub fn gate(&self) -> Option<&Gate> {
+        self.metadata.gate.as_ref()
+    }
+
+    /// Replace the noise gate. An open gate stays open when its settings change.
+    pub fn set_gate(&mut self, gate: Option<Gate>) -> Result<()> {
+        if let Some(gate) = &gate {
+            gate.check().map_err(|(_, message)| anyhow!(message))?;
+        }
+        self.change(|m| m.gate = gate)
+    }
+
+    /// Whether the noise gate currently lets the signal through.
+    pub fn gate_open(&self) -> bool {
+        self.metadata.gate.is_some() && self.controls.gate_open.load(Ordering::Relaxed)
+    }
+
+    pub fn processors(&self) -> &[ProcessorConfig] {
+        &self.metadata.processors
+    }
+
+    /// Replace the inserted processors. Processors take over the state of the processor of
+    /// the same name they replace, if they support it, and start from a clean state
+    /// otherwise.
+    pub fn set_processors(&mut self, processors: Vec<ProcessorConfig>) -> Result<()> {
+        self.change(|m| m.processors = processors)
+    }
+
+    pub fn sidechain(&self) -> Option<&Sidechain> {
+        self.metadata.sidechain.as_ref()
+    }
+
+    /// Replace the ducking settings. The gain reduction carries over, so changing the
+    /// settings of a ducked connection does not make it jump back up.
+    pub fn set_sidechain(&mut self, sidechain: Option<Sidechain>) -> Result<()> {
+        if let Some(sidechain) = &sidechain {
+            sidechain
+                .ducking
+                .check()
+                .map_err(|(_, message)| anyhow!(message))?;
+        }
+        let released = sidechain.is_none();
+        self.change(|m| m.sidechain = sidechain)?;
+        if released {
+            self.set_trigger(None)?;
+            self.controls
+                .duck_gain
+                .store(1_f32.to_bits(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Level of the source channel, for the connections it ducks.
+    pub fn level(&self) -> Level {
+        Level(Arc::clone(&self.controls.level))
+    }
+
+    /// Follow the level of the source channel given in the sidechain, or release the
+    /// ducking without a level.
+    pub fn set_trigger(&mut self, level: Option<Level>) -> Result<()> {
+        if level == self.trigger {
+            return Ok(());
+        }
+        self.trigger = level;
+        // the ducking gets the level when it is built
+        if self.chain.iter().any(|stage| stage.name == "duck") {
+            self.swap_chain(self.chain.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Apply a change to the metadata, rebuilding the processor chain if the change
+    /// affects it.
+    fn change(&mut self, change: impl FnOnce(&mut ConnectionMetadata)) -> Result<()> {
+        let mut metadata = self.metadata.clone();
+        change(&mut metadata);
+        let chain = metadata.chain();
+        if chain != self.chain {
+            self.swap_chain(chain)?;
+        }
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Build a processor chain from the registry and hand it to the sink callback. Stages
+    /// take over the state of the stage of the same name they replace, so the delay
+    /// crossfades to its new length and filters, gate and ducking carry on.
+    fn swap_chain(&mut self, chain: Vec<ProcessorConfig>) -> Result<()> {
+        let context = Context {
+            sample_rate: SAMPLE_RATE,
+            sidechain: self.trigger.clone(),
+            gate_open: Arc::clone(&self.controls.gate_open),
+            duck_gain: Arc::clone(&self.controls.duck_gain),
+        };
+        let stages = chain
+            .iter()
+            .map(|stage| stage.build(&context))
+            .collect::<Result<Vec<_>>>()?;
+        let continuity = processor::continuity(&chain, &self.chain);
+        let updates = self
+            .controls
+            .chains
+            .lock()
+            .map_err(|_| anyhow!("Processor chain poisoned"))?;
+        if let Some(updates) = updates.as_ref() {
+            // chains replaced since the last change are freed here rather than on the
+            // audio thread
+            updates.retired.try_iter().for_each(drop);
+            // sending only fails once the sink callback is gone, with nothing to process
+            let _ = updates.chains.send(Chain { stages, continuity });
+        }
+        drop(updates);
+        self.chain = chain;
+        Ok(())
+    }
+
+    /// Current gain reduction by the sidechain, in dB.
+    pub fn duck_db(&self) -> f32 {
+        20.0 * f32::from_bits(self.controls.duck_gain.load(Ordering::Relaxed)).log10()
+    }
+
+    pub fn protection(&self) -> Option<&Arc<SinkGuard>> {
+        self.protection.as_ref()
+    }
+
+    /// Share the protection of the sink channel with the other connections to it, or
+    /// remove it.
+    pub fn set_protection(&mut self, protection: Option<Arc<SinkGuard>>) -> Result<()> {
+        let guard = match &protection {
+            Some(sink) => Guard::shared(sink, SAMPLE_RATE).ok_or(anyhow!(
+                "Sink {}({}) cannot protect more than {} connections",
+                self.metadata.sink_name,
+                self.metadata.sink_channel,
+                dsp::SINK_CONNECTIONS
+            ))?,
+            None => Guard::new(&Protection::default(), SAMPLE_RATE),
+        };
+        self.update_guard(GuardUpdate::Replace(guard))?;
+        self.controls.tripped.store(
+            protection.as_ref().is_some_and(|sink| sink.tripped()),
+            Ordering::Relaxed,
+        );
+        self.protection = protection;
+        Ok(())
+    }
+
+    /// Problems detected on the sink channel.
+    pub fn status(&self) -> Status {
+        Status {
+            clipping: self.controls.clipping.load(Ordering::Relaxed),
+            dc_offset: self.controls.dc_offset.load(Ordering::Relaxed),
+            tripped: self.controls.tripped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Unmute the sink channel after the protection tripped, along with the other
+    /// connections to it.
+    pub fn rearm(&self) -> Result<()> {
+        self.update_guard(GuardUpdate::Rearm)?;
+        self.controls.tripped.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn update_guard(&self, update: GuardUpdate) -> Result<()> {
+        let guard = self
+            .controls
+            .guard
+            .lock()
+            .map_err(|_| anyhow!("Guard poisoned"))?;
+        if let Some(guard) = guard.as_ref() {
+            // guards replaced since the last update are freed here rather than on the
+            // audio thread
+            guard.retired.try_iter().for_each(drop);
+            // sending only fails once the sink callback is gone, with nothing to protect
+            let _ = guard.updates.send(update);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            source_peak: f32::from_bits(self.controls.source_peak.swap(0, Ordering::Relaxed)),
+            sink_peak: f32::from_bits(self.controls.sink_peak.swap(0, Ordering::Relaxed)),
+            overruns: self.controls.overruns.load(Ordering::Relaxed),
+            underruns: self.controls.underruns.load(Ordering::Relaxed),
+        }
     }
 
     fn find_matching_configs(
         source_device: &cpal::Device,
         sink_device: &cpal::Device,
-        source_channel: u16,
-        sink_channel: u16,
+        source_channels: u16,
+        sink_channels: u16,
     ) -> Result<(cpal::StreamConfig, cpal::StreamConfig)> {
         let sample_rate = cpal::SampleRate(SAMPLE_RATE);
 
         // TODO: find common sample rate
         let mut supported_source_configs = source_device
             .supported_input_configs()?
-            .filter(|config| config.channels() >= source_channel)
+            .filter(|config| config.channels() >= source_channels)
             .filter(|config| config.min_sample_rate() <= sample_rate)
             .filter(|config| config.max_sample_rate() >= sample_rate);
 
         let mut supported_sink_configs = sink_device
             .supported_output_configs()?
-            .filter(|config| config.channels() >= sink_channel)
+            .filter(|config| config.channels() >= sink_channels)
             .filter(|config| config.min_sample_rate() <= sample_rate)
             .filter(|config| config.max_sample_rate() >= sample_rate);
 
@@ -160,38 +1002,348 @@ impl Connection {
     }
 }
 
+/// Audio callbacks of a connection, taking interleaved frames of the given width. The
+/// source callback passes the source channel on through the ring buffer, starting with
+/// `prefill` samples of silence, and the sink callback processes it and writes it to the
+/// sink channel.
+fn callbacks(
+    mode: Mode,
+    (source, source_width): (usize, usize),
+    (sink, sink_width): (usize, usize),
+    ringbuf: HeapRb<f32>,
+    prefill: usize,
+    controls: &Arc<Controls>,
+    sink_label: String,
+) -> (
+    impl FnMut(&[f32]) + Send + 'static,
+    impl FnMut(&mut [f32]) + Send + 'static,
+) {
+    let (mut producer, mut consumer) = ringbuf.split();
+    for _ in 0..prefill {
+        producer.push(0.0).unwrap();
+    }
+
+    let source_controls = Arc::clone(controls);
+    let source_cb = move |samples: &[f32]| {
+        let mut peak = 0_f32;
+        let mut level = 0_f32;
+        let mut frames = samples
+            .chunks(source_width)
+            .map(|frame| {
+                level = level.max(frame[source].abs());
+                match mode {
+                    // halved so that correlated channels do not clip
+                    Mode::Sum => (frame[source] + frame[source + 1]) * 0.5,
+                    Mode::Single | Mode::Pan | Mode::Link => frame[source],
+                }
+            })
+            .inspect(|sample| peak = peak.max(sample.abs()));
+
+        producer.push_iter(&mut frames);
+
+        // anything left over did not fit in the ring buffer
+        if frames.count() > 0 {
+            source_controls.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        // bit patterns of non-negative floats order the same way as their values
+        source_controls
+            .source_peak
+            .fetch_max(peak.to_bits(), Ordering::Relaxed);
+        source_controls
+            .level
+            .store(level.to_bits(), Ordering::Relaxed);
+    };
+
+    let (guard_updates, updates) = mpsc::channel();
+    let (retire_guard, retired) = mpsc::sync_channel(RETIRED);
+    if let Ok(mut guard) = controls.guard.lock() {
+        *guard = Some(GuardUpdates {
+            updates: guard_updates,
+            retired,
+        });
+    }
+    monitor(controls, sink_label);
+
+    let (chains, chain_updates) = mpsc::channel();
+    let (retire, retired) = mpsc::sync_channel(RETIRED);
+    if let Ok(mut updates) = controls.chains.lock() {
+        *updates = Some(ChainUpdates { chains, retired });
+    }
+    let mut chain = controls
+        .chain
+        .lock()
+        .map(|mut chain| std::mem::take(&mut *chain))
+        .unwrap_or_default();
+
+    let sink_controls = Arc::clone(controls);
+    let mut guard = Guard::new(&Protection::default(), SAMPLE_RATE);
+    // processors work on blocks of the connection's channel, gathered here
+    let mut block = Vec::with_capacity(BLOCK_CAPACITY);
+    let mut status = Status::default();
+    let sink_cb = move |samples: &mut [f32]| {
+        let gain = if sink_controls.muted.load(Ordering::Relaxed) {
+            0_f32
+        } else {
+            f32::from_bits(sink_controls.gain.load(Ordering::Relaxed))
+        };
+        let mut peak = 0_f32;
+        let mut starved = false;
+
+        block.clear();
+        block.extend(
+            (0..samples.len() / sink_width).map(|_| match consumer.pop() {
+                Some(s) => s,
+                None => {
+                    starved = true;
+                    0_f32
+                }
+            }),
+        );
+
+        for Chain {
+            mut stages,
+            continuity,
+        } in chain_updates.try_iter()
+        {
+            for (stage, previous) in stages.iter_mut().zip(&continuity) {
+                if let Some(previous) = previous.and_then(|i| chain.get_mut(i)) {
+                    stage.continue_from(previous.as_mut());
+                }
+            }
+            let stages = std::mem::replace(&mut chain, stages);
+            // only freed here if the control thread has not collected the retired chains
+            let _ = retire.try_send(Chain { stages, continuity });
+        }
+        for stage in chain.iter_mut() {
+            stage.process(&mut block);
+        }
+
+        let (left, right) = unpack(sink_controls.pan.load(Ordering::Relaxed));
+        for update in updates.try_iter() {
+            match update {
+                GuardUpdate::Replace(new) => {
+                    let mut old = std::mem::replace(&mut guard, new);
+                    // leaves the ceiling to the other connections right away, while the
+                    // guard is only freed once the control thread collects it
+                    old.leave();
+                    let _ = retire_guard.try_send(old);
+                }
+                GuardUpdate::Rearm => guard.reset(),
+            }
+        }
+        samples
+            .chunks_mut(sink_width)
+            .zip(&block)
+            .for_each(|(frame, output)| {
+                let output = guard.process(output * gain);
+                match mode {
+                    Mode::Pan => {
+                        frame[sink] = output * left;
+                        frame[sink + 1] = output * right;
+                    }
+                    Mode::Single | Mode::Sum | Mode::Link => frame[sink] = output,
+                }
+                peak = peak.max(output.abs());
+            });
+        let current = guard.status();
+        if current != status {
+            publish(&sink_controls, current);
+            status = current;
+        }
+
+        if starved {
+            sink_controls.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        sink_controls
+            .sink_peak
+            .fetch_max(peak.to_bits(), Ordering::Relaxed);
+    };
+
+    (source_cb, sink_cb)
+}
+
+/// Convert a delay to samples at the connection sample rate.
+pub fn ms_to_samples(ms: f32) -> usize {
+    (ms * SAMPLE_RATE as f32 / 1000.0).round() as usize
+}
+
+/// Convert a number of samples at the connection sample rate to a delay.
+pub fn samples_to_ms(samples: usize) -> f32 {
+    samples as f32 * 1000.0 / SAMPLE_RATE as f32
+}
+
+/// Processor chain replacing the one the sink callback runs, with the stage of the running
+/// chain each stage takes over from.
+struct Chain {
+    stages: Vec<Box<dyn Processor>>,
+    continuity: Vec<Option<usize>>,
+}
+
+/// Control thread ends of the channels that hand processor chains to the sink callback,
+/// and the chains they replace back to be freed.
+struct ChainUpdates {
+    chains: mpsc::Sender<Chain>,
+    retired: mpsc::Receiver<Chain>,
+}
+
+/// Control thread ends of the channels that update the guard of the sink callback, and
+/// hand the guards it replaces back to be freed.
+struct GuardUpdates {
+    updates: mpsc::Sender<GuardUpdate>,
+    retired: mpsc::Receiver<Guard>,
+}
+
+/// Change of the guard requested by the control thread.
+enum GuardUpdate {
+    Replace(Guard),
+    Rearm,
+}
+
+/// Publish a new status of the sink channel, for [`monitor`] to log.
+fn publish(controls: &Controls, current: Status) {
+    controls.clipping.store(current.clipping, Ordering::Relaxed);
+    controls
+        .dc_offset
+        .store(current.dc_offset, Ordering::Relaxed);
+    controls.tripped.store(current.tripped, Ordering::Relaxed);
+}
+
+/// Sink channel whose status is watched by the monitor thread.
+struct Monitored {
+    controls: Weak<Controls>,
+    sink: String,
+    status: Status,
+}
+
+/// Log the problems appearing on the sink channel of a connection for as long as it
+/// exists. Logging can block, so it happens on a thread of its own rather than in the
+/// audio callbacks.
+fn monitor(controls: &Arc<Controls>, sink: String) {
+    static MONITORED: Mutex<Vec<Monitored>> = Mutex::new(Vec::new());
+    static STARTED: Once = Once::new();
+
+    if let Ok(mut monitored) = MONITORED.lock() {
+        monitored.push(Monitored {
+            controls: Arc::downgrade(controls),
+            sink,
+            status: Status::default(),
+        });
+    }
+    STARTED.call_once(|| {
+        thread::spawn(|| loop {
+            thread::sleep(MONITOR_INTERVAL);
+            let Ok(mut monitored) = MONITORED.lock() else {
+                return;
+            };
+            monitored.retain_mut(|m| {
+                let Some(controls) = m.controls.upgrade() else {
+                    return false;
+                };
+                let current = Status {
+                    clipping: controls.clipping.load(Ordering::Relaxed),
+                    dc_offset: controls.dc_offset.load(Ordering::Relaxed),
+                    tripped: controls.tripped.load(Ordering::Relaxed),
+                };
+                report(&m.sink, m.status, current);
+                m.status = current;
+                true
+            });
+        });
+    });
+}
+
+/// Log the problems that appeared on a sink channel.
+fn report(sink: &str, previous: Status, current: Status) {
+    if current.clipping && !previous.clipping {
+        log::warn!("Sustained clipping on {}", sink);
+    }
+    if current.dc_offset && !previous.dc_offset {
+        log::warn!("DC offset on {}", sink);
+    }
+    if current.tripped && !previous.tripped {
+        log::error!(
+            "Level on {} too high for too long, muted until rearmed",
+            sink
+        );
+    }
+}
+
+fn pack((left, right): (f32, f32)) -> u64 {
+    (left.to_bits() as u64) << 32 | right.to_bits() as u64
+}
+
+fn unpack(gains: u64) -> (f32, f32) {
+    (
+        f32::from_bits((gains >> 32) as u32),
+        f32::from_bits(gains as u32),
+    )
+}
+
+fn db_to_linear(gain_db: f32) -> f32 {
+    10_f32.powf(gain_db / 20.0)
+}
+
 impl fmt::Display for Connection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}({}) -> {}({}) [{}; {}Hz; {}ms] ",
+            "{}({}) -> {}({}) [{}; {}Hz; {}ms; {:+.1}dB] ",
             self.metadata.source_name,
             self.metadata.source_channel,
             self.metadata.sink_name,
             self.metadata.sink_channel,
             self.metadata.host_name,
             SAMPLE_RATE,
-            LATENCY.as_millis()
+            self.latency.as_millis(),
+            self.metadata.gain_db
         )?;
+        match self.metadata.mode {
+            Mode::Single => (),
+            Mode::Pan => write!(f, "(pan {} {}) ", self.metadata.pan, self.metadata.pan_law)?,
+            Mode::Sum => write!(
+                f,
+                "(sum of {}+{}) ",
+                self.metadata.source_channel,
+                self.metadata.source_channel + 1
+            )?,
+            Mode::Link => write!(f, "(link) ")?,
+        }
+        if self.metadata.delay_ms > 0.0 {
+            write!(f, "(delay {}ms) ", self.metadata.delay_ms)?;
+        }
+        for (i, filter) in self.metadata.filters.iter().enumerate() {
+            write!(f, "(filter {}: {}) ", i, filter)?;
+        }
+        if let Some(gate) = &self.metadata.gate {
+            let state = if self.gate_open() { "open" } else { "closed" };
+            write!(f, "(gate {}: {}) ", gate, state)?;
+        }
+        for (i, processor) in self.metadata.processors.iter().enumerate() {
+            write!(f, "(insert {}: {}) ", i, processor)?;
+        }
+        if let Some(sidechain) = &self.metadata.sidechain {
+            write!(
+                f,
+                "(ducked by {}({}) {}; now {:+.1}dB) ",
+                sidechain.source_name,
+                sidechain.source_channel,
+                sidechain.ducking,
+                self.duck_db()
+            )?;
+        }
+        if self.metadata.muted {
+            write!(f, "(muted) ")?;
+        }
+        let status = self.status();
+        if status.tripped {
+            write!(f, "(TRIPPED) ")?;
+        }
+        if status.clipping {
+            write!(f, "(clipping) ")?;
+        }
+        if status.dc_offset {
+            write!(f, "(DC offset) ")?;
+        }
         Ok(())
     }
 }
-
-impl Serialize for Connection {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        self.metadata.serialize(serializer)
-    }
-}
-
-impl<'de> Deserialize<'de> for Connection {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let metadata = ConnectionMetadata::deserialize(deserializer)?;
-        Ok(Connection::from_metadata(metadata).map_err(D::Error::custom)?)
-    }
-}
@@ -0,0 +1,106 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reply to a command sent over the control socket.
+#[derive(Serialize, Deserialize)]
+pub struct Response {
+    pub output: String,
+    pub error: Option<String>,
+}
+
+/// Path of the socket a running daemon accepts commands on. Without a runtime directory it
+/// falls back to the shared temporary directory, so the socket is only accessible by its owner.
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("patchbay.sock")
+}
+
+/// Control socket listened on by the daemon.
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    pub fn bind() -> Result<Self> {
+        let path = socket_path();
+
+        // only one instance runs at a time, so any existing socket is stale
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(Server { listener, path })
+    }
+
+    /// Accept a pending request without blocking.
+    pub fn accept(&self) -> Result<Option<Request>> {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+
+        Ok(Some(Request {
+            args: serde_json::from_str(&line)?,
+            stream,
+        }))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Command received over the control socket, split into arguments.
+pub struct Request {
+    args: Vec<String>,
+    stream: UnixStream,
+}
+
+impl Request {
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn respond(mut self, response: Response) -> Result<()> {
+        serde_json::to_writer(&mut self.stream, &response)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Send a command to the running daemon.
+pub fn send(args: &[String]) -> Result<Response> {
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| anyhow!("Could not connect to daemon at {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+
+    serde_json::to_writer(&mut stream, args)?;
+    stream.write_all(b"\n")?;
+
+    Ok(serde_json::from_reader(&stream)?)
+}
@@ -0,0 +1,1392 @@
+use serde::{Deserialize, Serialize};
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Length of the crossfade between the old and new delay when the delay changes, in samples.
+const DELAY_FADE: usize = 480;
+
+/// Delay line with a fixed maximum length, allocated up front so that it can run in the
+/// audio callback. Changes of the delay crossfade between the old and new read positions
+/// instead of jumping, so they do not click.
+pub struct Delay {
+    buffer: Vec<f32>,
+    write: usize,
+    current: usize,
+    target: usize,
+    previous: usize,
+    fade: usize,
+}
+
+impl Delay {
+    pub fn new(max_samples: usize) -> Self {
+        Delay {
+            buffer: vec![0.0; max_samples + 1],
+            write: 0,
+            current: 0,
+            target: 0,
+            previous: 0,
+            fade: 0,
+        }
+    }
+
+    /// Set the delay, clamped to the maximum. Takes effect once any crossfade in progress
+    /// has finished.
+    pub fn set(&mut self, samples: usize) {
+        self.target = samples.min(self.buffer.len() - 1);
+    }
+
+    /// Set the delay immediately, without crossfading. Only meant for delays that are set
+    /// before any audio goes through them.
+    pub fn jump(&mut self, samples: usize) {
+        self.set(samples);
+        self.current = self.target;
+        self.fade = 0;
+    }
+
+    /// Take over the line of the delay being replaced and crossfade from its delay to this
+    /// one's, so that changing the delay of a running connection does not click. Lines of
+    /// different lengths cannot be taken over.
+    pub fn continue_from(&mut self, other: &mut Delay) {
+        if self.buffer.len() == other.buffer.len() {
+            let target = self.target;
+            std::mem::swap(self, other);
+            self.set(target);
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.buffer[self.write] = sample;
+
+        if self.fade == 0 && self.target != self.current {
+            self.previous = self.current;
+            self.current = self.target;
+            self.fade = DELAY_FADE;
+        }
+
+        let mut output = self.tap(self.current);
+        if self.fade > 0 {
+            let old = self.fade as f32 / DELAY_FADE as f32;
+            output = output * (1.0 - old) + self.tap(self.previous) * old;
+            self.fade -= 1;
+        }
+
+        self.write = (self.write + 1) % self.buffer.len();
+        output
+    }
+
+    fn tap(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.write + len - delay) % len]
+    }
+}
+
+/// Response of a biquad filter.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    HighPass,
+    LowPass,
+    LowShelf,
+    HighShelf,
+    Peaking,
+}
+
+impl FilterKind {
+    /// Whether the filter boosts or cuts by `gain_db`, rather than passing or blocking.
+    pub fn has_gain(&self) -> bool {
+        matches!(
+            self,
+            FilterKind::LowShelf | FilterKind::HighShelf | FilterKind::Peaking
+        )
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "highpass" | "hp" => Ok(FilterKind::HighPass),
+            "lowpass" | "lp" => Ok(FilterKind::LowPass),
+            "lowshelf" => Ok(FilterKind::LowShelf),
+            "highshelf" => Ok(FilterKind::HighShelf),
+            "peaking" | "peak" => Ok(FilterKind::Peaking),
+            _ => Err(anyhow::anyhow!(
+                "Unknown filter '{}' (expected highpass, lowpass, lowshelf, highshelf or peaking)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FilterKind::HighPass => "highpass",
+            FilterKind::LowPass => "lowpass",
+            FilterKind::LowShelf => "lowshelf",
+            FilterKind::HighShelf => "highshelf",
+            FilterKind::Peaking => "peaking",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Filter settings as stored in configuration files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub frequency: f32,
+    #[serde(default = "default_q")]
+    pub q: f32,
+    #[serde(default)]
+    pub gain_db: f32,
+}
+
+/// Butterworth response for pass filters, and a moderate bandwidth for the others.
+pub const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+fn default_q() -> f32 {
+    DEFAULT_Q
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, frequency: f32) -> Self {
+        Filter {
+            kind,
+            frequency,
+            q: DEFAULT_Q,
+            gain_db: 0.0,
+        }
+    }
+
+    /// Check the settings against the sample rate, returning the name of the offending
+    /// field along with the problem.
+    pub fn check(&self, sample_rate: u32) -> Result<(), (&'static str, String)> {
+        let nyquist = sample_rate as f32 / 2.0;
+        if !(self.frequency > 0.0 && self.frequency < nyquist) {
+            return Err((
+                "frequency",
+                format!(
+                    "Frequency {}Hz out of range (0 to {}Hz)",
+                    self.frequency, nyquist
+                ),
+            ));
+        }
+        if !(self.q > 0.0 && self.q.is_finite()) {
+            return Err(("q", format!("Q {} must be positive", self.q)));
+        }
+        if !self.gain_db.is_finite() {
+            return Err(("gain_db", format!("Invalid gain {}", self.gain_db)));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}Hz Q{}", self.kind, self.frequency, self.q)?;
+        if self.kind.has_gain() {
+            write!(f, " {:+}dB", self.gain_db)?;
+        }
+        Ok(())
+    }
+}
+
+/// Biquad filter with coefficients from the Audio EQ Cookbook, computed in double
+/// precision to keep low frequency filters stable.
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    pub fn new(filter: &Filter, sample_rate: u32) -> Self {
+        let w0 = 2.0 * PI * filter.frequency as f64 / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * filter.q as f64);
+        let a = 10_f64.powf(filter.gain_db as f64 / 40.0);
+
+        let (b0, b1, b2, a0, a1, a2) = match filter.kind {
+            FilterKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::LowShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + s),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - s),
+                    (a + 1.0) + (a - 1.0) * cos + s,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - s,
+                )
+            }
+            FilterKind::HighShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + s),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - s),
+                    (a + 1.0) - (a - 1.0) * cos + s,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - s,
+                )
+            }
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+        };
+
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    /// Continue from the state of another filter, so that retuning a running filter does
+    /// not restart it from silence.
+    pub fn continue_from(&mut self, other: &Biquad) {
+        self.x1 = other.x1;
+        self.x2 = other.x2;
+        self.y1 = other.y1;
+        self.y2 = other.y2;
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let x = sample as f64;
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y as f32
+    }
+}
+
+/// Peak limiter that reduces gain instantly when a sample would exceed the ceiling and
+/// recovers exponentially, so the output never exceeds the ceiling.
+pub struct Limiter {
+    ceiling: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(ceiling_db: f32, release_ms: f32, sample_rate: u32) -> Self {
+        Limiter {
+            ceiling: 10_f32.powf(ceiling_db / 20.0),
+            release: (-1.0 / (release_ms / 1000.0 * sample_rate as f32)).exp(),
+            gain: 1.0,
+        }
+    }
+
+    /// Current gain reduction, as a linear factor.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Take over the gain reduction of the limiter being replaced, so that changing its
+    /// settings does not let a peak through.
+    pub fn continue_from(&mut self, other: &Limiter) {
+        self.gain = other.gain;
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.limit(sample, self.ceiling)
+    }
+
+    /// Limit a sample to a ceiling below the configured one, for a limiter sharing its
+    /// ceiling with others.
+    fn limit(&mut self, sample: f32, ceiling: f32) -> f32 {
+        // recover towards unity before checking the sample, then clamp down if needed
+        self.gain = 1.0 - (1.0 - self.gain) * self.release;
+        let peak = sample.abs();
+        if peak * self.gain > ceiling {
+            self.gain = ceiling / peak;
+        }
+        (sample * self.gain).clamp(-ceiling, ceiling)
+    }
+}
+
+/// Level at which samples are considered clipped.
+const CLIP_LEVEL: f32 = 0.999;
+
+/// Consecutive clipped samples that count as sustained clipping rather than a stray peak.
+const CLIP_RUN: usize = 4;
+
+/// Average level above which a signal is considered to carry a DC offset.
+const DC_LEVEL: f32 = 0.05;
+
+/// Connections that can share the protection of a sink channel.
+pub const SINK_CONNECTIONS: usize = 16;
+
+/// Time the trip level has to be exceeded before the sink is muted, by default.
+pub const DEFAULT_TRIP_MS: f32 = 500.0;
+
+/// Output protection settings of a sink channel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Protection {
+    /// Ceiling of the brickwall limiter, no limiter if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ceiling_db: Option<f32>,
+    /// Level that mutes the sink once exceeded for `trip_ms`, never muted if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trip_db: Option<f32>,
+    #[serde(default = "default_trip_ms")]
+    pub trip_ms: f32,
+}
+
+fn default_trip_ms() -> f32 {
+    DEFAULT_TRIP_MS
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Protection {
+            ceiling_db: None,
+            trip_db: None,
+            trip_ms: DEFAULT_TRIP_MS,
+        }
+    }
+}
+
+impl Protection {
+    /// Check the settings, returning the name of the offending field along with the
+    /// problem.
+    pub fn check(&self) -> Result<(), (&'static str, String)> {
+        if let Some(ceiling_db) = self.ceiling_db.filter(|db| *db > 0.0 || db.is_nan()) {
+            return Err((
+                "ceiling_db",
+                format!("Ceiling {}dB must be at most 0dB", ceiling_db),
+            ));
+        }
+        if let Some(trip_db) = self.trip_db.filter(|db| *db > 0.0 || db.is_nan()) {
+            return Err((
+                "trip_db",
+                format!("Trip level {}dB must be at most 0dB", trip_db),
+            ));
+        }
+        if !(self.trip_ms > 0.0 && self.trip_ms.is_finite()) {
+            return Err((
+                "trip_ms",
+                format!("Trip time {}ms must be positive", self.trip_ms),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ceiling_db {
+            Some(ceiling_db) => write!(f, "limit {}dB", ceiling_db)?,
+            None => write!(f, "no limit")?,
+        }
+        if let Some(trip_db) = self.trip_db {
+            write!(f, ", mute above {}dB for {}ms", trip_db, self.trip_ms)?;
+        }
+        Ok(())
+    }
+}
+
+/// Problems detected by a [`Guard`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Status {
+    /// Sustained clipping within the last second.
+    pub clipping: bool,
+    pub dc_offset: bool,
+    /// Muted after exceeding the trip level, until reset.
+    pub tripped: bool,
+}
+
+/// Protection of a sink channel shared by the guards of every connection to it, so that
+/// the trip level and the ceiling apply to the sum of the connections. Each guard
+/// publishes the level it receives and the peak it may send, and the ceiling is split
+/// between them by their levels without the peaks ever adding up to more than it.
+pub struct SinkGuard {
+    protection: Protection,
+    slots: [GuardSlot; SINK_CONNECTIONS],
+    tripped: AtomicBool,
+    /// Counts rearms, so that every guard restarts timing the trip level.
+    rearms: AtomicUsize,
+}
+
+/// Levels a guard publishes to the others protecting the same sink channel.
+#[derive(Default)]
+struct GuardSlot {
+    used: AtomicBool,
+    level: AtomicU32,
+    limit: AtomicU32,
+}
+
+impl SinkGuard {
+    pub fn new(protection: &Protection) -> Self {
+        SinkGuard {
+            protection: protection.clone(),
+            slots: Default::default(),
+            tripped: AtomicBool::new(false),
+            rearms: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn protection(&self) -> &Protection {
+        &self.protection
+    }
+
+    /// Whether the sink channel is muted until rearmed.
+    pub fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+}
+
+/// Last stage of a sink channel: detects clipping and DC offset, mutes the output once the
+/// trip level is exceeded for too long and limits it to the ceiling.
+pub struct Guard {
+    limiter: Option<Limiter>,
+    /// Protection shared with the other connections to the sink channel, and the slot
+    /// of this guard in it.
+    sink: Option<(Arc<SinkGuard>, usize)>,
+    rearms: usize,
+    trip_level: f32,
+    trip_samples: usize,
+    over: usize,
+    envelope: f32,
+    envelope_release: f32,
+    clip_run: usize,
+    clip_hold: usize,
+    hold_samples: usize,
+    dc: f32,
+    dc_coefficient: f32,
+    tripped: bool,
+}
+
+impl Guard {
+    pub fn new(protection: &Protection, sample_rate: u32) -> Self {
+        let samples = |ms: f32| (ms / 1000.0 * sample_rate as f32) as usize;
+        Guard {
+            limiter: protection
+                .ceiling_db
+                .map(|ceiling_db| Limiter::new(ceiling_db, 50.0, sample_rate)),
+            sink: None,
+            rearms: 0,
+            trip_level: protection
+                .trip_db
+                .map_or(f32::INFINITY, |db| 10_f32.powf(db / 20.0)),
+            trip_samples: samples(protection.trip_ms).max(1),
+            over: 0,
+            envelope: 0.0,
+            envelope_release: (-1.0 / samples(50.0) as f32).exp(),
+            clip_run: 0,
+            clip_hold: 0,
+            hold_samples: sample_rate as usize,
+            dc: 0.0,
+            // averages over about a second
+            dc_coefficient: 1.0 / sample_rate as f32,
+            tripped: false,
+        }
+    }
+
+    /// Guard of a connection to a protected sink channel, or `None` if the channel already
+    /// has as many connections as can share its protection.
+    pub fn shared(sink: &Arc<SinkGuard>, sample_rate: u32) -> Option<Self> {
+        let slot = sink.slots.iter().position(|slot| {
+            slot.used
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        let mut guard = Guard::new(&sink.protection, sample_rate);
+        guard.sink = Some((Arc::clone(sink), slot));
+        guard.rearms = sink.rearms.load(Ordering::Relaxed);
+        Some(guard)
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            clipping: self.clip_hold > 0,
+            dc_offset: self.dc.abs() > DC_LEVEL,
+            tripped: self.tripped(),
+        }
+    }
+
+    fn tripped(&self) -> bool {
+        match &self.sink {
+            Some((sink, _)) => sink.tripped.load(Ordering::Relaxed),
+            None => self.tripped,
+        }
+    }
+
+    /// Unmute after tripping, along with every other connection to a shared sink channel.
+    pub fn reset(&mut self) {
+        self.tripped = false;
+        self.over = 0;
+        if let Some((sink, _)) = &self.sink {
+            sink.tripped.store(false, Ordering::Relaxed);
+            self.rearms = sink.rearms.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+    }
+
+    /// Stop sharing the protection of the sink channel, leaving the whole ceiling to the
+    /// other connections.
+    pub fn leave(&mut self) {
+        if let Some((sink, slot)) = &self.sink {
+            let slot = &sink.slots[*slot];
+            slot.level.store(0, Ordering::Relaxed);
+            slot.limit.store(0, Ordering::Relaxed);
+            slot.used.store(false, Ordering::Release);
+        }
+        self.sink = None;
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let level = sample.abs();
+
+        self.dc += (sample - self.dc) * self.dc_coefficient;
+
+        self.clip_hold = self.clip_hold.saturating_sub(1);
+        if level >= CLIP_LEVEL {
+            self.clip_run += 1;
+            if self.clip_run >= CLIP_RUN {
+                self.clip_hold = self.hold_samples;
+            }
+        } else {
+            self.clip_run = 0;
+        }
+
+        // the envelope bridges zero crossings, so a loud tone counts as exceeding the level
+        self.envelope = level.max(self.envelope * self.envelope_release);
+        let Some((sink, slot)) = &self.sink else {
+            if self.envelope > self.trip_level {
+                self.over += 1;
+                if self.over >= self.trip_samples {
+                    self.tripped = true;
+                }
+            } else {
+                self.over = 0;
+            }
+
+            if self.tripped {
+                return 0.0;
+            }
+            return match &mut self.limiter {
+                Some(limiter) => limiter.process(sample),
+                None => sample,
+            };
+        };
+
+        // levels of the other connections, and the peaks they may send
+        let (others, taken) = sink
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i != slot)
+            .fold((0_f32, 0_f32), |(others, taken), (_, other)| {
+                (
+                    others + f32::from_bits(other.level.load(Ordering::Relaxed)),
+                    taken + f32::from_bits(other.limit.load(Ordering::Relaxed)),
+                )
+            });
+        let own = &sink.slots[*slot];
+        own.level.store(self.envelope.to_bits(), Ordering::Relaxed);
+
+        let rearms = sink.rearms.load(Ordering::Relaxed);
+        if rearms != self.rearms {
+            self.rearms = rearms;
+            self.over = 0;
+        }
+        // the sum of the levels bounds the level of the summed signal
+        let total = self.envelope + others;
+        if total > self.trip_level {
+            self.over += 1;
+            if self.over >= self.trip_samples {
+                sink.tripped.store(true, Ordering::Relaxed);
+            }
+        } else {
+            self.over = 0;
+        }
+
+        if sink.tripped.load(Ordering::Relaxed) {
+            own.limit.store(0, Ordering::Relaxed);
+            return 0.0;
+        }
+        match &mut self.limiter {
+            Some(limiter) => {
+                // a share of the ceiling by level, but never more than the others leave
+                let share = match total > 0.0 {
+                    true => limiter.ceiling * self.envelope / total,
+                    false => limiter.ceiling,
+                };
+                let ceiling = share.min(limiter.ceiling - taken).max(0.0);
+                own.limit.store(ceiling.to_bits(), Ordering::Relaxed);
+                limiter.limit(sample, ceiling)
+            }
+            None => sample,
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
+
+/// How the level of a panned signal is split between the left and right channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PanLaw {
+    /// Constant power, -3dB on each side in the center.
+    #[default]
+    Power,
+    /// Constant amplitude, -6dB on each side in the center.
+    Linear,
+    /// Full level on both sides in the center, attenuating only the opposite side.
+    Balance,
+}
+
+impl FromStr for PanLaw {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "power" => Ok(PanLaw::Power),
+            "linear" => Ok(PanLaw::Linear),
+            "balance" => Ok(PanLaw::Balance),
+            _ => Err(anyhow::anyhow!(
+                "Unknown pan law '{}' (expected power, linear or balance)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PanLaw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PanLaw::Power => "power",
+            PanLaw::Linear => "linear",
+            PanLaw::Balance => "balance",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Left and right gains of a signal panned from -1 (left) to 1 (right).
+pub fn pan_gains(pan: f32, law: PanLaw) -> (f32, f32) {
+    let pan = pan.clamp(-1.0, 1.0);
+    match law {
+        PanLaw::Power => {
+            let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            (angle.cos(), angle.sin())
+        }
+        PanLaw::Linear => ((1.0 - pan) / 2.0, (1.0 + pan) / 2.0),
+        PanLaw::Balance => ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0)),
+    }
+}
+
+/// Gain reduction of a connection while another connection's source is active.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ducking {
+    /// Level of the triggering source above which the connection is ducked.
+    #[serde(default = "default_threshold_db")]
+    pub threshold_db: f32,
+    /// Gain reduction once fully ducked.
+    #[serde(default = "default_depth_db")]
+    pub depth_db: f32,
+    #[serde(default = "default_attack_ms")]
+    pub attack_ms: f32,
+    #[serde(default = "default_release_ms")]
+    pub release_ms: f32,
+}
+
+fn default_threshold_db() -> f32 {
+    -40.0
+}
+
+fn default_depth_db() -> f32 {
+    12.0
+}
+
+fn default_attack_ms() -> f32 {
+    10.0
+}
+
+fn default_release_ms() -> f32 {
+    500.0
+}
+
+impl Default for Ducking {
+    fn default() -> Self {
+        Ducking {
+            threshold_db: default_threshold_db(),
+            depth_db: default_depth_db(),
+            attack_ms: default_attack_ms(),
+            release_ms: default_release_ms(),
+        }
+    }
+}
+
+impl Ducking {
+    /// Check the settings, returning the name of the offending field along with the
+    /// problem.
+    pub fn check(&self) -> Result<(), (&'static str, String)> {
+        if self.threshold_db > 0.0 || self.threshold_db.is_nan() {
+            return Err((
+                "threshold_db",
+                format!("Threshold {}dB must be at most 0dB", self.threshold_db),
+            ));
+        }
+        if !(self.depth_db > 0.0 && self.depth_db.is_finite()) {
+            return Err((
+                "depth_db",
+                format!("Depth {}dB must be positive", self.depth_db),
+            ));
+        }
+        for (field, name, ms) in [
+            ("attack_ms", "Attack", self.attack_ms),
+            ("release_ms", "Release", self.release_ms),
+        ] {
+            if !(ms >= 0.0 && ms.is_finite()) {
+                return Err((field, format!("{} {}ms must not be negative", name, ms)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Ducking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "-{}dB above {}dB, attack {}ms, release {}ms",
+            self.depth_db, self.threshold_db, self.attack_ms, self.release_ms
+        )
+    }
+}
+
+/// Gain that follows the level of a sidechain, moving towards the ducked gain with the
+/// attack time while the level is above the threshold and back to unity with the release
+/// time once it falls below.
+pub struct Ducker {
+    threshold: f32,
+    depth: f32,
+    attack: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Ducker {
+    pub fn new(ducking: &Ducking, sample_rate: u32) -> Self {
+        // zero times give a coefficient of zero, so the gain jumps to its target
+        let coefficient = |ms: f32| (-1.0 / (ms / 1000.0 * sample_rate as f32)).exp();
+        Ducker {
+            threshold: 10_f32.powf(ducking.threshold_db / 20.0),
+            depth: 10_f32.powf(-ducking.depth_db / 20.0),
+            attack: coefficient(ducking.attack_ms),
+            release: coefficient(ducking.release_ms),
+            gain: 1.0,
+        }
+    }
+
+    /// Current gain, as a linear factor.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Take over the gain of the ducker being replaced, so changing the settings does not
+    /// jump back to unity.
+    pub fn continue_from(&mut self, other: &Ducker) {
+        self.gain = other.gain;
+    }
+
+    /// Advance by one sample given the sidechain level, returning the gain.
+    pub fn process(&mut self, level: f32) -> f32 {
+        let (target, coefficient) = if level > self.threshold {
+            (self.depth, self.attack)
+        } else {
+            (1.0, self.release)
+        };
+        self.gain = target + (self.gain - target) * coefficient;
+        self.gain
+    }
+}
+
+/// Noise gate settings. The gate opens when the level rises above the threshold and
+/// closes once it has stayed below the threshold minus the hysteresis for the hold time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gate {
+    #[serde(default = "default_gate_threshold_db")]
+    pub threshold_db: f32,
+    #[serde(default = "default_hysteresis_db")]
+    pub hysteresis_db: f32,
+    #[serde(default = "default_gate_attack_ms")]
+    pub attack_ms: f32,
+    #[serde(default = "default_hold_ms")]
+    pub hold_ms: f32,
+    #[serde(default = "default_gate_release_ms")]
+    pub release_ms: f32,
+}
+
+fn default_gate_threshold_db() -> f32 {
+    -50.0
+}
+
+fn default_hysteresis_db() -> f32 {
+    6.0
+}
+
+fn default_gate_attack_ms() -> f32 {
+    1.0
+}
+
+fn default_hold_ms() -> f32 {
+    50.0
+}
+
+fn default_gate_release_ms() -> f32 {
+    100.0
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Gate {
+            threshold_db: default_gate_threshold_db(),
+            hysteresis_db: default_hysteresis_db(),
+            attack_ms: default_gate_attack_ms(),
+            hold_ms: default_hold_ms(),
+            release_ms: default_gate_release_ms(),
+        }
+    }
+}
+
+impl Gate {
+    /// Check the settings, returning the name of the offending field along with the
+    /// problem.
+    pub fn check(&self) -> Result<(), (&'static str, String)> {
+        if self.threshold_db > 0.0 || self.threshold_db.is_nan() {
+            return Err((
+                "threshold_db",
+                format!("Threshold {}dB must be at most 0dB", self.threshold_db),
+            ));
+        }
+        for (field, name, value, unit) in [
+            ("hysteresis_db", "Hysteresis", self.hysteresis_db, "dB"),
+            ("attack_ms", "Attack", self.attack_ms, "ms"),
+            ("hold_ms", "Hold", self.hold_ms, "ms"),
+            ("release_ms", "Release", self.release_ms, "ms"),
+        ] {
+            if !(value >= 0.0 && value.is_finite()) {
+                return Err((
+                    field,
+                    format!("{} {}{} must not be negative", name, value, unit),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Gate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}dB, hysteresis {}dB, attack {}ms, hold {}ms, release {}ms",
+            self.threshold_db, self.hysteresis_db, self.attack_ms, self.hold_ms, self.release_ms
+        )
+    }
+}
+
+/// Noise gate following the peak envelope of the signal, which fades in with the attack
+/// time when it opens and out with the release time once the hold time has passed.
+pub struct NoiseGate {
+    open_level: f32,
+    close_level: f32,
+    attack: f32,
+    release: f32,
+    hold_samples: usize,
+    hold: usize,
+    envelope: f32,
+    envelope_release: f32,
+    open: bool,
+    gain: f32,
+}
+
+impl NoiseGate {
+    pub fn new(gate: &Gate, sample_rate: u32) -> Self {
+        let samples = |ms: f32| ms / 1000.0 * sample_rate as f32;
+        // zero times give a coefficient of zero, so the gain jumps to its target
+        let coefficient = |ms: f32| (-1.0 / samples(ms)).exp();
+        NoiseGate {
+            open_level: 10_f32.powf(gate.threshold_db / 20.0),
+            close_level: 10_f32.powf((gate.threshold_db - gate.hysteresis_db) / 20.0),
+            attack: coefficient(gate.attack_ms),
+            release: coefficient(gate.release_ms),
+            hold_samples: samples(gate.hold_ms) as usize,
+            hold: 0,
+            envelope: 0.0,
+            // bridges zero crossings of low tones, the hold time covers longer gaps
+            envelope_release: coefficient(10.0),
+            open: false,
+            gain: 0.0,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Take over the state of the gate being replaced, so changing the settings of an
+    /// open gate does not cut the signal.
+    pub fn continue_from(&mut self, other: &NoiseGate) {
+        self.envelope = other.envelope;
+        self.open = other.open;
+        self.gain = other.gain;
+        self.hold = other.hold.min(self.hold_samples);
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.envelope = sample.abs().max(self.envelope * self.envelope_release);
+
+        if self.envelope > self.open_level || (self.open && self.envelope > self.close_level) {
+            self.open = true;
+            self.hold = self.hold_samples;
+        } else if self.hold > 0 {
+            self.hold -= 1;
+        } else {
+            self.open = false;
+        }
+
+        let (target, coefficient) = if self.open {
+            (1.0, self.attack)
+        } else {
+            (0.0, self.release)
+        };
+        self.gain = target + (self.gain - target) * coefficient;
+        sample * self.gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay() {
+        let mut delay = Delay::new(10);
+        assert_eq!(delay.process(1.0), 1.0);
+
+        let mut delay = Delay::new(10);
+        delay.set(3);
+        // the first samples fade from the undelayed signal
+        let output: Vec<f32> = (0..DELAY_FADE + 8)
+            .map(|n| delay.process(n as f32))
+            .collect();
+        let settled = &output[DELAY_FADE..];
+        for (n, sample) in settled.iter().enumerate() {
+            assert_eq!(*sample, (DELAY_FADE + n - 3) as f32);
+        }
+    }
+
+    #[test]
+    fn smooth_change() {
+        let mut delay = Delay::new(100);
+        let mut last = 0.0;
+        for n in 0..200 {
+            last = delay.process(n as f32);
+        }
+        delay.set(100);
+
+        // a ramp jumps back by the delay, the crossfade spreads the jump out
+        for n in 200..1000 {
+            let sample = delay.process(n as f32);
+            assert!((sample - last).abs() <= 1.0 + 100.0 / DELAY_FADE as f32 + 1e-3);
+            last = sample;
+        }
+        assert_eq!(last, 899.0);
+    }
+
+    #[test]
+    fn continued() {
+        let mut previous = Delay::new(100);
+        let mut last = 0.0;
+        for n in 0..200 {
+            last = previous.process(n as f32);
+        }
+        let mut delay = Delay::new(100);
+        delay.jump(100);
+        delay.continue_from(&mut previous);
+
+        // the new delay takes over the line and crossfades like a live change
+        for n in 200..1000 {
+            let sample = delay.process(n as f32);
+            assert!((sample - last).abs() <= 1.0 + 100.0 / DELAY_FADE as f32 + 1e-3);
+            last = sample;
+        }
+        assert_eq!(last, 899.0);
+    }
+
+    #[test]
+    fn clamped() {
+        let mut delay = Delay::new(4);
+        delay.set(1000);
+        let output: Vec<f32> = (0..DELAY_FADE + 10)
+            .map(|n| delay.process(n as f32))
+            .collect();
+        assert_eq!(output[DELAY_FADE + 9], (DELAY_FADE + 5) as f32);
+    }
+
+    /// Peak output level of a filter driven by a sine wave, once settled.
+    fn response(filter: &Filter, frequency: f32) -> f32 {
+        let mut biquad = Biquad::new(filter, 48000);
+        (0..48000)
+            .map(|n| (2.0 * std::f32::consts::PI * frequency * n as f32 / 48000.0).sin())
+            .map(|x| biquad.process(x))
+            .skip(24000)
+            .fold(0.0, |peak, y| peak.max(y.abs()))
+    }
+
+    fn db(level: f32) -> f32 {
+        20.0 * level.log10()
+    }
+
+    #[test]
+    fn filters() {
+        let highpass = Filter::new(FilterKind::HighPass, 1000.0);
+        assert!(db(response(&highpass, 100.0)) < -35.0);
+        assert!((db(response(&highpass, 1000.0)) + 3.0).abs() < 0.1);
+        assert!(db(response(&highpass, 10000.0)).abs() < 0.1);
+
+        let lowpass = Filter::new(FilterKind::LowPass, 1000.0);
+        assert!(db(response(&lowpass, 100.0)).abs() < 0.1);
+        assert!(db(response(&lowpass, 10000.0)) < -35.0);
+
+        let peaking = Filter {
+            gain_db: 6.0,
+            ..Filter::new(FilterKind::Peaking, 1000.0)
+        };
+        assert!((db(response(&peaking, 1000.0)) - 6.0).abs() < 0.1);
+        assert!(db(response(&peaking, 50.0)).abs() < 0.1);
+
+        let shelf = Filter {
+            gain_db: -6.0,
+            ..Filter::new(FilterKind::LowShelf, 200.0)
+        };
+        assert!((db(response(&shelf, 20.0)) + 6.0).abs() < 0.2);
+        assert!(db(response(&shelf, 10000.0)).abs() < 0.1);
+    }
+
+    #[test]
+    fn filter_settings() {
+        assert_eq!("HP".parse::<FilterKind>().unwrap(), FilterKind::HighPass);
+        assert!("bandpass".parse::<FilterKind>().is_err());
+
+        assert!(Filter::new(FilterKind::LowPass, 1000.0)
+            .check(48000)
+            .is_ok());
+        assert_eq!(
+            Filter::new(FilterKind::LowPass, 30000.0)
+                .check(48000)
+                .unwrap_err()
+                .0,
+            "frequency"
+        );
+        let filter = Filter {
+            q: 0.0,
+            ..Filter::new(FilterKind::LowPass, 1000.0)
+        };
+        assert_eq!(filter.check(48000).unwrap_err().0, "q");
+    }
+
+    #[test]
+    fn limiter() {
+        let mut limiter = Limiter::new(-6.0, 10.0, 48000);
+        let ceiling = 10_f32.powf(-6.0 / 20.0);
+        assert!((limiter.process(1.0) - ceiling).abs() < 1e-6);
+        assert!(limiter.gain() < 0.51);
+        assert!(limiter.process(0.1).abs() < 0.1);
+
+        // recovers to unity once the signal stays below the ceiling
+        for _ in 0..48000 {
+            limiter.process(0.1);
+        }
+        assert!((limiter.process(0.1) - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn guard() {
+        let protection = Protection {
+            ceiling_db: Some(-6.0),
+            trip_db: Some(-3.0),
+            trip_ms: 10.0,
+        };
+        let mut guard = Guard::new(&protection, 48000);
+        let tone = |n: usize| (n as f32 * 0.1).sin();
+
+        // a quiet tone passes unchanged
+        for n in 0..4800 {
+            assert_eq!(guard.process(tone(n) * 0.1), tone(n) * 0.1);
+        }
+        assert_eq!(guard.status(), Status::default());
+
+        // a loud tone is limited, then muted
+        for n in 0..240 {
+            assert!(guard.process(tone(n)).abs() <= 0.5012);
+        }
+        assert!(!guard.status().tripped);
+        for n in 240..600 {
+            guard.process(tone(n));
+        }
+        assert!(guard.status().tripped);
+        assert_eq!(guard.process(1.0), 0.0);
+
+        guard.reset();
+        assert!(!guard.status().tripped);
+        assert_ne!(guard.process(0.1), 0.0);
+    }
+
+    #[test]
+    fn shared_guard() {
+        let protection = Protection {
+            ceiling_db: Some(-6.0),
+            trip_db: Some(-3.0),
+            trip_ms: 10.0,
+        };
+        let sink = Arc::new(SinkGuard::new(&protection));
+        let mut first = Guard::shared(&sink, 48000).unwrap();
+        let mut second = Guard::shared(&sink, 48000).unwrap();
+        let tone = |n: usize| (n as f32 * 0.1).sin() * 0.4;
+
+        // each connection alone is below the ceiling and the trip level, their sum is not
+        for n in 0..240 {
+            let sum = first.process(tone(n)) + second.process(tone(n));
+            assert!(sum.abs() <= 0.5012);
+        }
+        assert!(!first.status().tripped);
+        for n in 240..600 {
+            first.process(tone(n));
+            second.process(tone(n));
+        }
+        assert!(first.status().tripped);
+        assert!(second.status().tripped);
+        assert_eq!(second.process(tone(600)), 0.0);
+
+        // rearming either connection unmutes both, and a connection leaving frees its
+        // share of the ceiling
+        second.reset();
+        assert!(!first.status().tripped);
+        drop(second);
+        for n in 0..48000 {
+            first.process(tone(n));
+        }
+        assert!((first.process(tone(1)) - tone(1)).abs() < 1e-3);
+        assert!(!first.status().tripped);
+
+        let guards = (1..SINK_CONNECTIONS)
+            .map(|_| Guard::shared(&sink, 48000).unwrap())
+            .collect::<Vec<_>>();
+        assert!(Guard::shared(&sink, 48000).is_none());
+        drop(guards);
+        assert!(Guard::shared(&sink, 48000).is_some());
+    }
+
+    #[test]
+    fn detection() {
+        let mut guard = Guard::new(&Protection::default(), 48000);
+        for _ in 0..CLIP_RUN - 1 {
+            guard.process(1.0);
+        }
+        guard.process(0.0);
+        assert!(!guard.status().clipping);
+        for _ in 0..CLIP_RUN {
+            guard.process(1.0);
+        }
+        assert!(guard.status().clipping);
+        // without a trip level the guard never mutes
+        assert!(!guard.status().tripped);
+        assert_eq!(guard.process(1.0), 1.0);
+
+        let mut guard = Guard::new(&Protection::default(), 48000);
+        for n in 0..48000 {
+            guard.process((n as f32 * 0.1).sin() * 0.5 + 0.2);
+        }
+        assert!(guard.status().dc_offset);
+        assert!(!guard.status().clipping);
+    }
+
+    #[test]
+    fn protection_settings() {
+        assert!(Protection::default().check().is_ok());
+        let protection = Protection {
+            ceiling_db: Some(3.0),
+            ..Protection::default()
+        };
+        assert_eq!(protection.check().unwrap_err().0, "ceiling_db");
+        let protection = Protection {
+            trip_ms: 0.0,
+            ..Protection::default()
+        };
+        assert_eq!(protection.check().unwrap_err().0, "trip_ms");
+    }
+
+    #[test]
+    fn panning() {
+        let db = |gain: f32| 20.0 * gain.log10();
+        let (left, right) = pan_gains(0.0, PanLaw::Power);
+        assert!((db(left) + 3.01).abs() < 0.01 && (db(right) + 3.01).abs() < 0.01);
+        let (left, right) = pan_gains(0.0, PanLaw::Linear);
+        assert_eq!((left, right), (0.5, 0.5));
+        assert_eq!(pan_gains(0.0, PanLaw::Balance), (1.0, 1.0));
+
+        for law in [PanLaw::Power, PanLaw::Linear, PanLaw::Balance] {
+            let (left, right) = pan_gains(-1.0, law);
+            assert!((left - 1.0).abs() < 1e-6 && right.abs() < 1e-6);
+            assert_eq!(pan_gains(2.0, law), pan_gains(1.0, law));
+        }
+        assert_eq!(pan_gains(0.5, PanLaw::Balance), (0.5, 1.0));
+    }
+
+    #[test]
+    fn ducker() {
+        let ducking = Ducking {
+            attack_ms: 1.0,
+            release_ms: 10.0,
+            ..Ducking::default()
+        };
+        let mut ducker = Ducker::new(&ducking, 48000);
+        assert_eq!(ducker.process(0.001), 1.0);
+
+        // fully ducked well after the attack time, and not before
+        assert!(db(ducker.process(0.5)) > -1.0);
+        for _ in 0..480 {
+            ducker.process(0.5);
+        }
+        assert!((db(ducker.gain()) + 12.0).abs() < 0.1);
+
+        // back to unity after the release time
+        for _ in 0..4800 {
+            ducker.process(0.0);
+        }
+        assert!(db(ducker.gain()).abs() < 0.1);
+
+        let instant = Ducking {
+            attack_ms: 0.0,
+            ..Ducking::default()
+        };
+        assert!((db(Ducker::new(&instant, 48000).process(1.0)) + 12.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ducking_settings() {
+        assert!(Ducking::default().check().is_ok());
+        let ducking = Ducking {
+            depth_db: 0.0,
+            ..Ducking::default()
+        };
+        assert_eq!(ducking.check().unwrap_err().0, "depth_db");
+        let ducking = Ducking {
+            threshold_db: f32::NAN,
+            ..Ducking::default()
+        };
+        assert_eq!(ducking.check().unwrap_err().0, "threshold_db");
+        let ducking = Ducking {
+            release_ms: -1.0,
+            ..Ducking::default()
+        };
+        assert_eq!(ducking.check().unwrap_err().0, "release_ms");
+        assert_eq!(
+            Ducking::default().to_string(),
+            "-12dB above -40dB, attack 10ms, release 500ms"
+        );
+    }
+
+    #[test]
+    fn gate() {
+        let gate = Gate {
+            threshold_db: -20.0,
+            hysteresis_db: 10.0,
+            attack_ms: 0.0,
+            hold_ms: 10.0,
+            release_ms: 0.0,
+        };
+        let mut noise_gate = NoiseGate::new(&gate, 48000);
+        assert_eq!(noise_gate.process(0.05), 0.0);
+        assert!(!noise_gate.is_open());
+
+        assert_eq!(noise_gate.process(0.5), 0.5);
+        assert!(noise_gate.is_open());
+
+        // between the close level (-30dB) and the threshold the gate stays open
+        for _ in 0..4800 {
+            noise_gate.process(0.05);
+        }
+        assert!(noise_gate.is_open());
+
+        // below the close level it closes after the hold time and the envelope decay
+        for _ in 0..480 {
+            noise_gate.process(0.0);
+        }
+        assert!(noise_gate.is_open());
+        for _ in 0..4800 {
+            noise_gate.process(0.0);
+        }
+        assert!(!noise_gate.is_open());
+        assert_eq!(noise_gate.process(0.01), 0.0);
+    }
+
+    #[test]
+    fn gate_settings() {
+        assert!(Gate::default().check().is_ok());
+        let gate = Gate {
+            threshold_db: 3.0,
+            ..Gate::default()
+        };
+        assert_eq!(gate.check().unwrap_err().0, "threshold_db");
+        let gate = Gate {
+            hold_ms: -1.0,
+            ..Gate::default()
+        };
+        assert_eq!(gate.check().unwrap_err().0, "hold_ms");
+        assert_eq!(
+            Gate::default().to_string(),
+            "-50dB, hysteresis 6dB, attack 1ms, hold 50ms, release 100ms"
+        );
+    }
+}
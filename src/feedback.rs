@@ -0,0 +1,208 @@
+use crate::connection::ConnectionMetadata;
+
+use regex::Regex;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::OnceLock;
+
+/// Names of drivers that present the same device for playback and recording, passing
+/// whatever is played back to the recording side.
+pub const LOOPBACK_NAMES: &[&str] = &["loopback", "blackhole", "soundflower", "virtual"];
+
+/// Whether audio played to an output device comes back on an input device, as with
+/// loopback drivers and the monitor sources of PulseAudio and PipeWire.
+pub fn loops_back(output: &str, input: &str) -> bool {
+    let output = output.to_lowercase();
+    let input = input.to_lowercase();
+
+    if input == format!("monitor of {}", output) || input == format!("{}.monitor", output) {
+        return true;
+    }
+
+    // VB-Audio cables record on "CABLE Output" what is played to "CABLE Input"
+    if output.contains("cable input") && input == output.replace("cable input", "cable output") {
+        return true;
+    }
+
+    // the ALSA loopback card plays back on one subdevice and records on the other
+    static DEVICE: OnceLock<Regex> = OnceLock::new();
+    let device = DEVICE.get_or_init(|| Regex::new(r"(,\s*)?dev=\d+").unwrap());
+    LOOPBACK_NAMES.iter().any(|name| output.contains(name))
+        && device.replace_all(&output, "") == device.replace_all(&input, "")
+}
+
+/// Find the existing routes that would carry the sink of a new route back to its source,
+/// through loopback devices. Every channel a route reads or writes is followed, so routes
+/// panning to two sink channels or summing two source channels loop through either one.
+/// Returns `None` if the new route does not close a loop, and the routes along the loop in
+/// order otherwise.
+pub fn find_loop<'a>(
+    routes: &[&'a ConnectionMetadata],
+    new: &ConnectionMetadata,
+) -> Option<Vec<&'a ConnectionMetadata>> {
+    let new_channels = new.channels();
+    // outputs reached so far, along with the index of the route that reached them
+    let mut queue: VecDeque<_> = new_channels
+        .iter()
+        .map(|(_, sink)| (new.sink_name.as_str(), *sink, None))
+        .collect();
+    let mut parents: Vec<(usize, Option<usize>)> = Vec::new();
+    let mut visited = HashSet::new();
+
+    while let Some((output, channel, parent)) = queue.pop_front() {
+        if !visited.insert((output, channel)) {
+            continue;
+        }
+        if loops_back(output, &new.source_name)
+            && new_channels.iter().any(|(source, _)| *source == channel)
+        {
+            let mut path = Vec::new();
+            let mut current = parent;
+            while let Some(i) = current {
+                let (route, parent) = parents[i];
+                path.push(routes[route]);
+                current = parent;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        // loopbacks keep the channel, so only routes from the same channel continue
+        for (i, route) in routes.iter().enumerate() {
+            if route.host_name != new.host_name || !loops_back(output, &route.source_name) {
+                continue;
+            }
+            for (_, sink) in route
+                .channels()
+                .into_iter()
+                .filter(|(source, _)| *source == channel)
+            {
+                parents.push((i, parent));
+                queue.push_back((route.sink_name.as_str(), sink, Some(parents.len() - 1)));
+            }
+        }
+    }
+    None
+}
+
+/// Describe a feedback loop found by [`find_loop`], starting from the new route.
+pub fn describe(new: &ConnectionMetadata, path: &[&ConnectionMetadata]) -> String {
+    let mut s = format!("{}({})", new.source_name, new.source_channel);
+    for route in std::iter::once(new).chain(path.iter().copied()) {
+        s.push_str(&format!(" -> {}({})", route.sink_name, route.sink_channel));
+    }
+    format!("{} -> {}({})", s, new.source_name, new.source_channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Mode;
+
+    fn route(
+        source: &str,
+        source_channel: u16,
+        sink: &str,
+        sink_channel: u16,
+    ) -> ConnectionMetadata {
+        ConnectionMetadata::new(
+            "ALSA".to_string(),
+            source.to_string(),
+            sink.to_string(),
+            source_channel,
+            sink_channel,
+        )
+    }
+
+    #[test]
+    fn loopback_devices() {
+        assert!(loops_back("Built-in Output", "Monitor of Built-in Output"));
+        assert!(loops_back(
+            "alsa_output.pci.analog-stereo",
+            "alsa_output.pci.analog-stereo.monitor"
+        ));
+        assert!(loops_back("BlackHole 2ch", "BlackHole 2ch"));
+        assert!(loops_back(
+            "CABLE Input (VB-Audio Virtual Cable)",
+            "CABLE Output (VB-Audio Virtual Cable)"
+        ));
+        assert!(loops_back(
+            "hw:CARD=Loopback,DEV=0",
+            "hw:CARD=Loopback,DEV=1"
+        ));
+
+        assert!(!loops_back("Scarlett 2i2 USB", "Scarlett 2i2 USB"));
+        assert!(!loops_back("Built-in Output", "Built-in Microphone"));
+        assert!(!loops_back("hw:CARD=Loopback,DEV=0", "hw:CARD=USB,DEV=0"));
+    }
+
+    #[test]
+    fn loops() {
+        let mic = route("Mic", 0, "BlackHole 2ch", 0);
+        let back = route("BlackHole 2ch", 0, "Mic", 0);
+        let routes = [&mic];
+        assert_eq!(find_loop(&routes, &back), None);
+
+        // through the loopback into a device and back into the loopback
+        let through = route("BlackHole 2ch", 0, "Speakers", 0);
+        let monitor = route("Monitor of Speakers", 0, "BlackHole 2ch", 0);
+        let routes = [&mic, &through];
+        assert_eq!(find_loop(&routes, &monitor), Some(vec![&through]));
+        assert_eq!(
+            describe(&monitor, &[&through]),
+            "Monitor of Speakers(0) -> BlackHole 2ch(0) -> Speakers(0) -> Monitor of Speakers(0)"
+        );
+
+        // other channels and hosts do not loop
+        let other = route("Monitor of Speakers", 1, "BlackHole 2ch", 0);
+        assert_eq!(find_loop(&routes, &other), None);
+        let jack = ConnectionMetadata {
+            host_name: "JACK".to_string(),
+            ..monitor.clone()
+        };
+        assert_eq!(find_loop(&[&through], &jack), None);
+
+        // a route straight back into its own loopback
+        let direct = route("BlackHole 2ch", 1, "BlackHole 2ch", 1);
+        assert_eq!(find_loop(&[], &direct), Some(vec![]));
+    }
+
+    fn with_mode(mode: Mode, route: ConnectionMetadata) -> ConnectionMetadata {
+        ConnectionMetadata { mode, ..route }
+    }
+
+    #[test]
+    fn pan_loops() {
+        // panning to both channels of the loopback feeds the route recording channel 1
+        let back = route("BlackHole 2ch", 1, "Mic", 0);
+        let pan = with_mode(Mode::Pan, route("Monitor of Mic", 0, "BlackHole 2ch", 0));
+        assert_eq!(find_loop(&[&back], &pan), Some(vec![&back]));
+
+        // an existing panned route carries the loop on to its second channel
+        let through = with_mode(Mode::Pan, route("BlackHole 2ch", 0, "Speakers", 0));
+        let monitor = route("Monitor of Speakers", 1, "BlackHole 2ch", 0);
+        assert_eq!(find_loop(&[&through], &monitor), Some(vec![&through]));
+
+        let single = route("BlackHole 2ch", 0, "Speakers", 0);
+        assert_eq!(find_loop(&[&single], &monitor), None);
+    }
+
+    #[test]
+    fn sum_loops() {
+        // summing both channels of a monitor records what was played to channel 1
+        let play = route("BlackHole 2ch", 0, "Speakers", 1);
+        let sum = with_mode(
+            Mode::Sum,
+            route("Monitor of Speakers", 0, "BlackHole 2ch", 0),
+        );
+        assert_eq!(find_loop(&[&play], &sum), Some(vec![&play]));
+
+        // an existing summing route picks the loop up from its second channel
+        let through = with_mode(Mode::Sum, route("BlackHole 2ch", 0, "Speakers", 0));
+        let monitor = route("Monitor of Speakers", 0, "BlackHole 2ch", 1);
+        assert_eq!(find_loop(&[&through], &monitor), Some(vec![&through]));
+
+        let single = route("BlackHole 2ch", 0, "Speakers", 0);
+        assert_eq!(find_loop(&[&single], &monitor), None);
+    }
+}
@@ -0,0 +1,496 @@
+use crate::connection::SAMPLE_RATE;
+
+use anyhow::{anyhow, Result};
+use jack::{AudioIn, AudioOut, Client, ClientOptions, Control, Frames, Port, PortFlags};
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+type SourceCallback = Box<dyn FnMut(&[f32]) + Send>;
+type SinkCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// Host name of the JACK devices, which are the clients of the JACK server.
+pub const HOST_NAME: &str = "JACK";
+
+/// Name patchbay registers with the JACK server, JACK makes it unique if it is taken.
+pub const CLIENT_NAME: &str = "patchbay";
+
+/// Route changes that can wait for the process callback to pick them up.
+const PENDING_UPDATES: usize = 64;
+
+/// Routes the process callback has room for, so that adding one never reallocates on the
+/// audio thread.
+const MAX_ROUTES: usize = 256;
+
+/// How long removing a route waits for the process callback to hand back its ports.
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connection to the JACK server, shared by every route and opened on first use.
+struct Server {
+    client: jack::AsyncClient<(), Process>,
+    updates: mpsc::SyncSender<Update>,
+    next_id: AtomicUsize,
+    /// Routes added to the process callback and not handed back yet.
+    routes: AtomicUsize,
+}
+
+/// Change of the routes, passed to the process callback so that it never waits for a
+/// lock.
+enum Update {
+    Add(Ports),
+    /// Remove a route, handing its ports back to be unregistered.
+    Remove(usize, mpsc::SyncSender<Ports>),
+}
+
+fn server() -> Result<Arc<Server>> {
+    static SERVER: Mutex<Option<Arc<Server>>> = Mutex::new(None);
+
+    let mut server = SERVER.lock().map_err(|_| anyhow!("JACK client poisoned"))?;
+    if let Some(server) = server.as_ref() {
+        return Ok(Arc::clone(server));
+    }
+
+    let (client, _) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)
+        .map_err(|e| anyhow!("Could not connect to the JACK server: {}", e))?;
+    let (updates, pending) = mpsc::sync_channel(PENDING_UPDATES);
+    let frames = client.buffer_size() as usize;
+    let client = client.activate_async(
+        (),
+        Process {
+            routes: Vec::with_capacity(MAX_ROUTES),
+            updates: pending,
+            frames,
+        },
+    )?;
+    let opened = Arc::new(Server {
+        client,
+        updates,
+        next_id: AtomicUsize::new(0),
+        routes: AtomicUsize::new(0),
+    });
+    *server = Some(Arc::clone(&opened));
+    Ok(opened)
+}
+
+/// Clients with audio ports that produce audio, along with their number of ports.
+pub fn sources() -> Result<Vec<(String, u16)>> {
+    clients(PortFlags::IS_OUTPUT)
+}
+
+/// Clients with audio ports that take audio, along with their number of ports.
+pub fn sinks() -> Result<Vec<(String, u16)>> {
+    clients(PortFlags::IS_INPUT)
+}
+
+fn clients(flags: PortFlags) -> Result<Vec<(String, u16)>> {
+    let server = server()?;
+    let client = server.client.as_client();
+    Ok(group(&audio_ports(client, flags), client.name()))
+}
+
+fn audio_ports(client: &Client, flags: PortFlags) -> Vec<String> {
+    client.ports(None, Some(jack::jack_sys::FLOAT_MONO_AUDIO), flags)
+}
+
+/// Count the ports of every client, in the order JACK lists them, leaving out patchbay's
+/// own ports so that routes cannot feed patchbay into itself.
+fn group(ports: &[String], own: &str) -> Vec<(String, u16)> {
+    let mut clients: Vec<(String, u16)> = Vec::new();
+    for (client, _) in ports.iter().filter_map(|port| port.split_once(':')) {
+        if client == own {
+            continue;
+        }
+        match clients.iter_mut().find(|(name, _)| name == client) {
+            Some((_, count)) => *count += 1,
+            None => clients.push((client.to_string(), 1)),
+        }
+    }
+    clients
+}
+
+/// Full names of the given ports of a client, which are the channels of the device.
+fn channels(
+    client: &Client,
+    name: &str,
+    ports: Range<u16>,
+    flags: PortFlags,
+) -> Result<Vec<String>> {
+    let prefix = format!("{}:", name);
+    let all: Vec<String> = audio_ports(client, flags)
+        .into_iter()
+        .filter(|port| port.starts_with(&prefix))
+        .collect();
+    ports
+        .map(|channel| {
+            all.get(channel as usize).cloned().ok_or(anyhow!(
+                "JACK client '{}' has no {} port {}",
+                name,
+                if flags == PortFlags::IS_OUTPUT {
+                    "output"
+                } else {
+                    "input"
+                },
+                channel
+            ))
+        })
+        .collect()
+}
+
+/// Route between ports of other clients through ports of the patchbay client, named after
+/// the route, e.g. `patchbay:system.0 > system.1 in`. The ports are unregistered when the
+/// route is dropped.
+pub struct Route {
+    server: Arc<Server>,
+    id: usize,
+    running: Arc<AtomicBool>,
+}
+
+impl Route {
+    /// Register the ports of a route and connect them to the given ports of the source
+    /// and sink clients. `source_cb` and `sink_cb` take interleaved frames of the route's
+    /// source and sink ports, and the route starts halted.
+    pub fn new(
+        (source_name, source_ports): (&str, Range<u16>),
+        (sink_name, sink_ports): (&str, Range<u16>),
+        source_cb: impl FnMut(&[f32]) + Send + 'static,
+        sink_cb: impl FnMut(&mut [f32]) + Send + 'static,
+    ) -> Result<Self> {
+        let server = server()?;
+        let client = server.client.as_client();
+        if client.sample_rate() as u32 != SAMPLE_RATE {
+            return Err(anyhow!(
+                "JACK runs at {}Hz, patchbay needs {}Hz",
+                client.sample_rate(),
+                SAMPLE_RATE
+            ));
+        }
+
+        let sources = channels(
+            client,
+            source_name,
+            source_ports.clone(),
+            PortFlags::IS_OUTPUT,
+        )?;
+        let sinks = channels(client, sink_name, sink_ports.clone(), PortFlags::IS_INPUT)?;
+
+        let label = format!(
+            "{}.{} > {}.{}",
+            source_name, source_ports.start, sink_name, sink_ports.start
+        );
+        // routes over two channels number their ports after the channels
+        let port_name = |direction: &str, channels: &Range<u16>, channel: u16| {
+            if channels.len() == 1 {
+                format!("{} {}", label, direction)
+            } else {
+                format!("{} {} {}", label, direction, channel)
+            }
+        };
+        let inputs = source_ports
+            .clone()
+            .map(|c| client.register_port(&port_name("in", &source_ports, c), AudioIn))
+            .collect::<Result<Vec<_>, _>>()?;
+        let outputs = match sink_ports
+            .clone()
+            .map(|c| client.register_port(&port_name("out", &sink_ports, c), AudioOut))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                for port in inputs {
+                    let _ = client.unregister_port(port);
+                }
+                return Err(e.into());
+            }
+        };
+        let own_inputs = inputs
+            .iter()
+            .map(Port::name)
+            .collect::<Result<Vec<_>, _>>()?;
+        let own_outputs = outputs
+            .iter()
+            .map(Port::name)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let frames = client.buffer_size() as usize;
+        let running = Arc::new(AtomicBool::new(false));
+        let id = server.next_id.fetch_add(1, Ordering::Relaxed);
+        let ports = Ports {
+            id,
+            input: vec![0.0; frames * inputs.len()],
+            output: vec![0.0; frames * outputs.len()],
+            inputs,
+            outputs,
+            running: Arc::clone(&running),
+            source_cb: Box::new(source_cb),
+            sink_cb: Box::new(sink_cb),
+        };
+        // the process callback has room for every route that got a place here
+        if server
+            .routes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |routes| {
+                (routes < MAX_ROUTES).then_some(routes + 1)
+            })
+            .is_err()
+        {
+            let _ = ports.unregister(client);
+            return Err(anyhow!(
+                "JACK client cannot run more than {} routes",
+                MAX_ROUTES
+            ));
+        }
+        if let Err(e) = server.updates.try_send(Update::Add(ports)) {
+            server.routes.fetch_sub(1, Ordering::Relaxed);
+            if let mpsc::TrySendError::Full(Update::Add(ports))
+            | mpsc::TrySendError::Disconnected(Update::Add(ports)) = e
+            {
+                let _ = ports.unregister(client);
+            }
+            return Err(anyhow!(
+                "JACK client is not processing, could not add route"
+            ));
+        }
+
+        // from here on dropping the route unregisters its ports
+        let route = Route {
+            server: Arc::clone(&server),
+            id,
+            running,
+        };
+        for (source, input) in sources.iter().zip(&own_inputs) {
+            client.connect_ports_by_name(source, input)?;
+        }
+        for (output, sink) in own_outputs.iter().zip(&sinks) {
+            client.connect_ports_by_name(output, sink)?;
+        }
+        Ok(route)
+    }
+
+    pub fn run(&self) {
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    pub fn halt(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        let (reply, removed) = mpsc::sync_channel(1);
+        let result = self
+            .server
+            .updates
+            .try_send(Update::Remove(self.id, reply))
+            .map_err(|_| anyhow!("JACK client is not processing"))
+            .and_then(|_| {
+                removed
+                    .recv_timeout(REMOVE_TIMEOUT)
+                    .map_err(|_| anyhow!("JACK client did not release the route"))
+            })
+            .and_then(|ports| {
+                // a route that was not handed back keeps its place in the process callback
+                self.server.routes.fetch_sub(1, Ordering::Relaxed);
+                Ok(ports.unregister(self.server.client.as_client())?)
+            });
+        if let Err(e) = result {
+            log::error!("Could not unregister JACK ports: {}", e);
+        }
+    }
+}
+
+/// Direct link from a port of a source client to a port of a sink client, without
+/// patchbay's ports in between. The ports are connected while the link runs, and
+/// disconnected when it is dropped.
+pub struct Link {
+    server: Arc<Server>,
+    source: String,
+    sink: String,
+}
+
+impl Link {
+    pub fn new(
+        (source_name, source_port): (&str, u16),
+        (sink_name, sink_port): (&str, u16),
+    ) -> Result<Self> {
+        let server = server()?;
+        let client = server.client.as_client();
+        let source = channels(
+            client,
+            source_name,
+            source_port..source_port + 1,
+            PortFlags::IS_OUTPUT,
+        )?
+        .remove(0);
+        let sink = channels(
+            client,
+            sink_name,
+            sink_port..sink_port + 1,
+            PortFlags::IS_INPUT,
+        )?
+        .remove(0);
+        Ok(Link {
+            server,
+            source,
+            sink,
+        })
+    }
+
+    /// Connect the ports, unless they already are.
+    pub fn run(&self) -> Result<()> {
+        if !self.connected()? {
+            self.server
+                .client
+                .as_client()
+                .connect_ports_by_name(&self.source, &self.sink)?;
+        }
+        Ok(())
+    }
+
+    pub fn halt(&self) -> Result<()> {
+        if self.connected()? {
+            self.server
+                .client
+                .as_client()
+                .disconnect_ports_by_name(&self.source, &self.sink)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the ports are connected, which they are not once the source port is gone.
+    fn connected(&self) -> Result<bool> {
+        match self.server.client.as_client().port_by_name(&self.source) {
+            Some(port) => Ok(port.is_connected_to(&self.sink)?),
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Err(e) = self.halt() {
+            log::error!("Could not disconnect JACK ports: {}", e);
+        }
+    }
+}
+
+/// Ports of a route along with the connection callbacks, owned by the process callback.
+struct Ports {
+    id: usize,
+    inputs: Vec<Port<AudioIn>>,
+    outputs: Vec<Port<AudioOut>>,
+    running: Arc<AtomicBool>,
+    source_cb: SourceCallback,
+    sink_cb: SinkCallback,
+    /// Interleaved frames of the input and output ports, sized for the JACK buffer.
+    input: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl Ports {
+    /// Unregister the ports, which disconnects them as well.
+    fn unregister(self, client: &Client) -> Result<(), jack::Error> {
+        self.inputs
+            .into_iter()
+            .try_for_each(|port| client.unregister_port(port))?;
+        self.outputs
+            .into_iter()
+            .try_for_each(|port| client.unregister_port(port))
+    }
+
+    fn resize(&mut self, frames: usize) {
+        self.input.resize(frames * self.inputs.len(), 0.0);
+        self.output.resize(frames * self.outputs.len(), 0.0);
+    }
+
+    fn process(&mut self, scope: &jack::ProcessScope) {
+        let frames = scope.n_frames() as usize;
+        let (inputs, outputs) = (self.inputs.len(), self.outputs.len());
+        if !self.running.load(Ordering::Relaxed) || self.output.len() < frames * outputs {
+            for port in &mut self.outputs {
+                port.as_mut_slice(scope).fill(0.0);
+            }
+            return;
+        }
+
+        let input = &mut self.input[..frames * inputs];
+        for (channel, port) in self.inputs.iter().enumerate() {
+            for (frame, sample) in port.as_slice(scope).iter().enumerate() {
+                input[frame * inputs + channel] = *sample;
+            }
+        }
+        (self.source_cb)(input);
+
+        let output = &mut self.output[..frames * outputs];
+        output.fill(0.0);
+        (self.sink_cb)(output);
+        for (channel, port) in self.outputs.iter_mut().enumerate() {
+            for (frame, sample) in port.as_mut_slice(scope).iter_mut().enumerate() {
+                *sample = output[frame * outputs + channel];
+            }
+        }
+    }
+}
+
+/// Process callback, which owns the routes and takes route changes from the other threads
+/// between cycles.
+struct Process {
+    routes: Vec<Ports>,
+    updates: mpsc::Receiver<Update>,
+    /// Current JACK buffer size, for routes added after it changed.
+    frames: usize,
+}
+
+impl jack::ProcessHandler for Process {
+    fn process(&mut self, _: &Client, scope: &jack::ProcessScope) -> Control {
+        for update in self.updates.try_iter() {
+            match update {
+                Update::Add(mut ports) => {
+                    ports.resize(self.frames);
+                    // within the capacity, as routes only get this far with a place
+                    self.routes.push(ports);
+                }
+                Update::Remove(id, reply) => {
+                    if let Some(i) = self.routes.iter().position(|ports| ports.id == id) {
+                        let _ = reply.try_send(self.routes.swap_remove(i));
+                    }
+                }
+            }
+        }
+        for ports in self.routes.iter_mut() {
+            ports.process(scope);
+        }
+        Control::Continue
+    }
+
+    fn buffer_size(&mut self, _: &Client, frames: Frames) -> Control {
+        self.frames = frames as usize;
+        for ports in self.routes.iter_mut() {
+            ports.resize(self.frames);
+        }
+        Control::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_ports() {
+        let ports = [
+            "system:capture_1",
+            "system:capture_2",
+            "Firefox:output_FL",
+            "patchbay:mic.0 > system.0 out",
+            "Firefox:output_FR",
+            "malformed",
+        ]
+        .map(String::from);
+        assert_eq!(
+            group(&ports, "patchbay"),
+            [("system".to_string(), 2), ("Firefox".to_string(), 2)]
+        );
+    }
+}
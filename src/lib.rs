@@ -1,18 +1,134 @@
 pub mod cli;
+pub mod completion;
+pub mod config;
 pub mod connection;
+#[cfg(unix)]
+pub mod control;
+pub mod dsp;
+pub mod feedback;
+#[cfg(feature = "jack")]
+pub mod jack_client;
+pub mod loopback;
+pub mod matrix;
 pub mod patchbay;
+pub mod processor;
+pub mod routes;
+pub mod script;
 pub mod system;
+pub mod tui;
+
+use config::Format;
+use connection::Mode;
+use dsp::{Ducking, Filter, Gate, PanLaw, Protection};
+use processor::ProcessorConfig;
 
 #[derive(Debug, PartialEq)]
 pub enum Action {
-    List,
+    List {
+        verbose: bool,
+        json: bool,
+    },
     Host(String),
-    Connect(String, u16, String, u16),
+    Connect(String, u16, String, u16, ConnectOptions),
     Disconnect(String),
+    Aliases,
+    Alias(String, String),
+    Unalias(String),
+    VirtualDevices,
+    /// Virtual device name and host, the default host if none is given.
+    Virtual(String, Option<String>),
+    Unvirtual(String),
+    Pan(String, f32),
+    Delay(String, f32),
+    Filter(String, Filter),
+    Unfilter(String, Option<usize>),
+    Gate(String, GateOptions),
+    Ungate(String),
+    Insert(String, ProcessorConfig),
+    Uninsert(String, Option<usize>),
+    Duck(String, String, Ducking),
+    Unduck(String),
+    Protect(String, u16, Protection),
+    Unprotect(String, u16),
+    Rearm(Option<String>),
     Print,
+    Matrix,
+    Undo,
+    Redo,
     Start,
     Stop,
-    Save(String),
-    Load(String),
+    Save(String, Option<Format>),
+    Load(String, Option<Format>),
+    Validate(String, Option<Format>),
+    Source(String),
     Quit,
 }
+
+/// Options of `connect` besides the channels.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConnectOptions {
+    pub mode: Mode,
+    pub pan: f32,
+    pub pan_law: PanLaw,
+    /// Connect even if the connection closes a feedback loop.
+    pub force: bool,
+}
+
+/// Settings given to `gate`, applied over the current gate of the connection or the
+/// defaults.
+#[derive(Debug, Default, PartialEq)]
+pub struct GateOptions {
+    pub threshold_db: Option<f32>,
+    pub hysteresis_db: Option<f32>,
+    pub attack_ms: Option<f32>,
+    pub hold_ms: Option<f32>,
+    pub release_ms: Option<f32>,
+}
+
+impl GateOptions {
+    pub fn apply(&self, gate: &mut Gate) {
+        for (option, value) in [
+            (self.threshold_db, &mut gate.threshold_db),
+            (self.hysteresis_db, &mut gate.hysteresis_db),
+            (self.attack_ms, &mut gate.attack_ms),
+            (self.hold_ms, &mut gate.hold_ms),
+            (self.release_ms, &mut gate.release_ms),
+        ] {
+            if let Some(option) = option {
+                *value = option;
+            }
+        }
+    }
+}
+
+impl Action {
+    /// Whether the action can change the routing, and so the saved configuration.
+    pub fn mutates(&self) -> bool {
+        matches!(
+            self,
+            Action::Host(_)
+                | Action::Connect(..)
+                | Action::Disconnect(_)
+                | Action::Alias(..)
+                | Action::Unalias(_)
+                | Action::Virtual(..)
+                | Action::Unvirtual(_)
+                | Action::Pan(..)
+                | Action::Delay(..)
+                | Action::Filter(..)
+                | Action::Unfilter(..)
+                | Action::Gate(..)
+                | Action::Ungate(_)
+                | Action::Insert(..)
+                | Action::Uninsert(..)
+                | Action::Duck(..)
+                | Action::Unduck(_)
+                | Action::Protect(..)
+                | Action::Unprotect(..)
+                | Action::Undo
+                | Action::Redo
+                | Action::Load(..)
+                | Action::Source(_)
+        )
+    }
+}
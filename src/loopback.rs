@@ -0,0 +1,133 @@
+use crate::feedback;
+use crate::system;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Device that other applications play into or record from, backed by a loopback
+/// driver. Patchbay uses one side of the loopback and the applications the other, so
+/// the virtual device acts as a source of what the applications play and as a sink for
+/// what they record.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VirtualDevice {
+    pub name: String,
+    pub host_name: String,
+    /// Device patchbay opens for the virtual device.
+    pub device: String,
+    /// Device the applications open, the same as `device` for drivers that present a
+    /// single device for both sides.
+    pub peer: String,
+}
+
+impl fmt::Display for VirtualDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} [{}]: {}, applications use {}",
+            self.name, self.host_name, self.device, self.peer
+        )
+    }
+}
+
+/// Loopback devices among the devices of a host, as pairs of the device patchbay opens
+/// and the device applications open.
+///
+/// The ALSA loopback card passes what is played on one of its devices to the other, so
+/// patchbay takes the second device and leaves the first one, which applications open by
+/// default, to them. Other loopback drivers present one device for both sides.
+pub fn pairs(inputs: &[String], outputs: &[String]) -> Vec<(String, String)> {
+    static ALSA: OnceLock<Regex> = OnceLock::new();
+    let alsa =
+        ALSA.get_or_init(|| Regex::new(r"^hw:CARD=([^,]*(?i:loopback)[^,]*),DEV=1$").unwrap());
+    let duplex = |name: &String| inputs.contains(name) && outputs.contains(name);
+
+    outputs
+        .iter()
+        .filter(|name| duplex(name))
+        .filter_map(|name| {
+            if let Some(captures) = alsa.captures(name) {
+                let peer = format!("hw:CARD={},DEV=0", &captures[1]);
+                return duplex(&peer).then(|| (name.clone(), peer));
+            }
+            // the other ALSA devices of the loopback card are plugins on top of its devices
+            let lowercase = name.to_lowercase();
+            (!name.contains("CARD=")
+                && feedback::LOOPBACK_NAMES
+                    .iter()
+                    .any(|loopback| lowercase.contains(loopback)))
+            .then(|| (name.clone(), name.clone()))
+        })
+        .collect()
+}
+
+/// Find a loopback device on a host that no virtual device uses yet.
+pub fn allocate(host_name: &str, taken: &[VirtualDevice]) -> Result<(String, String)> {
+    let devices = system::Devices::list(host_name)?;
+    let names = |devices: &[(String, u16)]| -> Vec<String> {
+        devices.iter().map(|(name, _)| name.clone()).collect()
+    };
+
+    pairs(&names(&devices.inputs), &names(&devices.outputs))
+        .into_iter()
+        .find(|(device, _)| {
+            !taken
+                .iter()
+                .any(|v| v.host_name == host_name && &v.device == device)
+        })
+        .ok_or(anyhow!(
+            "No free loopback device on host '{}', load the ALSA loopback module \
+             (snd-aloop) or install a loopback driver",
+            host_name
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn loopback_pairs() {
+        let devices = names(&[
+            "default",
+            "hw:CARD=PCH,DEV=0",
+            "hw:CARD=Loopback,DEV=0",
+            "hw:CARD=Loopback,DEV=1",
+            "plughw:CARD=Loopback,DEV=1",
+            "front:CARD=Loopback,DEV=0",
+            "hw:CARD=Loopback_1,DEV=0",
+            "hw:CARD=Loopback_1,DEV=1",
+        ]);
+        assert_eq!(
+            pairs(&devices, &devices),
+            [
+                (
+                    "hw:CARD=Loopback,DEV=1".to_string(),
+                    "hw:CARD=Loopback,DEV=0".to_string()
+                ),
+                (
+                    "hw:CARD=Loopback_1,DEV=1".to_string(),
+                    "hw:CARD=Loopback_1,DEV=0".to_string()
+                ),
+            ]
+        );
+
+        let inputs = names(&["BlackHole 2ch", "MacBook Pro Microphone"]);
+        let outputs = names(&["BlackHole 2ch", "MacBook Pro Speakers"]);
+        assert_eq!(
+            pairs(&inputs, &outputs),
+            [("BlackHole 2ch".to_string(), "BlackHole 2ch".to_string())]
+        );
+
+        // both sides are needed
+        assert!(pairs(&names(&["hw:CARD=Loopback,DEV=1"]), &devices).is_empty());
+        assert!(pairs(&[], &outputs).is_empty());
+    }
+}
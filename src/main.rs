@@ -1,56 +1,88 @@
-use patchbay::cli;
-use patchbay::connection::Connection;
+use patchbay::cli::{self, Args, Command};
+use patchbay::config::{self, Format};
+use patchbay::connection::{self, Connection, Mode};
+#[cfg(unix)]
+use patchbay::control;
 use patchbay::patchbay::Patchbay;
+use patchbay::script::{self, Script};
 use patchbay::system;
-use patchbay::Action;
+use patchbay::tui;
+use patchbay::{Action, ConnectOptions};
 
 use anyhow::{anyhow, Result};
+use clap::Parser as _;
 use sysinfo::System;
-use cpal::traits::{DeviceTrait, HostTrait};
 use uuid::Uuid;
 
-use std::env;
 use std::io::{Read, Write};
 use std::path::Path;
-use std::process;
+use std::process::{self, ExitCode};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time;
 
-fn list() -> Result<()> {
-    for host in system::hosts() {
-        if let Ok(host) = host {
-            let host_name = host.id().name();
-            println!("Devices ({}):", host_name);
-            for device in host.devices()? {
-                let input_channels = if device.default_input_config().is_ok() {
-                    device.default_input_config()?.channels()
-                } else {
-                    0
-                };
-                let output_channels = if device.default_output_config().is_ok() {
-                    device.default_output_config()?.channels()
-                } else {
-                    0
-                };
-                println!(
-                    "{} (in: {}, out: {})",
-                    device.name()?,
-                    input_channels,
-                    output_channels
-                );
+fn list(verbose: bool, json: bool, out: &mut dyn Write) -> Result<()> {
+    let hosts = system::describe_hosts();
+
+    if json {
+        serde_json::to_writer_pretty(&mut *out, &hosts)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    for host in hosts {
+        if let Some(error) = host.error {
+            writeln!(out, "Host {} unavailable: {}", host.name, error)?;
+            continue;
+        }
+
+        writeln!(out, "Devices ({}):", host.name)?;
+        for device in host.devices {
+            write!(
+                out,
+                "@{} {} (in: {}, out: {})",
+                device.index, device.name, device.input_channels, device.output_channels
+            )?;
+            match (device.default_input, device.default_output) {
+                (true, true) => write!(out, " [default input, default output]")?,
+                (true, false) => write!(out, " [default input]")?,
+                (false, true) => write!(out, " [default output]")?,
+                (false, false) => (),
+            }
+            writeln!(out)?;
+
+            if verbose {
+                for (direction, configs) in [
+                    ("in", &device.input_configs),
+                    ("out", &device.output_configs),
+                ] {
+                    for config in configs {
+                        write!(
+                            out,
+                            "    {:<3} {} ch, {}-{} Hz, {}",
+                            direction,
+                            config.channels,
+                            config.min_sample_rate,
+                            config.max_sample_rate,
+                            config.sample_format
+                        )?;
+                        match config.buffer_size {
+                            Some((min, max)) => writeln!(out, ", buffer {}-{} frames", min, max)?,
+                            None => writeln!(out, ", buffer size unknown")?,
+                        }
+                    }
+                }
             }
         }
     }
     Ok(())
 }
 
-fn set_host(host_name: &str, patchbay: &mut Patchbay) -> Result<()> {
-    patchbay.halt()?;
-    patchbay.remove_all_connections()?;
-    println!("Set host {}", host_name);
-    patchbay.set_host(host_name)
+fn set_host(host_name: &str, patchbay: &mut Patchbay, out: &mut dyn Write) -> Result<()> {
+    patchbay.set_host(host_name)?;
+    writeln!(out, "Set default host {}", host_name)?;
+    Ok(())
 }
 
 fn connect(
@@ -58,58 +90,194 @@ fn connect(
     source_channel: u16,
     sink_name: String,
     sink_channel: u16,
+    options: ConnectOptions,
     patchbay: &mut Patchbay,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    let connection = Connection::new(
-        patchbay.host().to_owned(),
-        source_name,
-        sink_name,
+    // unqualified names are on the host of the other device, or the default host
+    let (source_host, source_selector) =
+        system::split_qualified(&patchbay.expand_alias(&source_name));
+    let (sink_host, sink_selector) = system::split_qualified(&patchbay.expand_alias(&sink_name));
+    let host_name = match (source_host, sink_host) {
+        (Some(source_host), Some(sink_host)) if source_host != sink_host => {
+            return Err(anyhow!(
+                "Source and sink must be on the same host, found '{}' and '{}'",
+                source_host,
+                sink_host
+            ))
+        }
+        (Some(host), _) | (_, Some(host)) => host,
+        (None, None) => patchbay.host().to_owned(),
+    };
+
+    let source_device = system::resolve_input_device(&host_name, &source_selector)?;
+    let sink_device = system::resolve_output_device(&host_name, &sink_selector)?;
+
+    // aliases and virtual devices are saved as given, other selectors as the device they
+    // selected, as saved names have to match exactly
+    let saved = |name: String, device: &str| {
+        if patchbay.expand_alias(&name) == name {
+            device.to_owned()
+        } else {
+            name
+        }
+    };
+    let (source_name, sink_name) = (
+        saved(source_name, &source_device),
+        saved(sink_name, &sink_device),
+    );
+
+    let mut connection = Connection::new(
+        host_name,
+        source_device,
+        sink_device,
         source_channel,
         sink_channel,
-    )?;
-    let id = patchbay.add_connection(connection)?;
-    println!("Created connection with id {}", id);
+        options.mode,
+    )?
+    .with_names(source_name, sink_name);
+    if options.mode == Mode::Pan {
+        connection.set_pan(options.pan, options.pan_law)?;
+    }
+    let id = patchbay.add_connection(connection, options.force)?;
+    writeln!(out, "Created connection with id {}", id)?;
     Ok(())
 }
 
-fn disconnect(id: &str, patchbay: &mut Patchbay) -> Result<()> {
+/// Host and device name of a sink given like in `connect`.
+fn sink(sink_name: &str, patchbay: &Patchbay) -> Result<(String, String)> {
+    let (host_name, sink_name) = system::split_qualified(&patchbay.expand_alias(sink_name));
+    let host_name = host_name.unwrap_or(patchbay.host().to_owned());
+    let sink_name = system::resolve_output_device(&host_name, &sink_name)?;
+    Ok((host_name, sink_name))
+}
+
+fn disconnect(id: &str, patchbay: &mut Patchbay, out: &mut dyn Write) -> Result<()> {
     if id == "*" {
         patchbay.remove_all_connections()?;
     } else {
         patchbay.remove_connection(&Uuid::parse_str(id)?)?;
     }
 
-    println!("Removed connection {}", id);
+    writeln!(out, "Removed connection {}", id)?;
     Ok(())
 }
 
-fn save(path: &Path, patchbay: &mut Patchbay) -> Result<()> {
-    let mut f = std::fs::File::create(path)?;
-    f.write_all(serde_json::to_string_pretty(&patchbay)?.as_bytes())?;
-    println!("Saved configuration to {:?}", path);
+fn save(
+    path: &Path,
+    format: Option<Format>,
+    patchbay: &mut Patchbay,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let format = write_config(path, format, patchbay)?;
+    writeln!(out, "Saved {} configuration to {:?}", format, path)?;
     Ok(())
 }
 
-fn load(path: &Path, patchbay: &mut Patchbay) -> Result<()> {
+fn write_config(path: &Path, format: Option<Format>, patchbay: &Patchbay) -> Result<Format> {
+    let format = format.unwrap_or_else(|| Format::from_path(path));
+    config::write(path, &config::to_string(&patchbay.config(), format)?)?;
+    Ok(format)
+}
+
+/// Save the configuration after commands that change the routing, if enabled.
+fn autosave(args: &Args, patchbay: &Patchbay) -> Result<()> {
+    if let (true, Some(path)) = (args.autosave, &args.config) {
+        write_config(path, args.format, patchbay)
+            .map_err(|e| anyhow!("Autosave to {} failed: {}", path.display(), e))?;
+        log::info!("Autosaved configuration to {}", path.display());
+    }
+    Ok(())
+}
+
+fn load(
+    path: &Path,
+    format: Option<Format>,
+    patchbay: &mut Patchbay,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let format = format.unwrap_or_else(|| Format::from_path(path));
     let mut f = std::fs::File::open(path)?;
     let mut buf = String::new();
     f.read_to_string(&mut buf)?;
 
-    let new = serde_json::from_str(&buf)?;
+    patchbay.load(config::parse(&buf, format)?)?;
+    writeln!(out, "Loaded configuration")?;
+    Ok(())
+}
 
-    patchbay.halt()?;
-    patchbay.remove_all_connections()?;
-    *patchbay = new;
-    patchbay.halt()?;
-    println!("Loaded configuration");
+/// Re-read the configuration file and apply the differences to the patchbay.
+fn reload(path: &Path, format: Option<Format>, patchbay: &mut Patchbay) -> Result<()> {
+    let format = format.unwrap_or_else(|| Format::from_path(path));
+    let buf = std::fs::read_to_string(path)?;
+    let diff = patchbay.apply(config::parse(&buf, format)?)?;
+    log::info!("Reloaded configuration from {}: {}", path.display(), diff);
     Ok(())
 }
 
-fn run_daemon(mut patchbay: Patchbay) -> Result<()> {
+fn modified(path: &Path) -> Option<time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn validate(path: &Path, format: Option<Format>, out: &mut dyn Write) -> Result<()> {
+    let format = format.unwrap_or_else(|| Format::from_path(path));
+    let mut f = std::fs::File::open(path)?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+
+    let problems = config::validate(&buf, format);
+    for problem in &problems {
+        writeln!(out, "{}", problem)?;
+    }
+
+    if problems.is_empty() {
+        writeln!(out, "Configuration is valid")?;
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Found {} problem(s) in {}",
+            problems.len(),
+            path.display()
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn ctl(command: &[String]) -> Result<ExitCode> {
+    let response = control::send(command)?;
+    print!("{}", response.output);
+    match response.error {
+        Some(e) => {
+            eprintln!("{}", e);
+            Ok(ExitCode::FAILURE)
+        }
+        None => Ok(ExitCode::SUCCESS),
+    }
+}
+
+#[cfg(not(unix))]
+fn ctl(_: &[String]) -> Result<ExitCode> {
+    Err(anyhow!("Daemon control is only supported on unix"))
+}
+
+fn run_daemon(
+    mut patchbay: Patchbay,
+    #[cfg_attr(not(unix), allow(unused_mut, unused_variables))] mut parser: cli::Parser,
+    args: &Args,
+) -> Result<()> {
     let terminate = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&terminate))?;
+    let hangup = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hangup))?;
     let hundred_millis = time::Duration::from_millis(100);
 
+    // the configuration is reloaded whenever it is modified or on SIGHUP
+    let mut last_modified = args.config.as_deref().and_then(modified);
+
+    #[cfg(unix)]
+    let server = control::Server::bind()?;
+
     patchbay.run()?;
 
     println!(
@@ -117,8 +285,52 @@ fn run_daemon(mut patchbay: Patchbay) -> Result<()> {
         process::id()
     );
 
-
     while !terminate.load(Ordering::Relaxed) {
+        if let Some(path) = &args.config {
+            let current = modified(path);
+            if hangup.swap(false, Ordering::Relaxed) || current != last_modified {
+                last_modified = current;
+                if let Err(e) = reload(path, args.format, &mut patchbay) {
+                    log::error!(
+                        "Could not reload configuration, keeping current routing: {}",
+                        e
+                    );
+                }
+            }
+        }
+
+        #[cfg(unix)]
+        while let Some(request) = server.accept().unwrap_or_else(|e| {
+            log::error!("Control request failed: {}", e);
+            None
+        }) {
+            log::info!("Control request: {:?}", request.args());
+
+            let mut output = Vec::new();
+            let result = parser.parse(request.args()).and_then(|action| {
+                let mutates = action.mutates();
+                let flow = execute(action, &mut patchbay, &mut parser, &mut output)?;
+                if mutates {
+                    autosave(args, &patchbay)?;
+                    // the daemon's own saves are not edits to reload
+                    last_modified = args.config.as_deref().and_then(modified);
+                }
+                Ok(flow)
+            });
+
+            if let Ok(Flow::Quit) = result {
+                terminate.store(true, Ordering::Relaxed);
+            }
+
+            let response = control::Response {
+                output: String::from_utf8_lossy(&output).into_owned(),
+                error: result.err().map(|e| e.to_string()),
+            };
+            if let Err(e) = request.respond(response) {
+                log::error!("Could not respond to control request: {}", e);
+            }
+        }
+
         thread::sleep(hundred_millis);
     }
 
@@ -126,52 +338,218 @@ fn run_daemon(mut patchbay: Patchbay) -> Result<()> {
     Ok(())
 }
 
-fn run_repl(mut patchbay: Patchbay) -> Result<()> {
-    let stdin = std::io::stdin();
-    let mut stdout = std::io::stdout();
-    let mut parser = cli::Parser::new();
+enum Flow {
+    Continue,
+    Quit,
+}
+
+fn execute(
+    action: Action,
+    patchbay: &mut Patchbay,
+    parser: &mut cli::Parser,
+    out: &mut dyn Write,
+) -> Result<Flow> {
+    match action {
+        Action::List { verbose, json } => list(verbose, json, out),
+        Action::Host(host_name) => set_host(&host_name, patchbay, out),
+        Action::Connect(source_name, source_channel, sink_name, sink_channel, options) => connect(
+            source_name,
+            source_channel,
+            sink_name,
+            sink_channel,
+            options,
+            patchbay,
+            out,
+        ),
+        Action::Pan(id, pan) => {
+            patchbay.set_pan(&Uuid::parse_str(&id)?, pan)?;
+            writeln!(out, "Set pan of {} to {}", id, pan).map_err(Into::into)
+        }
+        Action::Disconnect(id) => disconnect(&id, patchbay, out),
+        Action::Delay(id, delay_ms) => {
+            patchbay.set_delay(&Uuid::parse_str(&id)?, delay_ms)?;
+            writeln!(out, "Set delay of {} to {}ms", id, delay_ms).map_err(Into::into)
+        }
+        Action::Filter(id, filter) => {
+            let message = format!("Added {} filter to {}", filter, id);
+            patchbay.add_filter(&Uuid::parse_str(&id)?, filter)?;
+            writeln!(out, "{}", message).map_err(Into::into)
+        }
+        Action::Unfilter(id, index) => {
+            patchbay.remove_filter(&Uuid::parse_str(&id)?, index)?;
+            writeln!(out, "Removed filters of {}", id).map_err(Into::into)
+        }
+        Action::Gate(id, options) => {
+            let id = Uuid::parse_str(&id)?;
+            let mut gate = patchbay
+                .connection(&id)?
+                .gate()
+                .cloned()
+                .unwrap_or_default();
+            options.apply(&mut gate);
+            let message = format!("Set gate of {} to {}", id, gate);
+            patchbay.set_gate(&id, Some(gate))?;
+            writeln!(out, "{}", message).map_err(Into::into)
+        }
+        Action::Ungate(id) => {
+            patchbay.set_gate(&Uuid::parse_str(&id)?, None)?;
+            writeln!(out, "Removed gate of {}", id).map_err(Into::into)
+        }
+        Action::Insert(id, processor) => {
+            let message = format!("Inserted {} into {}", processor, id);
+            patchbay.add_processor(&Uuid::parse_str(&id)?, processor)?;
+            writeln!(out, "{}", message).map_err(Into::into)
+        }
+        Action::Uninsert(id, index) => {
+            patchbay.remove_processor(&Uuid::parse_str(&id)?, index)?;
+            writeln!(out, "Removed processors of {}", id).map_err(Into::into)
+        }
+        Action::Duck(id, trigger, ducking) => {
+            let message = format!("Ducking {} by {}: {}", id, trigger, ducking);
+            patchbay.duck(&Uuid::parse_str(&id)?, &Uuid::parse_str(&trigger)?, ducking)?;
+            writeln!(out, "{}", message).map_err(Into::into)
+        }
+        Action::Unduck(id) => {
+            patchbay.unduck(&Uuid::parse_str(&id)?)?;
+            writeln!(out, "Stopped ducking {}", id).map_err(Into::into)
+        }
+        Action::Protect(sink_name, sink_channel, protection) => {
+            let (host_name, sink_name) = sink(&sink_name, patchbay)?;
+            let message = format!("Protected {}({}): {}", sink_name, sink_channel, protection);
+            patchbay.protect(&host_name, &sink_name, sink_channel, protection)?;
+            writeln!(out, "{}", message).map_err(Into::into)
+        }
+        Action::Unprotect(sink_name, sink_channel) => {
+            // the device may be gone, so fall back to the name as given
+            let (host_name, sink_name) = sink(&sink_name, patchbay).or_else(|_| {
+                let (host, name) = system::split_qualified(&patchbay.expand_alias(&sink_name));
+                Ok::<_, anyhow::Error>((host.unwrap_or(patchbay.host().to_owned()), name))
+            })?;
+            patchbay.unprotect(&host_name, &sink_name, sink_channel)?;
+            writeln!(out, "Removed protection of {}({})", sink_name, sink_channel)
+                .map_err(Into::into)
+        }
+        Action::Rearm(id) => {
+            match &id {
+                Some(id) => patchbay.rearm(Some(&Uuid::parse_str(id)?))?,
+                None => patchbay.rearm(None)?,
+            }
+            writeln!(
+                out,
+                "Rearmed {}",
+                id.as_deref().unwrap_or("all connections")
+            )
+            .map_err(Into::into)
+        }
+        Action::Aliases => patchbay
+            .aliases()
+            .iter()
+            .try_for_each(|(name, device)| writeln!(out, "{} = {}", name, device))
+            .map_err(Into::into),
+        Action::Alias(name, device) => {
+            patchbay.set_alias(&name, &device)?;
+            writeln!(out, "Set alias {} for {}", name, device).map_err(Into::into)
+        }
+        Action::Unalias(name) => {
+            patchbay.remove_alias(&name)?;
+            writeln!(out, "Removed alias {}", name).map_err(Into::into)
+        }
+        Action::VirtualDevices => patchbay
+            .virtual_devices()
+            .iter()
+            .try_for_each(|v| writeln!(out, "{}", v))
+            .map_err(Into::into),
+        Action::Virtual(name, host_name) => {
+            let host_name = host_name.unwrap_or(patchbay.host().to_owned());
+            let v = patchbay.add_virtual(&name, &host_name)?;
+            writeln!(
+                out,
+                "Added virtual device {} on {}, applications should use {}",
+                v.name, v.device, v.peer
+            )
+            .map_err(Into::into)
+        }
+        Action::Unvirtual(name) => {
+            patchbay.remove_virtual(&name)?;
+            writeln!(out, "Removed virtual device {}", name).map_err(Into::into)
+        }
+        Action::Print => write!(out, "{}", patchbay).map_err(Into::into),
+        Action::Matrix => write!(out, "{}", patchbay.matrix()).map_err(Into::into),
+        Action::Undo => writeln!(out, "Undid {}", patchbay.undo()?).map_err(Into::into),
+        Action::Redo => writeln!(out, "Redid {}", patchbay.redo()?).map_err(Into::into),
+        Action::Start => patchbay.run(),
+        Action::Stop => patchbay.halt(),
+        Action::Save(path, format) => save(Path::new(&path), format, patchbay, out),
+        Action::Load(path, format) => load(Path::new(&path), format, patchbay, out),
+        Action::Validate(path, format) => validate(Path::new(&path), format, out),
+        Action::Source(path) => return run_script(Path::new(&path), patchbay, parser, out),
+        Action::Quit => return Ok(Flow::Quit),
+    }?;
+    Ok(Flow::Continue)
+}
+
+fn run_script(
+    path: &Path,
+    patchbay: &mut Patchbay,
+    parser: &mut cli::Parser,
+    out: &mut dyn Write,
+) -> Result<Flow> {
+    let _running = script::Running::enter(path)?;
+    let mut f = std::fs::File::open(path)?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+
+    let mut script = Script::new();
+
+    for (n, line) in buf.lines().enumerate() {
+        let result = script.line(line).and_then(|tokens| match tokens {
+            Some(tokens) => execute(parser.parse(tokens)?, patchbay, parser, out),
+            None => Ok(Flow::Continue),
+        });
+
+        match result {
+            Ok(Flow::Continue) => continue,
+            Ok(Flow::Quit) => return Ok(Flow::Quit),
+            Err(e) => {
+                eprintln!("{}:{}: {}", path.display(), n + 1, e);
+                if script.abort_on_error() {
+                    return Err(anyhow!("Aborted script {}", path.display()));
+                }
+            }
+        }
+    }
+    Ok(Flow::Continue)
+}
+
+fn run_repl(mut patchbay: Patchbay, mut parser: cli::Parser, args: &Args) -> Result<()> {
+    let mut prompt = cli::Prompt::new(&parser)?;
 
     loop {
-        match cli::prompt("> ", &stdin, &mut stdout) {
-            Ok(input) => {
+        prompt.update(&patchbay);
+
+        match prompt.read("> ") {
+            Ok(None) => break,
+            Ok(Some(input)) => {
                 if input.is_empty() {
                     continue;
                 }
 
-                match parser.parse(cli::split_args(&input)) {
-                    Ok(action) => {
-                        let result = match action {
-                            Action::List => list(),
-                            Action::Host(host_name) => set_host(&host_name, &mut patchbay),
-                            Action::Connect(
-                                source_name,
-                                source_channel,
-                                sink_name,
-                                sink_channel,
-                            ) => connect(
-                                source_name,
-                                source_channel,
-                                sink_name,
-                                sink_channel,
-                                &mut patchbay,
-                            ),
-                            Action::Disconnect(id) => disconnect(&id, &mut patchbay),
-                            Action::Print => {
-                                print!("{}", patchbay);
-                                Ok(())
-                            }
-                            Action::Start => patchbay.run(),
-                            Action::Stop => patchbay.halt(),
-                            Action::Save(path) => save(&Path::new(&path), &mut patchbay),
-                            Action::Load(path) => load(&Path::new(&path), &mut patchbay),
-                            Action::Quit => break,
-                        };
-
-                        match result {
-                            Ok(_) => continue,
-                            Err(e) => eprintln!("{}", e),
-                        };
+                let result = parser.parse(cli::split_args(&input)).and_then(|action| {
+                    // devices may have come and gone since completion last scanned them
+                    if matches!(action, Action::List { .. }) {
+                        prompt.rescan();
+                    }
+                    let mutates = action.mutates();
+                    let flow = execute(action, &mut patchbay, &mut parser, &mut std::io::stdout())?;
+                    if mutates {
+                        autosave(args, &patchbay)?;
                     }
+                    Ok(flow)
+                });
+
+                match result {
+                    Ok(Flow::Continue) => continue,
+                    Ok(Flow::Quit) => break,
                     Err(e) => eprintln!("{}", e),
                 };
             }
@@ -181,36 +559,86 @@ fn run_repl(mut patchbay: Patchbay) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
+/// Create the patchbay for the run and daemon modes from the startup options.
+fn start(args: &Args, script: Option<&Path>) -> Result<Option<(Patchbay, cli::Parser)>> {
     let s = System::new_all();
-    for instance in s.processes_by_exact_name("patchbay") {
+    // on Linux the threads of a process are listed as well
+    for instance in s
+        .processes_by_exact_name("patchbay")
+        .filter(|instance| instance.thread_kind().is_none())
+    {
         if instance.pid().as_u32() != process::id() {
-            return Err(anyhow!("Process already started with PID {}", instance.pid()));
+            return Err(anyhow!(
+                "Process already started with PID {}",
+                instance.pid()
+            ));
         }
     }
 
     let mut patchbay = Patchbay::new(system::default_host().id().name());
+    let mut parser = cli::Parser::new();
+    let mut stdout = std::io::stdout();
 
-    let args: Vec<String> = env::args().collect();
-    let mut daemonize = false;
+    if let Some(path) = &args.config {
+        if let Err(e) = load(path, args.format, &mut patchbay, &mut stdout) {
+            log::warn!("Could not load configuration: {}", e);
+            log::warn!("Continuing with default");
+        }
+    }
 
-    for arg in args.iter().skip(1).take(2) {
-        if arg == "-d" {
-            daemonize = true;
-        } else {
-            match load(&Path::new(&arg), &mut patchbay) {
-                Ok(_) => (),
-                Err(e) => {
-                    eprintln!("Could not load configuration: {}", e);
-                    eprintln!("Continuing with default");
-                }
+    if let Some(host_name) = &args.host {
+        set_host(host_name, &mut patchbay, &mut stdout)?;
+    }
+
+    if let Some(path) = script {
+        if let Flow::Quit = run_script(path, &mut patchbay, &mut parser, &mut stdout)? {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some((patchbay, parser)))
+}
+
+fn run(args: Args) -> Result<ExitCode> {
+    if let Some(latency) = args.latency {
+        connection::set_latency(time::Duration::from_millis(latency));
+    }
+
+    let command = args.command.clone().unwrap_or(Command::Run {
+        tui: false,
+        script: None,
+    });
+
+    match command {
+        Command::Run { tui, script } => match start(&args, script.as_deref())? {
+            Some((mut patchbay, _)) if tui => tui::run(&mut patchbay)?,
+            Some((patchbay, parser)) => run_repl(patchbay, parser, &args)?,
+            None => (),
+        },
+        Command::Daemon { script } => {
+            if let Some((patchbay, parser)) = start(&args, script.as_deref())? {
+                run_daemon(patchbay, parser, &args)?;
             }
         }
+        Command::List { verbose, json } => list(verbose, json, &mut std::io::stdout())?,
+        Command::Ctl { command } => return ctl(&command),
+        Command::Validate { path } => validate(&path, args.format, &mut std::io::stdout())?,
     }
+    Ok(ExitCode::SUCCESS)
+}
 
-    if daemonize {
-        run_daemon(patchbay)
-    } else {
-        run_repl(patchbay)
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    env_logger::Builder::new()
+        .filter_level(args.log_level)
+        .init();
+
+    match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,254 @@
+use uuid::Uuid;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Host, device and channel of a row or column.
+pub type Endpoint = (String, String, u16);
+
+/// A routed source/sink channel pair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Crosspoint {
+    pub id: Uuid,
+    pub gain_db: f32,
+    pub muted: bool,
+}
+
+impl Crosspoint {
+    /// Short cell label: muted, gain where non-unity, or routed.
+    pub fn marker(&self) -> String {
+        if self.muted {
+            "m".to_string()
+        } else if self.gain_db != 0.0 {
+            format!("{:+.1}", self.gain_db)
+        } else {
+            "x".to_string()
+        }
+    }
+}
+
+/// Routing grid of source channels (rows) against sink channels (columns).
+pub struct Matrix {
+    sources: Vec<Endpoint>,
+    sinks: Vec<Endpoint>,
+    routes: BTreeMap<(Endpoint, Endpoint), Crosspoint>,
+}
+
+impl Matrix {
+    /// Build the grid from the host, source channel and sink channel of every route.
+    pub fn new<'a, I>(routes: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, (&'a str, u16), (&'a str, u16), Crosspoint)>,
+    {
+        let routes: BTreeMap<_, _> = routes
+            .into_iter()
+            .map(
+                |(host, (source, source_channel), (sink, sink_channel), crosspoint)| {
+                    (
+                        (
+                            (host.to_owned(), source.to_owned(), source_channel),
+                            (host.to_owned(), sink.to_owned(), sink_channel),
+                        ),
+                        crosspoint,
+                    )
+                },
+            )
+            .collect();
+
+        // sorted and deduplicated so the output does not depend on connection order
+        let sources: BTreeSet<_> = routes.keys().map(|(source, _)| source.clone()).collect();
+        let sinks: BTreeSet<_> = routes.keys().map(|(_, sink)| sink.clone()).collect();
+
+        Matrix {
+            sources: sources.into_iter().collect(),
+            sinks: sinks.into_iter().collect(),
+            routes,
+        }
+    }
+
+    /// Add rows and columns for channels that are not routed yet.
+    pub fn with_endpoints<S, K>(mut self, sources: S, sinks: K) -> Self
+    where
+        S: IntoIterator<Item = Endpoint>,
+        K: IntoIterator<Item = Endpoint>,
+    {
+        let merge = |current: &mut Vec<Endpoint>, new: Vec<Endpoint>| {
+            let merged: BTreeSet<_> = current.drain(..).chain(new).collect();
+            current.extend(merged);
+        };
+        merge(&mut self.sources, sources.into_iter().collect());
+        merge(&mut self.sinks, sinks.into_iter().collect());
+        self
+    }
+
+    pub fn sources(&self) -> &[Endpoint] {
+        &self.sources
+    }
+
+    pub fn sinks(&self) -> &[Endpoint] {
+        &self.sinks
+    }
+
+    /// Label of a row or column, with the host only if another host has a device of the
+    /// same name.
+    pub fn label(&self, (host, name, channel): &Endpoint) -> String {
+        let ambiguous = self
+            .sources
+            .iter()
+            .chain(&self.sinks)
+            .any(|(h, n, _)| n == name && h != host);
+        if ambiguous {
+            format!("{}({}) [{}]", name, channel, host)
+        } else {
+            format!("{}({})", name, channel)
+        }
+    }
+
+    pub fn crosspoint(&self, source: usize, sink: usize) -> Option<&Crosspoint> {
+        let key = (
+            self.sources.get(source)?.clone(),
+            self.sinks.get(sink)?.clone(),
+        );
+        self.routes.get(&key)
+    }
+}
+
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.routes.is_empty() {
+            return writeln!(f, "No connections");
+        }
+
+        // sink labels are too long for column headers, so number them and print a legend
+        writeln!(f, "Sinks:")?;
+        for (i, sink) in self.sinks.iter().enumerate() {
+            writeln!(f, "[{}] {}", i + 1, self.label(sink))?;
+        }
+        writeln!(f, "--")?;
+
+        let labels: Vec<String> = self
+            .sources
+            .iter()
+            .map(|source| self.label(source))
+            .collect();
+        let label_width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+        let cell_width = self
+            .routes
+            .values()
+            .map(|c| c.marker().len())
+            .chain(std::iter::once(self.sinks.len().to_string().len()))
+            .max()
+            .unwrap_or(1);
+
+        write!(f, "{:label_width$} |", "")?;
+        for i in 0..self.sinks.len() {
+            write!(f, " {:>cell_width$}", i + 1)?;
+        }
+        writeln!(f)?;
+
+        for (row, label) in labels.iter().enumerate() {
+            write!(f, "{:label_width$} |", label)?;
+            for column in 0..self.sinks.len() {
+                let marker = self
+                    .crosspoint(row, column)
+                    .map_or(".".to_string(), |c| c.marker());
+                write!(f, " {:>cell_width$}", marker)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crosspoint(gain_db: f32, muted: bool) -> Crosspoint {
+        Crosspoint {
+            id: Uuid::nil(),
+            gain_db,
+            muted,
+        }
+    }
+
+    #[test]
+    fn render() {
+        let m = Matrix::new(vec![
+            ("ALSA", ("mic", 1), ("phones", 0), crosspoint(0.0, false)),
+            ("ALSA", ("mic", 0), ("phones", 1), crosspoint(-3.0, false)),
+            ("ALSA", ("mic", 0), ("phones", 0), crosspoint(0.0, true)),
+        ]);
+        assert_eq!(
+            m.to_string(),
+            "\
+Sinks:
+[1] phones(0)
+[2] phones(1)
+--
+       |    1    2
+mic(0) |    m -3.0
+mic(1) |    x    .
+"
+        );
+    }
+
+    #[test]
+    fn empty() {
+        let m = Matrix::new(vec![]);
+        assert_eq!(m.to_string(), "No connections\n");
+    }
+
+    #[test]
+    fn endpoints() {
+        let endpoint = |name: &str, channel| ("ALSA".to_string(), name.to_string(), channel);
+        let m = Matrix::new(vec![(
+            "ALSA",
+            ("mic", 0),
+            ("phones", 1),
+            crosspoint(0.0, false),
+        )])
+        .with_endpoints(vec![endpoint("mic", 1)], vec![endpoint("phones", 0)]);
+        assert_eq!(m.sources(), [endpoint("mic", 0), endpoint("mic", 1)]);
+        assert_eq!(m.sinks(), [endpoint("phones", 0), endpoint("phones", 1)]);
+        assert!(m.crosspoint(0, 0).is_none());
+        assert!(m.crosspoint(0, 1).is_some());
+    }
+
+    #[test]
+    fn hosts() {
+        let m = Matrix::new(vec![
+            (
+                "ALSA",
+                ("default", 0),
+                ("phones", 0),
+                crosspoint(0.0, false),
+            ),
+            (
+                "JACK",
+                ("default", 0),
+                ("phones", 0),
+                crosspoint(-3.0, false),
+            ),
+            (
+                "JACK",
+                ("default", 0),
+                ("system", 0),
+                crosspoint(0.0, false),
+            ),
+        ]);
+        assert_eq!(
+            m.to_string(),
+            "\
+Sinks:
+[1] phones(0) [ALSA]
+[2] phones(0) [JACK]
+[3] system(0)
+--
+                  |    1    2    3
+default(0) [ALSA] |    x    .    .
+default(0) [JACK] |    . -3.0    x
+"
+        );
+    }
+}
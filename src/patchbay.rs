@@ -1,18 +1,40 @@
-use crate::connection::Connection;
+use crate::config::{self, Config, Diff, SinkProtection};
+use crate::connection::{Connection, ConnectionMetadata, Mode, Sidechain};
+use crate::dsp::{Ducking, Filter, Gate, Protection, SinkGuard};
+use crate::feedback;
+use crate::loopback::{self, VirtualDevice};
+use crate::matrix::{Crosspoint, Matrix};
+use crate::processor::ProcessorConfig;
+use crate::system;
 
 use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry as HashEntry;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::sync::Arc;
+
+/// Number of routing changes kept for undo.
+const HISTORY: usize = 100;
+
+/// Routing change recorded in the journal, along with the state before it.
+struct Entry {
+    description: String,
+    config: Config,
+}
 
-#[derive(Serialize, Deserialize)]
 pub struct Patchbay {
     host: String,
     connections: HashMap<Uuid, Connection>,
-    #[serde(skip)]
+    aliases: BTreeMap<String, String>,
+    protection: Vec<SinkProtection>,
+    /// Protection shared by the connections to each protected sink channel.
+    guards: Vec<(SinkProtection, Arc<SinkGuard>)>,
+    virtual_devices: Vec<VirtualDevice>,
     running: bool,
+    undo: Vec<Entry>,
+    redo: Vec<Entry>,
 }
 
 impl Patchbay {
@@ -20,53 +42,589 @@ impl Patchbay {
         Patchbay {
             host: host.to_owned(),
             connections: HashMap::new(),
+            aliases: BTreeMap::new(),
+            protection: Vec::new(),
+            guards: Vec::new(),
+            virtual_devices: Vec::new(),
             running: false,
+            undo: Vec::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    /// Create a halted patchbay, opening the streams of every connection in the configuration.
+    pub fn from_config(config: Config) -> Result<Self> {
+        let mut patchbay = Patchbay::new(&config.host);
+        let mut hosts = HashMap::new();
+        let connections = config
+            .connections
+            .iter()
+            .map(|(id, metadata)| {
+                let devices = Self::devices(&config, metadata, &mut hosts)?;
+                Ok((*id, Connection::from_metadata(metadata.clone(), devices)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        patchbay.aliases = config.aliases;
+        patchbay.protection = config.protection;
+        patchbay.virtual_devices = config.virtual_devices;
+        for (id, connection) in connections {
+            patchbay.insert(id, connection)?;
+        }
+        Ok(patchbay)
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            host: self.host.clone(),
+            connections: self
+                .connections
+                .iter()
+                .map(|(id, c)| (*id, c.metadata().clone()))
+                .collect(),
+            aliases: self.aliases.clone(),
+            protection: self.protection.clone(),
+            virtual_devices: self.virtual_devices.clone(),
+        }
+    }
+
+    /// Replace every connection with the ones in the configuration, leaving the patchbay
+    /// halted. On error the existing routing is left in place.
+    pub fn load(&mut self, config: Config) -> Result<()> {
+        let previous = self.config();
+        let new = Patchbay::from_config(config)?;
+
+        self.halt()?;
+        self.host = new.host;
+        self.connections = new.connections;
+        self.aliases = new.aliases;
+        self.protection = new.protection;
+        self.virtual_devices = new.virtual_devices;
+        self.record(previous, "load configuration".to_string());
+        Ok(())
+    }
+
+    /// Apply the differences to another configuration without interrupting unchanged
+    /// connections. The streams of new connections are opened first, so that on error
+    /// the existing routing is left in place.
+    pub fn apply(&mut self, config: Config) -> Result<Diff> {
+        let previous = self.config();
+        let host_changed = config.host != self.host;
+        let protection_changed = config.protection != self.protection;
+        let virtual_devices_changed = config.virtual_devices != self.virtual_devices;
+        let diff = self.update(config)?;
+        if host_changed || protection_changed || virtual_devices_changed || !diff.is_empty() {
+            self.record(previous, "apply configuration".to_string());
+        }
+        Ok(diff)
+    }
+
+    /// Revert the last routing change, returning its description.
+    pub fn undo(&mut self) -> Result<String> {
+        let entry = self.undo.pop().ok_or(anyhow!("Nothing to undo"))?;
+        let current = self.config();
+        match self.update(entry.config.clone()) {
+            Ok(_) => {
+                self.redo.push(Entry {
+                    description: entry.description.clone(),
+                    config: current,
+                });
+                Ok(entry.description)
+            }
+            Err(e) => {
+                self.undo.push(entry);
+                Err(e)
+            }
         }
     }
 
+    /// Reapply the last undone routing change, returning its description.
+    pub fn redo(&mut self) -> Result<String> {
+        let entry = self.redo.pop().ok_or(anyhow!("Nothing to redo"))?;
+        let current = self.config();
+        match self.update(entry.config.clone()) {
+            Ok(_) => {
+                self.undo.push(Entry {
+                    description: entry.description.clone(),
+                    config: current,
+                });
+                Ok(entry.description)
+            }
+            Err(e) => {
+                self.redo.push(entry);
+                Err(e)
+            }
+        }
+    }
+
+    /// Bring the routing in line with a configuration. New connections are opened and
+    /// changed ones reconfigured before any connection is removed or added, and changed
+    /// connections are put back as they were if one of them fails, so that on error the
+    /// routing is left untouched.
+    fn update(&mut self, config: Config) -> Result<Diff> {
+        let previous = self.config();
+        let diff = config::diff(&previous, &config);
+
+        for sink in &config.protection {
+            sink.protection
+                .check()
+                .map_err(|(_, message)| anyhow!(message))?;
+        }
+        let mut hosts = HashMap::new();
+        let added = diff
+            .added
+            .iter()
+            .map(|(id, metadata)| {
+                let devices = Self::devices(&config, metadata, &mut hosts)?;
+                Ok((*id, Connection::from_metadata(metadata.clone(), devices)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (n, (id, metadata)) in diff.changed.iter().enumerate() {
+            if let Err(e) = self.connection_mut(id).and_then(|c| configure(c, metadata)) {
+                for (id, _) in &diff.changed[..=n] {
+                    if let (Some(connection), Some(metadata)) =
+                        (self.connections.get_mut(id), previous.connections.get(id))
+                    {
+                        if let Err(e) = configure(connection, metadata) {
+                            log::error!("Could not restore connection {}: {}", id, e);
+                        }
+                    }
+                }
+                self.link_sidechains()?;
+                return Err(e);
+            }
+        }
+
+        for id in &diff.removed {
+            if let Some(connection) = self.connections.remove(id) {
+                connection.halt()?;
+            }
+        }
+        for (id, connection) in added {
+            self.insert(id, connection)?;
+        }
+
+        self.host = config.host;
+        self.aliases = config.aliases;
+        self.protection = config.protection;
+        self.virtual_devices = config.virtual_devices;
+        self.protect_connections()?;
+        self.link_sidechains()?;
+        Ok(diff)
+    }
+
+    /// Source and sink devices of a connection from a configuration. Aliases and virtual
+    /// devices select devices like names given by the user, any other name must be the
+    /// exact name of a device, so that saved connections never move to another device.
+    fn devices(
+        config: &Config,
+        m: &ConnectionMetadata,
+        hosts: &mut HashMap<String, system::Devices>,
+    ) -> Result<(String, String)> {
+        let devices = match hosts.entry(m.host_name.clone()) {
+            HashEntry::Occupied(entry) => entry.into_mut(),
+            HashEntry::Vacant(entry) => entry.insert(system::Devices::list(&m.host_name)?),
+        };
+        let selector = |name: &str| {
+            config
+                .virtual_devices
+                .iter()
+                .find(|v| v.host_name == m.host_name && v.name == name)
+                .map(|v| v.device.clone())
+                .or_else(|| {
+                    config
+                        .aliases
+                        .get(name)
+                        .map(|selector| system::split_qualified(selector).1)
+                })
+        };
+        let source = match selector(&m.source_name) {
+            Some(selector) => devices.input(&selector)?,
+            None => devices.input_named(&m.source_name)?,
+        };
+        let sink = match selector(&m.sink_name) {
+            Some(selector) => devices.output(&selector)?,
+            None => devices.output_named(&m.sink_name)?,
+        };
+        Ok((source.0.clone(), sink.0.clone()))
+    }
+
     pub fn host(&self) -> &str {
         &self.host
     }
 
+    /// Select the default host for new connections. Existing connections keep their host.
     pub fn set_host(&mut self, host: &str) -> Result<()> {
+        if host == self.host {
+            return Ok(());
+        }
+        let previous = self.config();
         self.host = host.to_string();
+        self.record(previous, format!("host {}", host));
         Ok(())
     }
 
-    pub fn add_connection(&mut self, connection: Connection) -> Result<Uuid> {
-        // make sure connection is the in the correct state
-        // (sometimes audio streams are auto started)
-        if self.running {
-            connection.run()?;
+    pub fn aliases(&self) -> &BTreeMap<String, String> {
+        &self.aliases
+    }
+
+    /// Device selector an alias stands for, the host qualified device behind a virtual
+    /// device, or the name itself if it is neither.
+    pub fn expand_alias(&self, name: &str) -> String {
+        if let Some(v) = self.virtual_devices.iter().find(|v| v.name == name) {
+            return format!("{}:{}", v.host_name, v.device);
+        }
+        self.aliases
+            .get(name)
+            .map_or(name, String::as_str)
+            .to_owned()
+    }
+
+    pub fn set_alias(&mut self, name: &str, selector: &str) -> Result<()> {
+        let previous = self.config();
+        self.aliases.insert(name.to_string(), selector.to_string());
+        self.record(previous, format!("alias {}", name));
+        Ok(())
+    }
+
+    pub fn remove_alias(&mut self, name: &str) -> Result<()> {
+        let previous = self.config();
+        self.aliases
+            .remove(name)
+            .ok_or(anyhow!("Alias {} does not exist.", name))?;
+        self.record(previous, format!("unalias {}", name));
+        Ok(())
+    }
+
+    pub fn virtual_devices(&self) -> &[VirtualDevice] {
+        &self.virtual_devices
+    }
+
+    /// Add a virtual device on a free loopback device of the host.
+    pub fn add_virtual(&mut self, name: &str, host_name: &str) -> Result<&VirtualDevice> {
+        if self.virtual_devices.iter().any(|v| v.name == name) {
+            return Err(anyhow!("Virtual device {} already exists.", name));
+        }
+        let (device, peer) = loopback::allocate(host_name, &self.virtual_devices)?;
+
+        let previous = self.config();
+        self.virtual_devices.push(VirtualDevice {
+            name: name.to_owned(),
+            host_name: host_name.to_owned(),
+            device,
+            peer,
+        });
+        self.record(previous, format!("virtual {}", name));
+        Ok(&self.virtual_devices[self.virtual_devices.len() - 1])
+    }
+
+    /// Remove a virtual device, releasing its loopback device. Connections to it are left
+    /// in place, as they use the loopback device itself.
+    pub fn remove_virtual(&mut self, name: &str) -> Result<()> {
+        let previous = self.config();
+        let count = self.virtual_devices.len();
+        self.virtual_devices.retain(|v| v.name != name);
+        if self.virtual_devices.len() == count {
+            return Err(anyhow!("Virtual device {} does not exist.", name));
+        }
+        self.record(previous, format!("unvirtual {}", name));
+        Ok(())
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = (&Uuid, &Connection)> {
+        self.connections.iter()
+    }
+
+    pub fn connection(&self, id: &Uuid) -> Result<&Connection> {
+        self.connections
+            .get(id)
+            .ok_or(anyhow!("Connection {} does not exist.", id))
+    }
+
+    pub fn matrix(&self) -> Matrix {
+        // connections panning or summing two channels show up at both crosspoints
+        Matrix::new(self.connections.iter().flat_map(|(id, c)| {
+            c.channels()
+                .into_iter()
+                .map(move |(source_channel, sink_channel)| {
+                    (
+                        c.host_name(),
+                        (c.source_device(), source_channel),
+                        (c.sink_device(), sink_channel),
+                        Crosspoint {
+                            id: *id,
+                            gain_db: c.gain_db(),
+                            muted: c.muted(),
+                        },
+                    )
+                })
+        }))
+    }
+
+    pub fn set_gain(&mut self, id: &Uuid, gain_db: f32) -> Result<()> {
+        let previous = self.config();
+        self.processed_mut(id)?.set_gain_db(gain_db);
+        self.record_adjustment(previous, format!("gain {}", id));
+        Ok(())
+    }
+
+    pub fn set_pan(&mut self, id: &Uuid, pan: f32) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        let pan_law = connection.pan_law();
+        connection.set_pan(pan, pan_law)?;
+        self.record_adjustment(previous, format!("pan {}", id));
+        Ok(())
+    }
+
+    pub fn set_delay(&mut self, id: &Uuid, delay_ms: f32) -> Result<()> {
+        let previous = self.config();
+        self.processed_mut(id)?.set_delay_ms(delay_ms)?;
+        self.record_adjustment(previous, format!("delay {}", id));
+        Ok(())
+    }
+
+    /// Append a filter to the filter chain of a connection.
+    pub fn add_filter(&mut self, id: &Uuid, filter: Filter) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        let mut filters = connection.filters().to_vec();
+        let description = format!("filter {} {}", id, filter);
+        filters.push(filter);
+        connection.set_filters(filters)?;
+        self.record(previous, description);
+        Ok(())
+    }
+
+    /// Remove a filter by its position in the chain, or every filter of the connection.
+    pub fn remove_filter(&mut self, id: &Uuid, index: Option<usize>) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        let mut filters = connection.filters().to_vec();
+        match index {
+            Some(index) if index < filters.len() => {
+                filters.remove(index);
+            }
+            Some(index) => return Err(anyhow!("Connection {} has no filter {}", id, index)),
+            None if filters.is_empty() => return Ok(()),
+            None => filters.clear(),
+        }
+        connection.set_filters(filters)?;
+        self.record(previous, format!("unfilter {}", id));
+        Ok(())
+    }
+
+    /// Set or remove the noise gate of a connection.
+    pub fn set_gate(&mut self, id: &Uuid, gate: Option<Gate>) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        if gate.is_none() && connection.gate().is_none() {
+            return Ok(());
+        }
+        let removed = gate.is_none();
+        connection.set_gate(gate)?;
+        if removed {
+            self.record(previous, format!("ungate {}", id));
         } else {
-            connection.halt()?;
+            self.record_adjustment(previous, format!("gate {}", id));
         }
+        Ok(())
+    }
 
-        let id = Uuid::new_v4();
+    /// Append a processor to the processor chain of a connection.
+    pub fn add_processor(&mut self, id: &Uuid, processor: ProcessorConfig) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        let mut processors = connection.processors().to_vec();
+        let description = format!("insert {} {}", id, processor);
+        processors.push(processor);
+        connection.set_processors(processors)?;
+        self.record(previous, description);
+        Ok(())
+    }
 
-        self.connections.insert(id, connection);
+    /// Remove a processor by its position in the chain, or every processor of the
+    /// connection.
+    pub fn remove_processor(&mut self, id: &Uuid, index: Option<usize>) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        let mut processors = connection.processors().to_vec();
+        match index {
+            Some(index) if index < processors.len() => {
+                processors.remove(index);
+            }
+            Some(index) => return Err(anyhow!("Connection {} has no processor {}", id, index)),
+            None if processors.is_empty() => return Ok(()),
+            None => processors.clear(),
+        }
+        connection.set_processors(processors)?;
+        self.record(previous, format!("uninsert {}", id));
+        Ok(())
+    }
+
+    pub fn protection(&self) -> &[SinkProtection] {
+        &self.protection
+    }
 
+    /// Set the protection of a sink channel, applying it to every connection to the
+    /// channel, now and in the future.
+    pub fn protect(
+        &mut self,
+        host_name: &str,
+        sink_name: &str,
+        sink_channel: u16,
+        protection: Protection,
+    ) -> Result<()> {
+        protection
+            .check()
+            .map_err(|(_, message)| anyhow!(message))?;
+
+        let previous = self.config();
+        let sink = SinkProtection {
+            host_name: host_name.to_owned(),
+            sink_name: sink_name.to_owned(),
+            sink_channel,
+            protection,
+        };
+        self.protection.retain(|p| {
+            (&p.host_name, &p.sink_name, p.sink_channel)
+                != (&sink.host_name, &sink.sink_name, sink.sink_channel)
+        });
+        self.protection.push(sink);
+        self.protect_connections()?;
+        self.record(previous, format!("protect {}({})", sink_name, sink_channel));
+        Ok(())
+    }
+
+    pub fn unprotect(&mut self, host_name: &str, sink_name: &str, sink_channel: u16) -> Result<()> {
+        let previous = self.config();
+        let count = self.protection.len();
+        self.protection.retain(|p| {
+            (p.host_name.as_str(), p.sink_name.as_str(), p.sink_channel)
+                != (host_name, sink_name, sink_channel)
+        });
+        if self.protection.len() == count {
+            return Err(anyhow!(
+                "Sink {}({}) is not protected",
+                sink_name,
+                sink_channel
+            ));
+        }
+        self.protect_connections()?;
+        self.record(
+            previous,
+            format!("unprotect {}({})", sink_name, sink_channel),
+        );
+        Ok(())
+    }
+
+    /// Duck a connection while the source channel of another connection is above the
+    /// threshold.
+    pub fn duck(&mut self, id: &Uuid, trigger: &Uuid, ducking: Ducking) -> Result<()> {
+        if id == trigger {
+            return Err(anyhow!("Connection {} cannot duck itself", id));
+        }
+        let trigger = self
+            .connections
+            .get(trigger)
+            .ok_or(anyhow!("Connection {} does not exist.", trigger))?;
+        if trigger.mode() == Mode::Link {
+            return Err(anyhow!("Direct links measure no level to duck by"));
+        }
+        let sidechain = Sidechain {
+            source_name: trigger.source_name().to_owned(),
+            source_channel: trigger.source_channel(),
+            ducking,
+        };
+
+        let previous = self.config();
+        self.processed_mut(id)?.set_sidechain(Some(sidechain))?;
+        self.link_sidechains()?;
+        self.record(previous, format!("duck {}", id));
+        Ok(())
+    }
+
+    pub fn unduck(&mut self, id: &Uuid) -> Result<()> {
+        let previous = self.config();
+        let connection = self.processed_mut(id)?;
+        if connection.sidechain().is_none() {
+            return Err(anyhow!("Connection {} is not ducked", id));
+        }
+        connection.set_sidechain(None)?;
+        self.record(previous, format!("unduck {}", id));
+        Ok(())
+    }
+
+    /// Unmute the sink channel of a connection whose protection tripped, or of every
+    /// connection without an id.
+    pub fn rearm(&mut self, id: Option<&Uuid>) -> Result<()> {
+        match id {
+            Some(id) => self.connection_mut(id)?.rearm(),
+            None => self.connections.values().try_for_each(Connection::rearm),
+        }
+    }
+
+    pub fn set_muted(&mut self, id: &Uuid, muted: bool) -> Result<()> {
+        let previous = self.config();
+        self.processed_mut(id)?.set_muted(muted);
+        let action = if muted { "mute" } else { "unmute" };
+        self.record(previous, format!("{} {}", action, id));
+        Ok(())
+    }
+
+    /// Add a connection, refusing it if it closes a feedback loop through loopback devices
+    /// unless forced.
+    pub fn add_connection(&mut self, connection: Connection, force: bool) -> Result<Uuid> {
+        if let (false, Some(description)) = (force, self.feedback(&connection.route())) {
+            return Err(anyhow!(
+                "Connection would close a feedback loop: {}",
+                description
+            ));
+        }
+
+        let previous = self.config();
+        let id = Uuid::new_v4();
+        let description = format!(
+            "connect {}({}) -> {}({})",
+            connection.source_name(),
+            connection.source_channel(),
+            connection.sink_name(),
+            connection.sink_channel()
+        );
+        self.insert(id, connection)?;
+        self.record(previous, description);
         Ok(id)
     }
 
     pub fn remove_connection(&mut self, id: &Uuid) -> Result<()> {
+        let previous = self.config();
         let c = self
             .connections
             .get(id)
             .ok_or(anyhow!("Connection {} does not exist.", id))?;
         c.halt()?;
         self.connections.remove(id);
+        self.link_sidechains()?;
+        self.record(previous, format!("disconnect {}", id));
         Ok(())
     }
 
     pub fn remove_all_connections(&mut self) -> Result<()> {
+        let previous = self.config();
         self.connections
             .iter()
             .try_for_each(|(_, connection)| connection.halt())?;
         self.connections.clear();
+        if !previous.connections.is_empty() {
+            self.record(previous, "disconnect *".to_string());
+        }
         Ok(())
     }
 
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
     pub fn run(&mut self) -> Result<()> {
         self.connections
             .iter()
@@ -82,14 +640,185 @@ impl Patchbay {
         self.running = false;
         Ok(())
     }
+
+    /// Journal a routing change, forgetting any undone changes.
+    fn record(&mut self, previous: Config, description: String) {
+        if self.undo.len() == HISTORY {
+            self.undo.remove(0);
+        }
+        self.undo.push(Entry {
+            description,
+            config: previous,
+        });
+        self.redo.clear();
+    }
+
+    /// Journal a parameter change, where repeated adjustments of the same parameter are
+    /// undone in one step.
+    fn record_adjustment(&mut self, previous: Config, description: String) {
+        let repeated = self.redo.is_empty()
+            && self
+                .undo
+                .last()
+                .is_some_and(|entry| entry.description == description);
+        if !repeated {
+            self.record(previous, description);
+        }
+    }
+
+    /// Give every connection the protection of its sink channel, shared with the other
+    /// connections to it. Sinks whose protection is unchanged keep their state, so a
+    /// tripped sink stays muted.
+    fn protect_connections(&mut self) -> Result<()> {
+        let protection = &self.protection;
+        self.guards.retain(|(sink, _)| protection.contains(sink));
+        for sink in protection {
+            if !self.guards.iter().any(|(guarded, _)| guarded == sink) {
+                let guard = Arc::new(SinkGuard::new(&sink.protection));
+                self.guards.push((sink.clone(), guard));
+            }
+        }
+        for connection in self.connections.values_mut() {
+            let guard = self
+                .guards
+                .iter()
+                // the signal of direct links never passes through patchbay to be guarded
+                .find(|(sink, _)| {
+                    connection.mode() != Mode::Link && sink.covers(&connection.route())
+                })
+                .map(|(_, guard)| guard);
+            if connection.protection().map(Arc::as_ptr) != guard.map(Arc::as_ptr) {
+                connection.set_protection(guard.cloned())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Give every ducked connection the level of its sidechain source, measured by any
+    /// connection from that source channel, named by the device or the name a connection
+    /// selected it by. Connections whose source is not connected anywhere are released
+    /// until it is.
+    fn link_sidechains(&mut self) -> Result<()> {
+        let levels: HashMap<_, _> = self
+            .connections
+            .values()
+            // the signal of direct links never passes through patchbay to be measured
+            .filter(|c| c.mode() != Mode::Link)
+            .flat_map(|c| {
+                [c.source_device(), c.source_name()].map(|name| {
+                    (
+                        (
+                            c.host_name().to_owned(),
+                            name.to_owned(),
+                            c.source_channel(),
+                        ),
+                        c.level(),
+                    )
+                })
+            })
+            .collect();
+        for connection in self.connections.values_mut() {
+            let level = connection.sidechain().and_then(|sidechain| {
+                levels
+                    .get(&(
+                        connection.host_name().to_owned(),
+                        sidechain.source_name.clone(),
+                        sidechain.source_channel,
+                    ))
+                    .cloned()
+            });
+            connection.set_trigger(level)?;
+        }
+        Ok(())
+    }
+
+    /// Describe the feedback loop a new connection would close with the existing ones.
+    fn feedback(&self, new: &ConnectionMetadata) -> Option<String> {
+        let routes: Vec<_> = self.connections.values().map(Connection::route).collect();
+        feedback::find_loop(&routes.iter().collect::<Vec<_>>(), new)
+            .map(|path| feedback::describe(new, &path))
+    }
+
+    /// Add a connection in the running state of the patchbay. Connections closing a feedback
+    /// loop are only refused when added by the user, loops coming from configurations are
+    /// logged.
+    fn insert(&mut self, id: Uuid, connection: Connection) -> Result<()> {
+        if let Some(description) = self.feedback(&connection.route()) {
+            log::warn!("Connection closes a feedback loop: {}", description);
+        }
+
+        // make sure connection is the in the correct state
+        // (sometimes audio streams are auto started)
+        if self.running {
+            connection.run()?;
+        } else {
+            connection.halt()?;
+        }
+
+        self.connections.insert(id, connection);
+        self.protect_connections()?;
+        self.link_sidechains()
+    }
+
+    fn connection_mut(&mut self, id: &Uuid) -> Result<&mut Connection> {
+        self.connections
+            .get_mut(id)
+            .ok_or(anyhow!("Connection {} does not exist.", id))
+    }
+
+    /// Connection whose signal runs through patchbay, as direct links have no gain, mute
+    /// or processing.
+    fn processed_mut(&mut self, id: &Uuid) -> Result<&mut Connection> {
+        let connection = self.connection_mut(id)?;
+        if connection.mode() == Mode::Link {
+            return Err(anyhow!(
+                "Connection {} is a direct link, without gain, mute or processing",
+                id
+            ));
+        }
+        Ok(connection)
+    }
+}
+
+/// Set every parameter of a connection to the one in the metadata.
+fn configure(connection: &mut Connection, metadata: &ConnectionMetadata) -> Result<()> {
+    metadata
+        .check_link()
+        .map_err(|(_, message)| anyhow!(message))?;
+    connection.set_gain_db(metadata.gain_db);
+    connection.set_muted(metadata.muted);
+    connection.set_pan(metadata.pan, metadata.pan_law)?;
+    connection.set_delay_ms(metadata.delay_ms)?;
+    connection.set_filters(metadata.filters.clone())?;
+    connection.set_gate(metadata.gate.clone())?;
+    connection.set_processors(metadata.processors.clone())?;
+    connection.set_sidechain(metadata.sidechain.clone())
 }
 
 impl fmt::Display for Patchbay {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Running: {}", self.running)?;
         writeln!(f, "--")?;
-        writeln!(f, "Host: {}", self.host)?;
+        writeln!(f, "Default host: {}", self.host)?;
         writeln!(f, "--")?;
+        if !self.protection.is_empty() {
+            writeln!(f, "Protected sinks:")?;
+            for sink in &self.protection {
+                writeln!(
+                    f,
+                    "{}({}) [{}]: {}",
+                    sink.sink_name, sink.sink_channel, sink.host_name, sink.protection
+                )?;
+            }
+            writeln!(f, "--")?;
+        }
+        if !self.virtual_devices.is_empty() {
+            writeln!(f, "Virtual devices:")?;
+            for v in &self.virtual_devices {
+                writeln!(f, "{}", v)?;
+            }
+            writeln!(f, "--")?;
+        }
         writeln!(f, "Connections:")?;
         for (id, c) in self.connections.iter() {
             writeln!(f, "{}: {}", id, c)?;
@@ -97,3 +826,254 @@ impl fmt::Display for Patchbay {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MAX_DELAY_MS;
+    use crate::system::TEST_HOST;
+
+    fn connect(patchbay: &mut Patchbay, source_channel: u16, mode: Mode) -> Uuid {
+        let connection = Connection::new(
+            TEST_HOST.to_string(),
+            "mic".to_string(),
+            "speakers".to_string(),
+            source_channel,
+            0,
+            mode,
+        )
+        .unwrap();
+        patchbay.add_connection(connection, false).unwrap()
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut patchbay = Patchbay::new("A");
+        assert!(patchbay.undo().is_err());
+
+        patchbay.set_host("B").unwrap();
+        patchbay.set_host("C").unwrap();
+
+        assert_eq!(patchbay.undo().unwrap(), "host C");
+        assert_eq!(patchbay.host(), "B");
+        assert_eq!(patchbay.undo().unwrap(), "host B");
+        assert_eq!(patchbay.host(), "A");
+        assert!(patchbay.undo().is_err());
+
+        assert_eq!(patchbay.redo().unwrap(), "host B");
+        assert_eq!(patchbay.host(), "B");
+
+        // a new change forgets the undone ones
+        patchbay.set_host("D").unwrap();
+        assert!(patchbay.redo().is_err());
+        assert_eq!(patchbay.undo().unwrap(), "host D");
+        assert_eq!(patchbay.host(), "B");
+    }
+
+    #[test]
+    fn unchanged_host() {
+        let mut patchbay = Patchbay::new("A");
+        patchbay.set_host("B").unwrap();
+        patchbay.set_host("B").unwrap();
+        assert_eq!(patchbay.undo().unwrap(), "host B");
+        assert_eq!(patchbay.host(), "A");
+        assert!(patchbay.undo().is_err());
+    }
+
+    #[test]
+    fn history_limit() {
+        let mut patchbay = Patchbay::new("0");
+        for n in 1..=HISTORY + 10 {
+            patchbay.set_host(&n.to_string()).unwrap();
+        }
+        while patchbay.undo().is_ok() {}
+        assert_eq!(patchbay.host(), "10");
+    }
+
+    #[test]
+    fn aliases() {
+        let mut patchbay = Patchbay::new("A");
+        patchbay.set_alias("mic", "Scarlett 2i2 USB").unwrap();
+        assert_eq!(patchbay.expand_alias("mic"), "Scarlett 2i2 USB");
+        assert_eq!(patchbay.expand_alias("phones"), "phones");
+        assert_eq!(patchbay.config().aliases.len(), 1);
+
+        assert!(patchbay.remove_alias("phones").is_err());
+        patchbay.remove_alias("mic").unwrap();
+        assert_eq!(patchbay.expand_alias("mic"), "mic");
+
+        assert_eq!(patchbay.undo().unwrap(), "unalias mic");
+        assert_eq!(patchbay.expand_alias("mic"), "Scarlett 2i2 USB");
+    }
+
+    #[test]
+    fn saved_names() {
+        let mut config = Patchbay::new(TEST_HOST).config();
+        config.aliases.insert("in".to_string(), "MI".to_string());
+        let mut metadata = ConnectionMetadata::new(
+            TEST_HOST.to_string(),
+            "in".to_string(),
+            "speakers".to_string(),
+            0,
+            0,
+        );
+        config.connections.insert(Uuid::new_v4(), metadata.clone());
+
+        // aliases select like user input and are saved as they are
+        let patchbay = Patchbay::from_config(config.clone()).unwrap();
+        let (_, connection) = patchbay.connections().next().unwrap();
+        assert_eq!(connection.source_device(), "mic");
+        assert_eq!(patchbay.config(), config);
+
+        // saved device names must match exactly
+        metadata.source_name = "MI".to_string();
+        config.connections.clear();
+        config.connections.insert(Uuid::new_v4(), metadata);
+        assert!(Patchbay::from_config(config).is_err());
+    }
+
+    #[test]
+    fn virtual_devices() {
+        let mut patchbay = Patchbay::new("A");
+        let mut config = patchbay.config();
+        config.virtual_devices.push(VirtualDevice {
+            name: "app".to_string(),
+            host_name: "ALSA".to_string(),
+            device: "hw:CARD=Loopback,DEV=1".to_string(),
+            peer: "hw:CARD=Loopback,DEV=0".to_string(),
+        });
+        patchbay.apply(config).unwrap();
+        assert_eq!(patchbay.expand_alias("app"), "ALSA:hw:CARD=Loopback,DEV=1");
+
+        assert!(patchbay.add_virtual("app", "ALSA").is_err());
+        assert!(patchbay.remove_virtual("game").is_err());
+        patchbay.remove_virtual("app").unwrap();
+        assert_eq!(patchbay.expand_alias("app"), "app");
+
+        assert_eq!(patchbay.undo().unwrap(), "unvirtual app");
+        assert_eq!(patchbay.virtual_devices().len(), 1);
+        assert_eq!(patchbay.undo().unwrap(), "apply configuration");
+        assert!(patchbay.virtual_devices().is_empty());
+    }
+
+    #[test]
+    fn unchanged() {
+        let mut patchbay = Patchbay::new("A");
+        patchbay.remove_all_connections().unwrap();
+        patchbay.apply(patchbay.config()).unwrap();
+        assert!(patchbay.undo().is_err());
+    }
+
+    #[test]
+    fn failed_update() {
+        let mut patchbay = Patchbay::new(TEST_HOST);
+        let single = connect(&mut patchbay, 0, Mode::Single);
+        let panned = connect(&mut patchbay, 1, Mode::Pan);
+        let before = patchbay.config();
+
+        // every valid change is rolled back along with the invalid one
+        for (delay_ms, pan) in [(MAX_DELAY_MS + 1.0, 0.5), (10.0, 2.0)] {
+            let mut config = before.clone();
+            for m in config.connections.values_mut() {
+                m.gain_db = -6.0;
+            }
+            config.connections.get_mut(&single).unwrap().delay_ms = delay_ms;
+            config.connections.get_mut(&panned).unwrap().pan = pan;
+            let mut added = before.connections[&single].clone();
+            added.sink_channel = 1;
+            config.connections.insert(Uuid::new_v4(), added);
+
+            assert!(patchbay.apply(config).is_err());
+            assert_eq!(patchbay.config(), before);
+        }
+        assert_eq!(patchbay.connection(&single).unwrap().delay_ms(), 0.0);
+        assert_eq!(patchbay.connection(&panned).unwrap().pan(), 0.0);
+        assert_eq!(patchbay.undo().unwrap(), "connect mic(1) -> speakers(0)");
+    }
+
+    #[test]
+    fn undo_redo_connections() {
+        let mut patchbay = Patchbay::new(TEST_HOST);
+        let id = connect(&mut patchbay, 0, Mode::Single);
+        patchbay.set_gain(&id, -6.0).unwrap();
+        patchbay.remove_connection(&id).unwrap();
+
+        // undoing the disconnect reopens the connection under its id and settings
+        assert_eq!(patchbay.undo().unwrap(), format!("disconnect {}", id));
+        assert_eq!(patchbay.connection(&id).unwrap().gain_db(), -6.0);
+        assert_eq!(patchbay.undo().unwrap(), format!("gain {}", id));
+        assert_eq!(patchbay.undo().unwrap(), "connect mic(0) -> speakers(0)");
+        assert!(patchbay.connection(&id).is_err());
+
+        // rebuilding connections on redo keeps the remaining redo steps
+        assert_eq!(patchbay.redo().unwrap(), "connect mic(0) -> speakers(0)");
+        assert_eq!(patchbay.connection(&id).unwrap().gain_db(), 0.0);
+        assert_eq!(patchbay.redo().unwrap(), format!("gain {}", id));
+        assert_eq!(patchbay.connection(&id).unwrap().gain_db(), -6.0);
+        assert_eq!(patchbay.redo().unwrap(), format!("disconnect {}", id));
+        assert!(patchbay.connection(&id).is_err());
+        assert!(patchbay.redo().is_err());
+    }
+
+    #[test]
+    fn shared_protection() {
+        let mut patchbay = Patchbay::new(TEST_HOST);
+        let first = connect(&mut patchbay, 0, Mode::Single);
+        let protection = Protection {
+            ceiling_db: Some(-6.0),
+            ..Protection::default()
+        };
+        patchbay
+            .protect(TEST_HOST, "speakers", 0, protection.clone())
+            .unwrap();
+        // connections made later share the protection of the sink channel
+        let second = connect(&mut patchbay, 1, Mode::Single);
+        let guard = |patchbay: &Patchbay, id| {
+            patchbay.connections[id]
+                .protection()
+                .map(|guard| Arc::as_ptr(guard) as usize)
+        };
+        let shared = guard(&patchbay, &first);
+        assert!(shared.is_some());
+        assert_eq!(guard(&patchbay, &second), shared);
+
+        // unchanged protection keeps the guard, and with it a tripped sink muted
+        patchbay
+            .protect(TEST_HOST, "speakers", 0, protection)
+            .unwrap();
+        assert_eq!(guard(&patchbay, &first), shared);
+
+        patchbay.unprotect(TEST_HOST, "speakers", 0).unwrap();
+        assert_eq!(guard(&patchbay, &first), None);
+        assert_eq!(guard(&patchbay, &second), None);
+    }
+
+    #[test]
+    fn links() {
+        let mut patchbay = Patchbay::new(TEST_HOST);
+        let link = connect(&mut patchbay, 0, Mode::Link);
+        assert!(patchbay.set_gain(&link, -6.0).is_err());
+        assert!(patchbay.set_muted(&link, true).is_err());
+        let highpass = Filter::new(crate::dsp::FilterKind::HighPass, 80.0);
+        assert!(patchbay.add_filter(&link, highpass).is_err());
+        let protection = Protection {
+            ceiling_db: Some(-6.0),
+            ..Protection::default()
+        };
+        patchbay
+            .protect(TEST_HOST, "speakers", 0, protection)
+            .unwrap();
+        assert!(patchbay.connection(&link).unwrap().protection().is_none());
+
+        // links are saved and restored like any other connection, without processing
+        let config = patchbay.config();
+        assert_eq!(config.connections[&link].mode, Mode::Link);
+        patchbay.remove_connection(&link).unwrap();
+        patchbay.apply(config.clone()).unwrap();
+        assert_eq!(patchbay.config(), config);
+        let mut processed = config.clone();
+        processed.connections.get_mut(&link).unwrap().gain_db = -6.0;
+        assert!(patchbay.apply(processed).is_err());
+        assert_eq!(patchbay.config(), config);
+    }
+}
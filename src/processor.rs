@@ -0,0 +1,477 @@
+use crate::connection::{self, Level};
+use crate::dsp::{Biquad, Delay, Ducker, Ducking, Filter, Gate, Limiter, NoiseGate};
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Processing stage of a connection, run by the audio callback on each block of samples
+/// of the connection's channel. The delay, filters, noise gate and ducking of a connection
+/// are processors as well, built from the registry like the inserted ones.
+///
+/// Processors run on the audio thread, so `process` should not block or allocate.
+pub trait Processor: Send + Any {
+    fn process(&mut self, samples: &mut [f32]);
+
+    /// Take over the state of the processor this one replaces when the chain of a running
+    /// connection changes. Processors start from a clean state by default.
+    fn continue_from(&mut self, _previous: &mut dyn Processor) {}
+}
+
+/// Processor parameters, as given in configuration files or on the command line.
+pub type Params = serde_json::Map<String, Value>;
+
+/// Creates a processor from its parameters and the connection it runs in.
+pub type Factory = dyn Fn(&Params, &Context) -> Result<Box<dyn Processor>> + Send + Sync;
+
+/// What processors get to know about their connection besides their parameters.
+#[derive(Clone)]
+pub struct Context {
+    pub sample_rate: u32,
+    /// Level of the source channel ducking the connection, while it is connected.
+    pub sidechain: Option<Level>,
+    /// Whether the noise gate lets the signal through.
+    pub gate_open: Arc<AtomicBool>,
+    /// Gain of the ducking, as the bits of an `f32`.
+    pub duck_gain: Arc<AtomicU32>,
+}
+
+impl Context {
+    pub fn new(sample_rate: u32) -> Self {
+        Context {
+            sample_rate,
+            sidechain: None,
+            gate_open: Arc::new(AtomicBool::new(false)),
+            duck_gain: Arc::new(AtomicU32::new(1_f32.to_bits())),
+        }
+    }
+}
+
+/// Processor settings as stored in configuration files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProcessorConfig {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Params::is_empty")]
+    pub params: Params,
+}
+
+impl ProcessorConfig {
+    pub fn new(name: &str) -> Self {
+        ProcessorConfig {
+            name: name.to_string(),
+            params: Params::new(),
+        }
+    }
+
+    /// Processor taking the fields of a settings struct as parameters.
+    pub fn with_settings<T: Serialize>(name: &str, settings: &T) -> Self {
+        let params = match serde_json::to_value(settings) {
+            Ok(Value::Object(params)) => params,
+            _ => Params::new(),
+        };
+        ProcessorConfig {
+            name: name.to_string(),
+            params,
+        }
+    }
+
+    /// Add a parameter, parsing numbers and booleans and keeping anything else as a
+    /// string.
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        let value = serde_json::from_str(value)
+            .ok()
+            .filter(|value: &Value| value.is_number() || value.is_boolean())
+            .unwrap_or_else(|| Value::String(value.to_string()));
+        self.params.insert(name.to_string(), value);
+        self
+    }
+
+    /// Create the processor with the factory registered under its name.
+    pub fn build(&self, context: &Context) -> Result<Box<dyn Processor>> {
+        let factory = registry()
+            .read()
+            .map_err(|_| anyhow!("Processor registry poisoned"))?
+            .get(&self.name)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown processor '{}' (available: {})",
+                    self.name,
+                    names().join(", ")
+                )
+            })?;
+        factory(&self.params, context).map_err(|e| anyhow!("{}: {}", self.name, e))
+    }
+}
+
+/// Stage of the previous chain that each stage of a new chain takes over from, where the
+/// n-th processor of a name continues from the n-th one of the same name.
+pub fn continuity(chain: &[ProcessorConfig], previous: &[ProcessorConfig]) -> Vec<Option<usize>> {
+    chain
+        .iter()
+        .enumerate()
+        .map(|(i, stage)| {
+            let n = chain[..i]
+                .iter()
+                .filter(|other| other.name == stage.name)
+                .count();
+            previous
+                .iter()
+                .enumerate()
+                .filter(|(_, other)| other.name == stage.name)
+                .nth(n)
+                .map(|(j, _)| j)
+        })
+        .collect()
+}
+
+impl fmt::Display for ProcessorConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (name, value) in &self.params {
+            match value {
+                Value::String(s) => write!(f, " {}={}", name, s)?,
+                value => write!(f, " {}={}", name, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn registry() -> &'static RwLock<BTreeMap<String, Arc<Factory>>> {
+    static REGISTRY: OnceLock<RwLock<BTreeMap<String, Arc<Factory>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut builtin: BTreeMap<String, Arc<Factory>> = BTreeMap::new();
+        builtin.insert("gain".to_string(), Arc::new(gain));
+        builtin.insert("delay".to_string(), Arc::new(delay));
+        builtin.insert("filter".to_string(), Arc::new(filter));
+        builtin.insert("limiter".to_string(), Arc::new(limiter));
+        builtin.insert("gate".to_string(), Arc::new(gate));
+        builtin.insert("duck".to_string(), Arc::new(duck));
+        RwLock::new(builtin)
+    })
+}
+
+/// Register a processor under a name, replacing any processor registered before under
+/// the same name. Connections created from now on can use it in their processor chain.
+/// Registering one of the built-in names replaces that stage of every connection built
+/// from now on.
+pub fn register<F>(name: &str, factory: F)
+where
+    F: Fn(&Params, &Context) -> Result<Box<dyn Processor>> + Send + Sync + 'static,
+{
+    if let Ok(mut registry) = registry().write() {
+        registry.insert(name.to_string(), Arc::new(factory));
+    }
+}
+
+/// Names of the registered processors.
+pub fn names() -> Vec<String> {
+    registry()
+        .read()
+        .map(|registry| registry.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Deserialize processor parameters into a settings struct.
+pub fn params<T: DeserializeOwned>(params: &Params) -> Result<T> {
+    serde_json::from_value(Value::Object(params.clone())).map_err(Into::into)
+}
+
+struct Gain(f32);
+
+impl Processor for Gain {
+    fn process(&mut self, samples: &mut [f32]) {
+        samples.iter_mut().for_each(|sample| *sample *= self.0);
+    }
+}
+
+fn gain(p: &Params, _: &Context) -> Result<Box<dyn Processor>> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Settings {
+        gain_db: f32,
+    }
+    let settings: Settings = params(p)?;
+    Ok(Box::new(Gain(10_f32.powf(settings.gain_db / 20.0))))
+}
+
+impl Processor for Delay {
+    fn process(&mut self, samples: &mut [f32]) {
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = Delay::process(self, *sample));
+    }
+
+    fn continue_from(&mut self, previous: &mut dyn Processor) {
+        if let Some(previous) = (previous as &mut dyn Any).downcast_mut::<Delay>() {
+            Delay::continue_from(self, previous);
+        }
+    }
+}
+
+fn delay(p: &Params, _: &Context) -> Result<Box<dyn Processor>> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Settings {
+        delay_ms: f32,
+    }
+    let settings: Settings = params(p)?;
+    if !(0.0..=connection::MAX_DELAY_MS).contains(&settings.delay_ms) {
+        return Err(anyhow!(
+            "Delay {}ms out of range (0 to {}ms)",
+            settings.delay_ms,
+            connection::MAX_DELAY_MS
+        ));
+    }
+    // every line is as long as the longest delay, so that a replacing delay can take over
+    // the line and crossfade to its length
+    let mut delay = Delay::new(connection::ms_to_samples(connection::MAX_DELAY_MS));
+    delay.jump(connection::ms_to_samples(settings.delay_ms));
+    Ok(Box::new(delay))
+}
+
+impl Processor for Biquad {
+    fn process(&mut self, samples: &mut [f32]) {
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = Biquad::process(self, *sample));
+    }
+
+    fn continue_from(&mut self, previous: &mut dyn Processor) {
+        if let Some(previous) = (previous as &mut dyn Any).downcast_ref::<Biquad>() {
+            Biquad::continue_from(self, previous);
+        }
+    }
+}
+
+fn filter(p: &Params, context: &Context) -> Result<Box<dyn Processor>> {
+    let filter: Filter = params(p)?;
+    filter
+        .check(context.sample_rate)
+        .map_err(|(_, message)| anyhow!(message))?;
+    Ok(Box::new(Biquad::new(&filter, context.sample_rate)))
+}
+
+impl Processor for Limiter {
+    fn process(&mut self, samples: &mut [f32]) {
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = Limiter::process(self, *sample));
+    }
+
+    fn continue_from(&mut self, previous: &mut dyn Processor) {
+        if let Some(previous) = (previous as &mut dyn Any).downcast_ref::<Limiter>() {
+            Limiter::continue_from(self, previous);
+        }
+    }
+}
+
+fn limiter(p: &Params, context: &Context) -> Result<Box<dyn Processor>> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Settings {
+        #[serde(default)]
+        ceiling_db: f32,
+        #[serde(default = "default_release")]
+        release_ms: f32,
+    }
+    fn default_release() -> f32 {
+        50.0
+    }
+    let settings: Settings = params(p)?;
+    if settings.ceiling_db > 0.0 || settings.release_ms <= 0.0 {
+        return Err(anyhow!(
+            "Ceiling must be at most 0dB and release must be positive"
+        ));
+    }
+    Ok(Box::new(Limiter::new(
+        settings.ceiling_db,
+        settings.release_ms,
+        context.sample_rate,
+    )))
+}
+
+/// Noise gate, showing whether it is open to the connection.
+struct GateStage {
+    gate: NoiseGate,
+    open: Arc<AtomicBool>,
+}
+
+impl Processor for GateStage {
+    fn process(&mut self, samples: &mut [f32]) {
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = self.gate.process(*sample));
+        self.open.store(self.gate.is_open(), Ordering::Relaxed);
+    }
+
+    fn continue_from(&mut self, previous: &mut dyn Processor) {
+        if let Some(previous) = (previous as &mut dyn Any).downcast_ref::<GateStage>() {
+            self.gate.continue_from(&previous.gate);
+        }
+    }
+}
+
+fn gate(p: &Params, context: &Context) -> Result<Box<dyn Processor>> {
+    let gate: Gate = params(p)?;
+    gate.check().map_err(|(_, message)| anyhow!(message))?;
+    Ok(Box::new(GateStage {
+        gate: NoiseGate::new(&gate, context.sample_rate),
+        open: Arc::clone(&context.gate_open),
+    }))
+}
+
+/// Ducking by the sidechain level of the connection, showing its gain to the connection.
+struct DuckStage {
+    ducker: Ducker,
+    sidechain: Option<Level>,
+    gain: Arc<AtomicU32>,
+}
+
+impl Processor for DuckStage {
+    fn process(&mut self, samples: &mut [f32]) {
+        // without a connected sidechain source the ducking releases
+        let level = self.sidechain.as_ref().map_or(0.0, Level::get);
+        let mut gain = f32::from_bits(self.gain.load(Ordering::Relaxed));
+        for sample in samples.iter_mut() {
+            gain = self.ducker.process(level);
+            *sample *= gain;
+        }
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    fn continue_from(&mut self, previous: &mut dyn Processor) {
+        if let Some(previous) = (previous as &mut dyn Any).downcast_ref::<DuckStage>() {
+            self.ducker.continue_from(&previous.ducker);
+        }
+    }
+}
+
+fn duck(p: &Params, context: &Context) -> Result<Box<dyn Processor>> {
+    let ducking: Ducking = params(p)?;
+    ducking.check().map_err(|(_, message)| anyhow!(message))?;
+    Ok(Box::new(DuckStage {
+        ducker: Ducker::new(&ducking, context.sample_rate),
+        sidechain: context.sidechain.clone(),
+        gain: Arc::clone(&context.duck_gain),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(config: &ProcessorConfig, samples: &[f32]) -> Vec<f32> {
+        let mut processor = config.build(&Context::new(48000)).unwrap();
+        let mut samples = samples.to_vec();
+        processor.process(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn builtin() {
+        let gain = ProcessorConfig::new("gain").with_param("gain_db", "-6.0206");
+        assert!((run(&gain, &[1.0])[0] - 0.5).abs() < 1e-4);
+
+        let delay = ProcessorConfig::new("delay").with_param("delay_ms", "0.0625");
+        assert_eq!(run(&delay, &[1.0, 2.0, 3.0, 4.0]), [0.0, 0.0, 0.0, 1.0]);
+
+        let filter = ProcessorConfig::new("filter")
+            .with_param("kind", "lowpass")
+            .with_param("frequency", "1000");
+        assert_eq!(run(&filter, &[0.0; 4]), [0.0; 4]);
+
+        let limiter = ProcessorConfig::new("limiter").with_param("ceiling_db", "-6.0206");
+        assert!(run(&limiter, &[1.0, -1.0, 0.25])
+            .iter()
+            .all(|sample| sample.abs() <= 0.5001));
+    }
+
+    #[test]
+    fn stages() {
+        let gate = ProcessorConfig::new("gate").with_param("threshold_db", "-40");
+        assert_eq!(run(&gate, &[0.001; 4]), [0.0; 4]);
+        // ducking without a sidechain level stays released
+        let duck = ProcessorConfig::new("duck");
+        assert_eq!(run(&duck, &[0.5; 4]), [0.5; 4]);
+        assert!(ProcessorConfig::new("duck")
+            .with_param("depth_db", "-6")
+            .build(&Context::new(48000))
+            .is_err());
+    }
+
+    #[test]
+    fn continued_stages() {
+        let chain = |names: &[&str]| -> Vec<_> {
+            names
+                .iter()
+                .map(|name| ProcessorConfig::new(name))
+                .collect()
+        };
+        assert_eq!(
+            continuity(
+                &chain(&["delay", "filter", "gate"]),
+                &chain(&["delay", "filter", "filter", "gate"])
+            ),
+            [Some(0), Some(1), Some(3)]
+        );
+        assert_eq!(
+            continuity(&chain(&["filter", "duck"]), &chain(&["delay"])),
+            [None, None]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(ProcessorConfig::new("reverb")
+            .build(&Context::new(48000))
+            .is_err());
+        assert!(ProcessorConfig::new("gain")
+            .build(&Context::new(48000))
+            .is_err());
+        assert!(ProcessorConfig::new("gain")
+            .with_param("gain_db", "loud")
+            .build(&Context::new(48000))
+            .is_err());
+        assert!(ProcessorConfig::new("gain")
+            .with_param("gain_db", "0")
+            .with_param("gain", "0")
+            .build(&Context::new(48000))
+            .is_err());
+        assert!(ProcessorConfig::new("filter")
+            .with_param("kind", "lowpass")
+            .with_param("frequency", "0")
+            .build(&Context::new(48000))
+            .is_err());
+    }
+
+    #[test]
+    fn custom() {
+        struct Invert;
+        impl Processor for Invert {
+            fn process(&mut self, samples: &mut [f32]) {
+                samples.iter_mut().for_each(|sample| *sample = -*sample);
+            }
+        }
+
+        register("invert", |_, _| Ok(Box::new(Invert)));
+        assert!(names().contains(&"invert".to_string()));
+        assert_eq!(run(&ProcessorConfig::new("invert"), &[1.0]), [-1.0]);
+    }
+
+    #[test]
+    fn display() {
+        let config = ProcessorConfig::new("filter")
+            .with_param("kind", "highpass")
+            .with_param("frequency", "80");
+        assert_eq!(config.to_string(), "filter frequency=80 kind=highpass");
+    }
+}
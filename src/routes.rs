@@ -0,0 +1,798 @@
+use crate::config::{Config, SinkProtection};
+use crate::connection::{ConnectionMetadata, Mode, Sidechain};
+use crate::dsp::{Ducking, Filter, Gate, PanLaw, Protection, DEFAULT_Q};
+use crate::loopback::VirtualDevice;
+use crate::processor::ProcessorConfig;
+use crate::script;
+
+use serde_json::Value;
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Compact, line-based configuration format listing one route per line:
+///
+/// ```text
+/// alias mic "Mic Pre"
+///
+/// host CoreAudio
+/// mic:0 -> "Headphones":1 gain=-3
+/// mic:1 -> "Headphones":0 gain=-3 delay=4.5ms filter=highpass:80 insert=limiter muted
+/// ```
+///
+/// Filters are written as `filter=<kind>:<frequency>[:<q>[:<gain>]]` and processors as
+/// `insert=<name>[:<param>=<value>,...]`, both applied in the order they are listed.
+///
+/// `gate=<threshold>[,<param>=<value>,...]` adds a noise gate after the filters, with the
+/// `hysteresis` in dB and the `attack`, `hold` and `release` times in milliseconds.
+///
+/// `pan=<position>` pans the source channel across the sink channel and the next one,
+/// with the pan law given by `law=<power|linear|balance>`, and `sum` sums the source
+/// channel and the next one into the sink channel.
+///
+/// `duck=<source>:<channel>[,<param>=<value>,...]` ducks the route while the source
+/// channel is active, with the `threshold` and `depth` in dB and the `attack` and
+/// `release` times in milliseconds.
+///
+/// `protect <sink>:<channel> [ceiling=<dB>] [trip=<dB>] [trip_time=<ms>]` lines set the
+/// output protection of a sink channel, and `virtual <name> <device> <peer>` lines add a
+/// virtual device backed by a loopback device of the host.
+///
+/// Routes use the host of the `host` line preceding them, and the first `host` line
+/// selects the patchbay host. Connection ids are not stored, new ones are generated
+/// every time the routes are loaded.
+pub struct Routes {
+    pub host: String,
+    pub aliases: BTreeMap<String, String>,
+    /// Routes along with the line they were read from.
+    pub routes: Vec<(usize, ConnectionMetadata)>,
+    pub protection: Vec<(usize, SinkProtection)>,
+    pub virtual_devices: Vec<(usize, VirtualDevice)>,
+}
+
+/// Syntax error in a route list.
+#[derive(Debug)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn read(s: &str) -> Result<Routes, Error> {
+    let mut host: Option<String> = None;
+    let mut current: Option<String> = None;
+    let mut aliases = BTreeMap::new();
+    let mut routes = Vec::new();
+    let mut protection = Vec::new();
+    let mut virtual_devices = Vec::new();
+
+    for (i, line) in s.lines().enumerate() {
+        let n = i + 1;
+        let error = |message: String| Error { line: n, message };
+
+        let tokens = tokenize(script::strip_comment(line)).map_err(error)?;
+        match tokens.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            [] => (),
+            ["host", name] => {
+                host.get_or_insert_with(|| name.to_string());
+                current = Some(name.to_string());
+            }
+            ["host", ..] => return Err(error("Expected 'host <name>'".to_string())),
+            ["alias", name, device] => {
+                aliases.insert(name.to_string(), device.to_string());
+            }
+            ["alias", ..] => return Err(error("Expected 'alias <name> <device>'".to_string())),
+            ["protect", sink, ref options @ ..] => {
+                let host_name = current
+                    .clone()
+                    .ok_or_else(|| error("Protection before the first 'host' line".to_string()))?;
+                let (sink_name, sink_channel) = endpoint(sink).map_err(error)?;
+
+                let mut settings = Protection::default();
+                for option in options {
+                    let (name, value) = option
+                        .split_once('=')
+                        .ok_or_else(|| error(format!("Unknown option '{}'", option)))?;
+                    let number = value
+                        .strip_suffix("dB")
+                        .or_else(|| value.strip_suffix("ms"))
+                        .unwrap_or(value)
+                        .parse()
+                        .map_err(|_| error(format!("Invalid {} '{}'", name, value)))?;
+                    match name {
+                        "ceiling" => settings.ceiling_db = Some(number),
+                        "trip" => settings.trip_db = Some(number),
+                        "trip_time" => settings.trip_ms = number,
+                        _ => return Err(error(format!("Unknown option '{}'", option))),
+                    }
+                }
+                protection.push((
+                    n,
+                    SinkProtection {
+                        host_name,
+                        sink_name,
+                        sink_channel,
+                        protection: settings,
+                    },
+                ));
+            }
+            ["virtual", name, device, peer] => {
+                let host_name = current.clone().ok_or_else(|| {
+                    error("Virtual device before the first 'host' line".to_string())
+                })?;
+                virtual_devices.push((
+                    n,
+                    VirtualDevice {
+                        name: name.to_string(),
+                        host_name,
+                        device: device.to_string(),
+                        peer: peer.to_string(),
+                    },
+                ));
+            }
+            ["virtual", ..] => {
+                return Err(error(
+                    "Expected 'virtual <name> <device> <peer>'".to_string(),
+                ))
+            }
+            [source, "->", sink, ref options @ ..] => {
+                let host_name = current
+                    .clone()
+                    .ok_or_else(|| error("Route before the first 'host' line".to_string()))?;
+                let (source_name, source_channel) = endpoint(source).map_err(error)?;
+                let (sink_name, sink_channel) = endpoint(sink).map_err(error)?;
+
+                let mut metadata = ConnectionMetadata::new(
+                    host_name,
+                    source_name,
+                    sink_name,
+                    source_channel,
+                    sink_channel,
+                );
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("gain", value)) => {
+                            metadata.gain_db = value
+                                .parse()
+                                .map_err(|_| error(format!("Invalid gain '{}'", value)))?;
+                        }
+                        Some(("delay", value)) => {
+                            metadata.delay_ms =
+                                value
+                                    .strip_suffix("ms")
+                                    .unwrap_or(value)
+                                    .parse()
+                                    .map_err(|_| error(format!("Invalid delay '{}'", value)))?;
+                        }
+                        Some(("filter", value)) => {
+                            metadata.filters.push(filter(value).map_err(error)?);
+                        }
+                        Some(("gate", value)) => {
+                            metadata.gate = Some(gate(value).map_err(error)?);
+                        }
+                        Some(("insert", value)) => {
+                            metadata.processors.push(processor(value).map_err(error)?);
+                        }
+                        Some(("pan", value)) => {
+                            metadata.mode = Mode::Pan;
+                            metadata.pan = value
+                                .parse()
+                                .map_err(|_| error(format!("Invalid pan '{}'", value)))?;
+                        }
+                        Some(("law", value)) => {
+                            metadata.pan_law = value
+                                .parse()
+                                .map_err(|e: anyhow::Error| error(e.to_string()))?;
+                        }
+                        Some(("duck", value)) => {
+                            metadata.sidechain = Some(sidechain(value).map_err(error)?);
+                        }
+                        None if *option == "sum" => metadata.mode = Mode::Sum,
+                        None if *option == "link" => metadata.mode = Mode::Link,
+                        None if *option == "muted" => metadata.muted = true,
+                        _ => return Err(error(format!("Unknown option '{}'", option))),
+                    }
+                }
+                routes.push((n, metadata));
+            }
+            _ => {
+                return Err(error(
+                    "Expected 'host <name>' or '<source>:<channel> -> <sink>:<channel>'"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(Routes {
+        host: host.ok_or(Error {
+            line: s.lines().count().max(1),
+            message: "Missing 'host' line".to_string(),
+        })?,
+        aliases,
+        routes,
+        protection,
+        virtual_devices,
+    })
+}
+
+/// Parse a route list, generating an id for each route.
+pub fn parse(s: &str) -> Result<Config, Error> {
+    let routes = read(s)?;
+    Ok(Config {
+        host: routes.host,
+        aliases: routes.aliases,
+        connections: routes
+            .routes
+            .into_iter()
+            .map(|(_, metadata)| (Uuid::new_v4(), metadata))
+            .collect(),
+        protection: routes
+            .protection
+            .into_iter()
+            .map(|(_, sink)| sink)
+            .collect(),
+        virtual_devices: routes.virtual_devices.into_iter().map(|(_, v)| v).collect(),
+    })
+}
+
+/// Write a configuration as a route list, sorted so that the output only depends on the
+/// routes and not on their ids.
+pub fn to_string(config: &Config) -> String {
+    let mut hosts: BTreeMap<&str, Vec<&ConnectionMetadata>> = BTreeMap::new();
+    hosts.entry(&config.host).or_default();
+    for metadata in config.connections.values() {
+        hosts.entry(&metadata.host_name).or_default().push(metadata);
+    }
+    for sink in &config.protection {
+        hosts.entry(&sink.host_name).or_default();
+    }
+    for v in &config.virtual_devices {
+        hosts.entry(&v.host_name).or_default();
+    }
+
+    // the patchbay host goes first, as the first host line selects it
+    let mut hosts: Vec<_> = hosts.into_iter().collect();
+    hosts.sort_by_key(|(host, _)| *host != config.host);
+
+    let mut s = String::new();
+    for (name, device) in &config.aliases {
+        let _ = writeln!(s, "alias {} {}", quote(name), quote(device));
+    }
+
+    for (i, (host, mut routes)) in hosts.into_iter().enumerate() {
+        // only the first host line is needed without routes, as it selects the default host
+        let mut protection: Vec<_> = config
+            .protection
+            .iter()
+            .filter(|sink| sink.host_name == host)
+            .collect();
+        let mut virtual_devices: Vec<_> = config
+            .virtual_devices
+            .iter()
+            .filter(|v| v.host_name == host)
+            .collect();
+        if i > 0 && routes.is_empty() && protection.is_empty() && virtual_devices.is_empty() {
+            continue;
+        }
+        if !s.is_empty() {
+            s.push('\n');
+        }
+        let _ = writeln!(s, "host {}", quote(host));
+
+        // virtual devices come before the routes using them
+        virtual_devices.sort_by(|a, b| a.name.cmp(&b.name));
+        for v in virtual_devices {
+            let _ = writeln!(
+                s,
+                "virtual {} {} {}",
+                quote(&v.name),
+                quote(&v.device),
+                quote(&v.peer)
+            );
+        }
+
+        routes.sort_by(|a, b| {
+            (
+                &a.source_name,
+                a.source_channel,
+                &a.sink_name,
+                a.sink_channel,
+            )
+                .cmp(&(
+                    &b.source_name,
+                    b.source_channel,
+                    &b.sink_name,
+                    b.sink_channel,
+                ))
+        });
+        for m in routes {
+            let _ = write!(
+                s,
+                "{}:{} -> {}:{}",
+                quote(&m.source_name),
+                m.source_channel,
+                quote(&m.sink_name),
+                m.sink_channel
+            );
+            match m.mode {
+                Mode::Single => (),
+                Mode::Pan => {
+                    let _ = write!(s, " pan={}", m.pan);
+                    if m.pan_law != PanLaw::default() {
+                        let _ = write!(s, " law={}", m.pan_law);
+                    }
+                }
+                Mode::Sum => s.push_str(" sum"),
+                Mode::Link => s.push_str(" link"),
+            }
+            if m.gain_db != 0.0 {
+                let _ = write!(s, " gain={}", m.gain_db);
+            }
+            if m.delay_ms != 0.0 {
+                let _ = write!(s, " delay={}ms", m.delay_ms);
+            }
+            for f in &m.filters {
+                let _ = write!(s, " filter={}:{}", f.kind, f.frequency);
+                if f.q != DEFAULT_Q || f.gain_db != 0.0 {
+                    let _ = write!(s, ":{}", f.q);
+                }
+                if f.gain_db != 0.0 {
+                    let _ = write!(s, ":{}", f.gain_db);
+                }
+            }
+            if let Some(gate) = &m.gate {
+                let _ = write!(s, " gate={}", gate.threshold_db);
+                let default = Gate::default();
+                for (name, value, default) in [
+                    ("hysteresis", gate.hysteresis_db, default.hysteresis_db),
+                    ("attack", gate.attack_ms, default.attack_ms),
+                    ("hold", gate.hold_ms, default.hold_ms),
+                    ("release", gate.release_ms, default.release_ms),
+                ] {
+                    if value != default {
+                        let _ = write!(s, ",{}={}", name, value);
+                    }
+                }
+            }
+            for p in &m.processors {
+                let _ = write!(s, " insert={}", p.name);
+                for (i, (name, value)) in p.params.iter().enumerate() {
+                    let separator = if i == 0 { ':' } else { ',' };
+                    let _ = match value {
+                        Value::String(value) => write!(s, "{}{}={}", separator, name, value),
+                        value => write!(s, "{}{}={}", separator, name, value),
+                    };
+                }
+            }
+            if let Some(sidechain) = &m.sidechain {
+                let _ = write!(
+                    s,
+                    " duck={}:{}",
+                    quote(&sidechain.source_name),
+                    sidechain.source_channel
+                );
+                let (d, default) = (&sidechain.ducking, Ducking::default());
+                for (name, value, default) in [
+                    ("threshold", d.threshold_db, default.threshold_db),
+                    ("depth", d.depth_db, default.depth_db),
+                    ("attack", d.attack_ms, default.attack_ms),
+                    ("release", d.release_ms, default.release_ms),
+                ] {
+                    if value != default {
+                        let _ = write!(s, ",{}={}", name, value);
+                    }
+                }
+            }
+            if m.muted {
+                s.push_str(" muted");
+            }
+            s.push('\n');
+        }
+
+        protection
+            .sort_by(|a, b| (&a.sink_name, a.sink_channel).cmp(&(&b.sink_name, b.sink_channel)));
+        for sink in protection {
+            let _ = write!(
+                s,
+                "protect {}:{}",
+                quote(&sink.sink_name),
+                sink.sink_channel
+            );
+            let p = &sink.protection;
+            if let Some(ceiling_db) = p.ceiling_db {
+                let _ = write!(s, " ceiling={}", ceiling_db);
+            }
+            if let Some(trip_db) = p.trip_db {
+                let _ = write!(s, " trip={}", trip_db);
+            }
+            if p.trip_ms != Protection::default().trip_ms {
+                let _ = write!(s, " trip_time={}", p.trip_ms);
+            }
+            s.push('\n');
+        }
+    }
+    s
+}
+
+/// Parse a filter written as `<kind>:<frequency>[:<q>[:<gain>]]`.
+fn filter(value: &str) -> Result<Filter, String> {
+    let mut fields = value.split(':');
+    let kind = fields.next().unwrap_or_default();
+    let mut filter = Filter::new(kind.parse().map_err(|e: anyhow::Error| e.to_string())?, 0.0);
+    let mut number = |name: &str| -> Result<Option<f32>, String> {
+        fields
+            .next()
+            .map(|field| {
+                field
+                    .parse()
+                    .map_err(|_| format!("Invalid filter {} '{}'", name, field))
+            })
+            .transpose()
+    };
+    filter.frequency = number("frequency")?
+        .ok_or_else(|| format!("Expected '<kind>:<frequency>', found '{}'", value))?;
+    if let Some(q) = number("Q")? {
+        filter.q = q;
+    }
+    if let Some(gain_db) = number("gain")? {
+        filter.gain_db = gain_db;
+    }
+    if fields.next().is_some() {
+        return Err(format!("Too many filter settings in '{}'", value));
+    }
+    Ok(filter)
+}
+
+/// Parse a processor written as `<name>[:<param>=<value>,...]`.
+fn processor(value: &str) -> Result<ProcessorConfig, String> {
+    let (name, params) = value.split_once(':').unwrap_or((value, ""));
+    let mut processor = ProcessorConfig::new(name);
+    for param in params.split(',').filter(|param| !param.is_empty()) {
+        let (name, value) = param
+            .split_once('=')
+            .ok_or_else(|| format!("Expected '<param>=<value>', found '{}'", param))?;
+        processor = processor.with_param(name, value);
+    }
+    Ok(processor)
+}
+
+/// Parse a noise gate written as `<threshold>[,<param>=<value>,...]`.
+fn gate(value: &str) -> Result<Gate, String> {
+    let mut fields = value.split(',');
+    let threshold = fields.next().unwrap_or_default();
+    let mut gate = Gate {
+        threshold_db: number("threshold", threshold)?,
+        ..Gate::default()
+    };
+    for param in fields {
+        let (name, value) = param
+            .split_once('=')
+            .ok_or_else(|| format!("Expected '<param>=<value>', found '{}'", param))?;
+        let value = number(name, value)?;
+        match name {
+            "hysteresis" => gate.hysteresis_db = value,
+            "attack" => gate.attack_ms = value,
+            "hold" => gate.hold_ms = value,
+            "release" => gate.release_ms = value,
+            _ => return Err(format!("Unknown gate parameter '{}'", name)),
+        }
+    }
+    Ok(gate)
+}
+
+/// Parse a number given in dB or milliseconds, with or without the unit.
+fn number(name: &str, value: &str) -> Result<f32, String> {
+    value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("ms"))
+        .unwrap_or(value)
+        .parse()
+        .map_err(|_| format!("Invalid {} '{}'", name, value))
+}
+
+/// Parse a sidechain written as `<source>:<channel>[,<param>=<value>,...]`. Device names
+/// may contain commas, but parameters follow the channel, after the last colon.
+fn sidechain(value: &str) -> Result<Sidechain, String> {
+    let (name, rest) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected '<source>:<channel>', found '{}'", value))?;
+    let (channel, params) = rest.split_once(',').unwrap_or((rest, ""));
+    let (source_name, source_channel) = endpoint(&format!("{}:{}", name, channel))?;
+
+    let mut ducking = Ducking::default();
+    for param in params.split(',').filter(|param| !param.is_empty()) {
+        let (name, value) = param
+            .split_once('=')
+            .ok_or_else(|| format!("Expected '<param>=<value>', found '{}'", param))?;
+        let value = number(name, value)?;
+        match name {
+            "threshold" => ducking.threshold_db = value,
+            "depth" => ducking.depth_db = value,
+            "attack" => ducking.attack_ms = value,
+            "release" => ducking.release_ms = value,
+            _ => return Err(format!("Unknown ducking parameter '{}'", name)),
+        }
+    }
+    Ok(Sidechain {
+        source_name,
+        source_channel,
+        ducking,
+    })
+}
+
+fn endpoint(token: &str) -> Result<(String, u16), String> {
+    let (name, channel) = token
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected '<device>:<channel>', found '{}'", token))?;
+    let channel = channel
+        .parse()
+        .map_err(|_| format!("Invalid channel '{}'", channel))?;
+    Ok((name.to_string(), channel))
+}
+
+/// Split a line at whitespace outside quotes, removing the quotes.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_token = true;
+            }
+            None if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("Unterminated quote".to_string());
+    }
+    if in_token {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+fn quote(name: &str) -> String {
+    let plain = !name.is_empty()
+        && !name.starts_with('#')
+        && name != "->"
+        && !name.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == ':');
+
+    if plain {
+        name.to_string()
+    } else if name.contains('"') {
+        format!("'{}'", name)
+    } else {
+        format!("\"{}\"", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::FilterKind;
+
+    fn metadata(host: &str, source: &str, sink: &str, gain_db: f32) -> ConnectionMetadata {
+        ConnectionMetadata {
+            gain_db,
+            ..ConnectionMetadata::new(host.to_string(), source.to_string(), sink.to_string(), 0, 1)
+        }
+    }
+
+    #[test]
+    fn parse_routes() {
+        let config = parse(
+            r#"
+            # studio
+            host CoreAudio
+            "Mic Pre":0 -> "Headphones":1 gain=-3  # talkback
+            'Mic "A"':0 -> Headphones:1 muted delay=2.5ms filter=hp:80 filter=peaking:1000:2:-3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.host, "CoreAudio");
+        let mut connections: Vec<_> = config.connections.into_values().collect();
+        connections.sort_by(|a, b| a.source_name.cmp(&b.source_name));
+
+        let mut muted = metadata("CoreAudio", "Mic \"A\"", "Headphones", 0.0);
+        muted.muted = true;
+        muted.delay_ms = 2.5;
+        muted.filters = vec![
+            Filter::new(FilterKind::HighPass, 80.0),
+            Filter {
+                q: 2.0,
+                gain_db: -3.0,
+                ..Filter::new(FilterKind::Peaking, 1000.0)
+            },
+        ];
+        assert_eq!(
+            connections,
+            vec![muted, metadata("CoreAudio", "Mic Pre", "Headphones", -3.0)]
+        );
+    }
+
+    #[test]
+    fn sidechains() {
+        let loopback = sidechain("hw:CARD=Loopback,DEV=0:1,depth=6dB,attack=0").unwrap();
+        assert_eq!(loopback.source_name, "hw:CARD=Loopback,DEV=0");
+        assert_eq!(loopback.source_channel, 1);
+        assert_eq!(
+            loopback.ducking,
+            Ducking {
+                depth_db: 6.0,
+                attack_ms: 0.0,
+                ..Ducking::default()
+            }
+        );
+        assert_eq!(sidechain("a:0").unwrap().ducking, Ducking::default());
+        assert!(sidechain("a:0,depth").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let line = |s: &str| read(s).err().map(|e| e.line);
+        assert_eq!(line("a:0 -> b:1"), Some(1));
+        assert_eq!(line("host a\n\na:x -> b:1"), Some(3));
+        assert_eq!(line("host a\na:0 -> b"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:1 loud"), Some(2));
+        assert_eq!(line("host \"a"), Some(1));
+        assert_eq!(line("host a\nalias mic"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:1 filter=hp"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:1 filter=hp:80:1:0:0"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:1 insert=gain:-3"), Some(2));
+        assert_eq!(line("host a\nprotect b:1 limit"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:0 pan=0 law=loud"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:0 duck=c"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:0 gate=quiet"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:0 gate=-40,range=20"), Some(2));
+        assert_eq!(line("host a\na:0 -> b:0 duck=c:0,knee=3"), Some(2));
+        assert_eq!(line("protect b:1"), Some(1));
+        assert_eq!(line("host a\nvirtual app hw:CARD=Loopback,DEV=1"), Some(2));
+        assert_eq!(line("virtual app b c"), Some(1));
+        assert_eq!(line(""), Some(1));
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            host: "CoreAudio".to_string(),
+            connections: BTreeMap::from([
+                (
+                    Uuid::new_v4(),
+                    metadata("CoreAudio", "Mic Pre", "phones", -3.5),
+                ),
+                (
+                    Uuid::new_v4(),
+                    ConnectionMetadata {
+                        mode: Mode::Pan,
+                        pan: -0.5,
+                        pan_law: PanLaw::Linear,
+                        ..metadata("CoreAudio", "a:b", "it's", 0.0)
+                    },
+                ),
+                (
+                    Uuid::new_v4(),
+                    ConnectionMetadata {
+                        mode: Mode::Sum,
+                        sidechain: Some(Sidechain {
+                            source_name: "Mic Pre".to_string(),
+                            source_channel: 0,
+                            ducking: Ducking {
+                                depth_db: 20.0,
+                                release_ms: 800.0,
+                                ..Ducking::default()
+                            },
+                        }),
+                        ..metadata("CoreAudio", "b", "mono", 0.0)
+                    },
+                ),
+                (
+                    Uuid::new_v4(),
+                    ConnectionMetadata {
+                        mode: Mode::Link,
+                        ..metadata("JACK", "Firefox", "system", 0.0)
+                    },
+                ),
+                (
+                    Uuid::new_v4(),
+                    ConnectionMetadata {
+                        filters: vec![
+                            Filter::new(FilterKind::LowPass, 120.0),
+                            Filter {
+                                gain_db: 2.5,
+                                ..Filter::new(FilterKind::HighShelf, 8000.0)
+                            },
+                        ],
+                        processors: vec![
+                            ProcessorConfig::new("limiter")
+                                .with_param("ceiling_db", "-1")
+                                .with_param("release_ms", "20"),
+                            ProcessorConfig::new("tape").with_param("model", "reel"),
+                        ],
+                        gate: Some(Gate {
+                            threshold_db: -45.0,
+                            hold_ms: 200.0,
+                            ..Gate::default()
+                        }),
+                        ..metadata("JACK", "system", "system", 0.0)
+                    },
+                ),
+            ]),
+            aliases: BTreeMap::from([("mic".to_string(), "Mic Pre".to_string())]),
+            protection: vec![SinkProtection {
+                host_name: "CoreAudio".to_string(),
+                sink_name: "phones".to_string(),
+                sink_channel: 1,
+                protection: Protection {
+                    ceiling_db: Some(-1.0),
+                    trip_db: Some(-0.5),
+                    trip_ms: 200.0,
+                },
+            }],
+            virtual_devices: vec![VirtualDevice {
+                name: "app".to_string(),
+                host_name: "ALSA".to_string(),
+                device: "hw:CARD=Loopback,DEV=1".to_string(),
+                peer: "hw:CARD=Loopback,DEV=0".to_string(),
+            }],
+        };
+
+        let s = to_string(&config);
+        assert_eq!(
+            s,
+            "alias mic \"Mic Pre\"\n\
+             \n\
+             host CoreAudio\n\
+             \"Mic Pre\":0 -> phones:1 gain=-3.5\n\
+             \"a:b\":0 -> \"it's\":1 pan=-0.5 law=linear\n\
+             b:0 -> mono:1 sum duck=\"Mic Pre\":0,depth=20,release=800\n\
+             protect phones:1 ceiling=-1 trip=-0.5 trip_time=200\n\
+             \n\
+             host ALSA\n\
+             virtual app \"hw:CARD=Loopback,DEV=1\" \"hw:CARD=Loopback,DEV=0\"\n\
+             \n\
+             host JACK\n\
+             Firefox:0 -> system:1 link\n\
+             system:0 -> system:1 filter=lowpass:120 filter=highshelf:8000:0.70710677:2.5 \
+             gate=-45,hold=200 insert=limiter:ceiling_db=-1,release_ms=20 insert=tape:model=reel\n"
+        );
+
+        let parsed = parse(&s).unwrap();
+        assert_eq!(parsed.host, config.host);
+        assert_eq!(parsed.aliases, config.aliases);
+        assert_eq!(parsed.protection, config.protection);
+        assert_eq!(parsed.virtual_devices, config.virtual_devices);
+        let values = |c: &Config| {
+            let mut v: Vec<_> = c.connections.values().cloned().collect();
+            v.sort_by(|a, b| a.source_name.cmp(&b.source_name));
+            v
+        };
+        assert_eq!(values(&parsed), values(&config));
+    }
+}
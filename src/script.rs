@@ -0,0 +1,222 @@
+use crate::cli;
+
+use anyhow::{anyhow, Result};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Script directives handled before lines reach the command parser:
+///
+/// * `# comment` - ignored up to the end of the line
+/// * `set -e` / `set +e` - enable/disable aborting on the first failing command
+/// * `let name = value` - define a variable, referenced as `$name` or `${name}`, with
+///   `$$` standing for a literal `$`
+pub struct Script {
+    variables: HashMap<String, String>,
+    abort_on_error: bool,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script {
+            variables: HashMap::new(),
+            abort_on_error: false,
+        }
+    }
+
+    pub fn abort_on_error(&self) -> bool {
+        self.abort_on_error
+    }
+
+    /// Process a line of the script, returning the tokens of the command to execute (if any).
+    pub fn line(&mut self, line: &str) -> Result<Option<Vec<String>>> {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        let tokens = cli::split_args(line)
+            .into_iter()
+            .filter(|t| !t.is_empty())
+            .map(|t| self.substitute(t))
+            .collect::<Result<Vec<_>>>()?;
+
+        match tokens.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["set", "-e"] => {
+                self.abort_on_error = true;
+                Ok(None)
+            }
+            ["set", "+e"] => {
+                self.abort_on_error = false;
+                Ok(None)
+            }
+            ["let", name, "=", value] => {
+                if !is_identifier(name) {
+                    return Err(anyhow!("Invalid variable name '{}'", name));
+                }
+                self.variables.insert(name.to_string(), value.to_string());
+                Ok(None)
+            }
+            ["let", ..] => Err(anyhow!("Expected 'let <name> = <value>'")),
+            _ => Ok(Some(tokens)),
+        }
+    }
+
+    fn substitute(&self, token: &str) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = token;
+
+        while let Some(i) = rest.find('$') {
+            result.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            if let Some(remainder) = rest.strip_prefix('$') {
+                result.push('$');
+                rest = remainder;
+                continue;
+            }
+
+            let (name, remainder) = if let Some(braced) = rest.strip_prefix('{') {
+                let end = braced
+                    .find('}')
+                    .ok_or(anyhow!("Unterminated variable in '{}'", token))?;
+                (&braced[..end], &braced[end + 1..])
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            };
+
+            let value = self
+                .variables
+                .get(name)
+                .ok_or(anyhow!("Undefined variable '{}'", name))?;
+            result.push_str(value);
+            rest = remainder;
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+}
+
+thread_local! {
+    static RUNNING: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Marks a script as running until dropped. Scripts that are already running are
+/// refused, as sourcing them again would never end.
+pub struct Running(PathBuf);
+
+impl Running {
+    pub fn enter(path: &Path) -> Result<Self> {
+        let path = path.canonicalize()?;
+        RUNNING.with(|running| {
+            let mut running = running.borrow_mut();
+            if running.contains(&path) {
+                return Err(anyhow!("Script {} sources itself", path.display()));
+            }
+            running.push(path.clone());
+            Ok(Running(path))
+        })
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.borrow_mut().retain(|path| *path != self.0));
+    }
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '"' || c == '\'' {
+            quoted = !quoted;
+        } else if c == '#' && !quoted && previous.is_whitespace() {
+            return &line[..i];
+        }
+        previous = c;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments() {
+        let mut s = Script::new();
+        assert_eq!(s.line("# a comment").unwrap(), None);
+        assert_eq!(s.line("   ").unwrap(), None);
+        assert_eq!(
+            s.line("save \"a # b.json\" # comment").unwrap(),
+            Some(vec!["save".to_string(), "a # b.json".to_string()])
+        );
+    }
+
+    #[test]
+    fn abort_on_error() {
+        let mut s = Script::new();
+        assert!(!s.abort_on_error());
+        s.line("set -e").unwrap();
+        assert!(s.abort_on_error());
+        s.line("set +e").unwrap();
+        assert!(!s.abort_on_error());
+    }
+
+    #[test]
+    fn variables() {
+        let mut s = Script::new();
+        assert_eq!(s.line("let mic = \"Mic Pre\"").unwrap(), None);
+        assert_eq!(s.line("let out = phones").unwrap(), None);
+        assert_eq!(
+            s.line("connect $mic 0 ${out}_2 1").unwrap(),
+            Some(vec![
+                "connect".to_string(),
+                "Mic Pre".to_string(),
+                "0".to_string(),
+                "phones_2".to_string(),
+                "1".to_string(),
+            ])
+        );
+        assert!(s.line("connect $nope 0 phones 1").is_err());
+        assert_eq!(
+            s.line("connect /^a$$/ 0 $$$out 1").unwrap(),
+            Some(vec![
+                "connect".to_string(),
+                "/^a$/".to_string(),
+                "0".to_string(),
+                "$phones".to_string(),
+                "1".to_string(),
+            ])
+        );
+        assert!(s.line("let bad name = 1").is_err());
+    }
+
+    #[test]
+    fn recursion() {
+        let path = std::env::temp_dir().join(format!("patchbay-{}.pb", std::process::id()));
+        std::fs::write(&path, "source self").unwrap();
+
+        let running = Running::enter(&path).unwrap();
+        assert!(Running::enter(&path).is_err());
+        drop(running);
+        assert!(Running::enter(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,11 +1,36 @@
+#[cfg(feature = "jack")]
+use crate::jack_client;
+
 use anyhow::{anyhow, Result};
 use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{self, HostUnavailable};
+use cpal::{self, SupportedBufferSize, SupportedStreamConfigRange};
+use regex::Regex;
+use serde::Serialize;
+
+/// Host with fixed devices and no streams, for testing the patchbay without sound cards.
+#[cfg(test)]
+pub const TEST_HOST: &str = "Test";
 
-pub fn hosts() -> impl Iterator<Item = Result<cpal::Host, HostUnavailable>> {
-    cpal::available_hosts()
+/// Names of the hosts available on this system.
+pub fn host_names() -> Vec<String> {
+    #[allow(unused_mut)]
+    let mut names: Vec<String> = cpal::available_hosts()
         .into_iter()
-        .map(|id| cpal::host_from_id(id))
+        .filter_map(|id| cpal::host_from_id(id).ok())
+        .map(|host| host.id().name().to_string())
+        .collect();
+    #[cfg(feature = "jack")]
+    names.push(jack_client::HOST_NAME.to_string());
+    names
+}
+
+/// Names of the hosts supported on this platform, including unavailable ones.
+fn all_host_names() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut names: Vec<&str> = cpal::ALL_HOSTS.iter().map(|id| id.name()).collect();
+    #[cfg(feature = "jack")]
+    names.push(jack_client::HOST_NAME);
+    names
 }
 
 pub fn default_host() -> cpal::Host {
@@ -21,6 +46,178 @@ pub fn find_host(name: &str) -> Result<cpal::Host> {
     )?)
 }
 
+/// Description of a host and its devices, as printed by `list`.
+#[derive(Serialize)]
+pub struct HostInfo {
+    pub name: String,
+    pub devices: Vec<DeviceInfo>,
+    /// Reason the host is unavailable, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    /// Index used to select the device with `@N`.
+    pub index: usize,
+    pub name: String,
+    pub default_input: bool,
+    pub default_output: bool,
+    /// Channels of the default input config, 0 for devices without inputs.
+    pub input_channels: u16,
+    /// Channels of the default output config, 0 for devices without outputs.
+    pub output_channels: u16,
+    pub input_configs: Vec<ConfigRange>,
+    pub output_configs: Vec<ConfigRange>,
+}
+
+/// Range of stream configurations supported by a device.
+#[derive(Serialize)]
+pub struct ConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+    /// Minimum and maximum buffer size in frames, if known.
+    pub buffer_size: Option<(u32, u32)>,
+}
+
+impl From<SupportedStreamConfigRange> for ConfigRange {
+    fn from(range: SupportedStreamConfigRange) -> Self {
+        ConfigRange {
+            channels: range.channels(),
+            min_sample_rate: range.min_sample_rate().0,
+            max_sample_rate: range.max_sample_rate().0,
+            sample_format: range.sample_format().to_string(),
+            buffer_size: match range.buffer_size() {
+                SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+                SupportedBufferSize::Unknown => None,
+            },
+        }
+    }
+}
+
+/// Describe every host supported on this platform, including unavailable ones.
+pub fn describe_hosts() -> Vec<HostInfo> {
+    let available = cpal::available_hosts();
+
+    #[allow(unused_mut)]
+    let mut hosts: Vec<HostInfo> = cpal::ALL_HOSTS
+        .iter()
+        .map(|id| {
+            let result = if available.contains(id) {
+                cpal::host_from_id(*id)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|host| describe_devices(&host))
+            } else {
+                Err(anyhow!("Host is not available on this system"))
+            };
+            host_info(id.name(), result)
+        })
+        .collect();
+    #[cfg(feature = "jack")]
+    hosts.push(host_info(jack_client::HOST_NAME, describe_jack_clients()));
+    hosts
+}
+
+fn host_info(name: &str, result: Result<Vec<DeviceInfo>>) -> HostInfo {
+    let (devices, error) = match result {
+        Ok(devices) => (devices, None),
+        Err(e) => (Vec::new(), Some(e.to_string())),
+    };
+    HostInfo {
+        name: name.to_string(),
+        devices,
+        error,
+    }
+}
+
+/// JACK clients as devices, with a channel for every audio port. Ports have no
+/// configurations of their own, they run at the rate of the JACK server.
+#[cfg(feature = "jack")]
+fn describe_jack_clients() -> Result<Vec<DeviceInfo>> {
+    let sources = jack_client::sources()?;
+    let sinks = jack_client::sinks()?;
+    let channels = |devices: &[(String, u16)], name: &str| {
+        devices
+            .iter()
+            .find(|(n, _)| n == name)
+            .map_or(0, |(_, channels)| *channels)
+    };
+
+    Ok(jack_devices(&sources, &sinks)
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| DeviceInfo {
+            index,
+            default_input: false,
+            default_output: false,
+            input_channels: channels(&sources, &name),
+            output_channels: channels(&sinks, &name),
+            input_configs: Vec::new(),
+            output_configs: Vec::new(),
+            name,
+        })
+        .collect())
+}
+
+/// Every JACK client, in the order they are listed as sources and then as sinks.
+#[cfg(feature = "jack")]
+fn jack_devices(sources: &[(String, u16)], sinks: &[(String, u16)]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for (name, _) in sources.iter().chain(sinks) {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+fn describe_devices(host: &cpal::Host) -> Result<Vec<DeviceInfo>> {
+    let default_input = host.default_input_device().and_then(|d| d.name().ok());
+    let default_output = host.default_output_device().and_then(|d| d.name().ok());
+
+    Ok(host
+        .devices()?
+        .enumerate()
+        .map(|(index, device)| {
+            let name = device.name().unwrap_or_default();
+            DeviceInfo {
+                index,
+                default_input: default_input.as_ref() == Some(&name),
+                default_output: default_output.as_ref() == Some(&name),
+                input_channels: device.default_input_config().map_or(0, |c| c.channels()),
+                output_channels: device.default_output_config().map_or(0, |c| c.channels()),
+                input_configs: device
+                    .supported_input_configs()
+                    .map(|configs| configs.map(ConfigRange::from).collect())
+                    .unwrap_or_default(),
+                output_configs: device
+                    .supported_output_configs()
+                    .map(|configs| configs.map(ConfigRange::from).collect())
+                    .unwrap_or_default(),
+                name,
+            }
+        })
+        .collect())
+}
+
+/// Split a `host:device` qualified device name into its host and device name. The prefix
+/// is only taken as a host if it names one, as device names may contain colons themselves
+/// (e.g. `hw:0` on ALSA).
+pub fn split_qualified(name: &str) -> (Option<String>, String) {
+    split_host(name, &all_host_names())
+}
+
+fn split_host(name: &str, host_names: &[&str]) -> (Option<String>, String) {
+    match name.split_once(':') {
+        Some((host, device)) if host_names.contains(&host) => {
+            (Some(host.to_string()), device.to_string())
+        }
+        _ => (None, name.to_string()),
+    }
+}
+
 pub fn find_input_device(host_name: &str, device_name: &str) -> Result<cpal::Device> {
     find_host(host_name)?
         .input_devices()?
@@ -40,3 +237,252 @@ pub fn find_output_device(host_name: &str, device_name: &str) -> Result<cpal::De
         })
         .ok_or(anyhow!("Could not find output device '{}'", device_name))
 }
+
+/// Resolve a device selector to the name of an input device on a host, see [`Devices`].
+pub fn resolve_input_device(host_name: &str, selector: &str) -> Result<String> {
+    Ok(Devices::list(host_name)?.input(selector)?.0.clone())
+}
+
+/// Resolve a device selector to the name of an output device on a host, see [`Devices`].
+pub fn resolve_output_device(host_name: &str, selector: &str) -> Result<String> {
+    Ok(Devices::list(host_name)?.output(selector)?.0.clone())
+}
+
+/// Devices of a host, listed once so that several selectors can be resolved against them.
+///
+/// Devices are selected by:
+///
+/// * `@N` - index in the list of all devices on the host, as numbered by `list`
+/// * exact name
+/// * `/regex/` - the only device whose name matches the regular expression
+/// * substring - the only device whose name contains the selector, ignoring case
+pub struct Devices {
+    pub host_name: String,
+    pub all: Vec<String>,
+    pub inputs: Vec<(String, u16)>,
+    pub outputs: Vec<(String, u16)>,
+}
+
+impl Devices {
+    pub fn list(host_name: &str) -> Result<Self> {
+        #[cfg(test)]
+        if host_name == TEST_HOST {
+            return Ok(Devices {
+                host_name: host_name.to_string(),
+                all: vec!["mic".to_string(), "speakers".to_string()],
+                inputs: vec![("mic".to_string(), 2)],
+                outputs: vec![("speakers".to_string(), 2)],
+            });
+        }
+        #[cfg(feature = "jack")]
+        if host_name == jack_client::HOST_NAME {
+            let inputs = jack_client::sources()?;
+            let outputs = jack_client::sinks()?;
+            return Ok(Devices {
+                host_name: host_name.to_string(),
+                all: jack_devices(&inputs, &outputs),
+                inputs,
+                outputs,
+            });
+        }
+
+        Ok(Devices {
+            host_name: host_name.to_string(),
+            all: find_host(host_name)?
+                .devices()?
+                .filter_map(|device| device.name().ok())
+                .collect(),
+            inputs: input_devices(host_name)?,
+            outputs: output_devices(host_name)?,
+        })
+    }
+
+    /// Name and maximum channel count of the selected input device.
+    pub fn input(&self, selector: &str) -> Result<&(String, u16)> {
+        self.select(selector, &self.inputs, "input")
+    }
+
+    /// Name and maximum channel count of the selected output device.
+    pub fn output(&self, selector: &str) -> Result<&(String, u16)> {
+        self.select(selector, &self.outputs, "output")
+    }
+
+    /// Name and maximum channel count of the input device with exactly the given name.
+    pub fn input_named(&self, name: &str) -> Result<&(String, u16)> {
+        self.find(name, &self.inputs, "input")
+    }
+
+    /// Name and maximum channel count of the output device with exactly the given name.
+    pub fn output_named(&self, name: &str) -> Result<&(String, u16)> {
+        self.find(name, &self.outputs, "output")
+    }
+
+    fn find<'a>(
+        &self,
+        name: &str,
+        candidates: &'a [(String, u16)],
+        kind: &str,
+    ) -> Result<&'a (String, u16)> {
+        candidates.iter().find(|(n, _)| n == name).ok_or(anyhow!(
+            "Could not find {} device '{}' on host '{}'",
+            kind,
+            name,
+            self.host_name
+        ))
+    }
+
+    fn select<'a>(
+        &self,
+        selector: &str,
+        candidates: &'a [(String, u16)],
+        kind: &str,
+    ) -> Result<&'a (String, u16)> {
+        let matches: Vec<&(String, u16)> = if let Some(index) = selector.strip_prefix('@') {
+            let index: usize = index
+                .parse()
+                .map_err(|_| anyhow!("Invalid device index '{}'", selector))?;
+            let name = self.all.get(index).ok_or(anyhow!(
+                "No device with index {} on host '{}'",
+                index,
+                self.host_name
+            ))?;
+            candidates.iter().filter(|(n, _)| n == name).collect()
+        } else if let Some(device) = candidates.iter().find(|(n, _)| n == selector) {
+            vec![device]
+        } else if let Some(pattern) = selector.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            let regex = Regex::new(pattern)?;
+            candidates
+                .iter()
+                .filter(|(n, _)| regex.is_match(n))
+                .collect()
+        } else {
+            let lowercase = selector.to_lowercase();
+            candidates
+                .iter()
+                .filter(|(n, _)| n.to_lowercase().contains(&lowercase))
+                .collect()
+        };
+
+        match matches[..] {
+            [device] => Ok(device),
+            [] => Err(anyhow!(
+                "Could not find {} device '{}' on host '{}'",
+                kind,
+                selector,
+                self.host_name
+            )),
+            _ => Err(anyhow!(
+                "'{}' matches several {} devices on host '{}': {}",
+                selector,
+                kind,
+                self.host_name,
+                matches
+                    .iter()
+                    .map(|(n, _)| format!("'{}'", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+/// Names and maximum channel counts of the input devices on a host.
+pub fn input_devices(host_name: &str) -> Result<Vec<(String, u16)>> {
+    #[cfg(feature = "jack")]
+    if host_name == jack_client::HOST_NAME {
+        return jack_client::sources();
+    }
+
+    Ok(find_host(host_name)?
+        .input_devices()?
+        .filter_map(|device| {
+            let channels = device
+                .supported_input_configs()
+                .ok()?
+                .map(|c| c.channels())
+                .max()?;
+            Some((device.name().ok()?, channels))
+        })
+        .collect())
+}
+
+/// Names and maximum channel counts of the output devices on a host.
+pub fn output_devices(host_name: &str) -> Result<Vec<(String, u16)>> {
+    #[cfg(feature = "jack")]
+    if host_name == jack_client::HOST_NAME {
+        return jack_client::sinks();
+    }
+
+    Ok(find_host(host_name)?
+        .output_devices()?
+        .filter_map(|device| {
+            let channels = device
+                .supported_output_configs()
+                .ok()?
+                .map(|c| c.channels())
+                .max()?;
+            Some((device.name().ok()?, channels))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selectors() {
+        let devices = Devices {
+            host_name: "ALSA".to_string(),
+            all: [
+                "hw:CARD=PCH,DEV=0",
+                "hw:CARD=USB,DEV=0",
+                "Scarlett 2i2 USB",
+                "USB",
+            ]
+            .map(String::from)
+            .to_vec(),
+            inputs: ["hw:CARD=USB,DEV=0", "Scarlett 2i2 USB", "USB"]
+                .map(|n| (n.to_string(), 2))
+                .to_vec(),
+            outputs: Vec::new(),
+        };
+        let select = |selector: &str| devices.input(selector).ok().map(|(n, _)| n.as_str());
+
+        assert_eq!(select("@1"), Some("hw:CARD=USB,DEV=0"));
+        // not an input
+        assert_eq!(select("@0"), None);
+        assert_eq!(select("@9"), None);
+        // exact names win over substrings
+        assert_eq!(select("USB"), Some("USB"));
+        assert_eq!(select("scarlett"), Some("Scarlett 2i2 USB"));
+        assert_eq!(select("/^hw:.*USB/"), Some("hw:CARD=USB,DEV=0"));
+        // ambiguous
+        assert_eq!(select("usb"), None);
+        assert_eq!(select("/USB/"), None);
+        assert_eq!(select("/(/"), None);
+        assert_eq!(select("mic"), None);
+        assert!(devices.output("USB").is_err());
+
+        let find = |name: &str| devices.input_named(name).ok().map(|(n, _)| n.as_str());
+        assert_eq!(find("USB"), Some("USB"));
+        assert_eq!(find("scarlett"), None);
+        assert_eq!(find("hw:CARD=USB"), None);
+        assert_eq!(find("/^hw:.*USB/"), None);
+    }
+
+    #[test]
+    fn qualified_names() {
+        let hosts = ["ALSA", "JACK"];
+        assert_eq!(
+            split_host("JACK:system", &hosts),
+            (Some("JACK".to_string()), "system".to_string())
+        );
+        assert_eq!(split_host("phones", &hosts), (None, "phones".to_string()));
+        assert_eq!(split_host("hw:0", &hosts), (None, "hw:0".to_string()));
+        assert_eq!(
+            split_host("ALSA:hw:0", &hosts),
+            (Some("ALSA".to_string()), "hw:0".to_string())
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use crate::connection::{Connection, Mode};
+use crate::matrix::{Endpoint, Matrix};
+use crate::patchbay::Patchbay;
+use crate::system;
+
+use anyhow::{anyhow, Result};
+use crossterm::cursor::Show;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::ExecutableCommand;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Cell, List, ListItem, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+
+use std::io::{stdout, Stdout};
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REFRESH: Duration = Duration::from_millis(100);
+const GAIN_STEP_DB: f32 = 1.0;
+const METER_FLOOR_DB: f32 = -60.0;
+const METER_WIDTH: usize = 10;
+
+const HELP: &str = "arrows/hjkl: move  space: toggle  f: force connect  +/-: gain  0: unity  m: mute  u/U: undo/redo  s: start/stop  H: next host  r: rescan  q: quit";
+
+/// Run the full-screen terminal UI until the user quits.
+pub fn run(patchbay: &mut Patchbay) -> Result<()> {
+    enable_raw_mode()?;
+    stdout().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+
+    // a panic would otherwise leave the terminal in raw mode on the alternate screen,
+    // with the panic message lost on it
+    let default_hook = Arc::new(panic::take_hook());
+    let hook = Arc::clone(&default_hook);
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        hook(info);
+    }));
+
+    let result = App::new(patchbay).run(&mut terminal);
+
+    // always restore the terminal, even if the app failed
+    let _ = panic::take_hook();
+    panic::set_hook(Box::new(move |info| default_hook(info)));
+    disable_raw_mode()?;
+    terminal.backend_mut().execute(LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+fn restore() {
+    let _ = disable_raw_mode();
+    let _ = stdout().execute(LeaveAlternateScreen);
+    let _ = stdout().execute(Show);
+}
+
+struct App<'a> {
+    patchbay: &'a mut Patchbay,
+    /// Devices of the default host, followed by those of the other hosts in use.
+    hosts: Vec<system::Devices>,
+    row: usize,
+    column: usize,
+    status: String,
+    quit: bool,
+}
+
+impl<'a> App<'a> {
+    fn new(patchbay: &'a mut Patchbay) -> Self {
+        let mut app = App {
+            patchbay,
+            hosts: Vec::new(),
+            row: 0,
+            column: 0,
+            status: HELP.to_string(),
+            quit: false,
+        };
+        app.rescan();
+        app
+    }
+
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+        while !self.quit {
+            let matrix = self.matrix();
+            self.row = self.row.min(matrix.sources().len().saturating_sub(1));
+            self.column = self.column.min(matrix.sinks().len().saturating_sub(1));
+
+            terminal.draw(|frame| self.draw(frame, &matrix))?;
+
+            if event::poll(REFRESH)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.handle_key(key.code, &matrix);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rescan(&mut self) {
+        let mut names = vec![self.patchbay.host().to_owned()];
+        for (_, c) in self.patchbay.connections() {
+            if !names.iter().any(|name| name == c.host_name()) {
+                names.push(c.host_name().to_owned());
+            }
+        }
+
+        self.hosts.clear();
+        for name in names {
+            match system::Devices::list(&name) {
+                Ok(devices) => self.hosts.push(devices),
+                Err(e) => self.status = format!("{}: {}", name, e),
+            }
+        }
+    }
+
+    /// Select the next available host as the default one and scan its devices.
+    fn next_host(&mut self) -> Result<()> {
+        let names = system::host_names();
+        let current = names.iter().position(|name| name == self.patchbay.host());
+        let next = match current {
+            Some(i) => &names[(i + 1) % names.len()],
+            None => names.first().ok_or(anyhow!("No hosts available"))?,
+        };
+        self.patchbay.set_host(next)?;
+        self.status = format!("Default host {}", next);
+        self.rescan();
+        Ok(())
+    }
+
+    fn matrix(&self) -> Matrix {
+        let channels = |host: &str, devices: &[(String, u16)]| -> Vec<Endpoint> {
+            devices
+                .iter()
+                .flat_map(|(name, channels)| {
+                    (0..*channels).map(|c| (host.to_owned(), name.clone(), c))
+                })
+                .collect()
+        };
+        let sources = self
+            .hosts
+            .iter()
+            .flat_map(|d| channels(&d.host_name, &d.inputs));
+        let sinks = self
+            .hosts
+            .iter()
+            .flat_map(|d| channels(&d.host_name, &d.outputs));
+        self.patchbay.matrix().with_endpoints(sources, sinks)
+    }
+
+    fn handle_key(&mut self, code: KeyCode, matrix: &Matrix) {
+        let result = match code {
+            KeyCode::Char('q') | KeyCode::Esc => {
+                self.quit = true;
+                Ok(())
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.row = self.row.saturating_sub(1);
+                Ok(())
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.row += 1;
+                Ok(())
+            }
+            KeyCode::Left | KeyCode::Char('h') => {
+                self.column = self.column.saturating_sub(1);
+                Ok(())
+            }
+            KeyCode::Right | KeyCode::Char('l') => {
+                self.column += 1;
+                Ok(())
+            }
+            KeyCode::Char(' ') | KeyCode::Enter => self.toggle(matrix, false),
+            KeyCode::Char('f') => self.toggle(matrix, true),
+            KeyCode::Char('+') | KeyCode::Char('=') => self.adjust_gain(matrix, GAIN_STEP_DB),
+            KeyCode::Char('-') => self.adjust_gain(matrix, -GAIN_STEP_DB),
+            KeyCode::Char('0') => self.reset_gain(matrix),
+            KeyCode::Char('m') => self.toggle_mute(matrix),
+            KeyCode::Char('u') => self
+                .patchbay
+                .undo()
+                .map(|d| self.status = format!("Undid {}", d)),
+            KeyCode::Char('U') => self
+                .patchbay
+                .redo()
+                .map(|d| self.status = format!("Redid {}", d)),
+            KeyCode::Char('s') => self.toggle_running(),
+            KeyCode::Char('H') => self.next_host(),
+            KeyCode::Char('r') => {
+                self.rescan();
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+
+        if let Err(e) = result {
+            self.status = e.to_string();
+        }
+    }
+
+    /// Connect or disconnect the selected crosspoint, connecting even if that closes a
+    /// feedback loop when forced.
+    fn toggle(&mut self, matrix: &Matrix, force: bool) -> Result<()> {
+        let (Some(source), Some(sink)) = (
+            matrix.sources().get(self.row),
+            matrix.sinks().get(self.column),
+        ) else {
+            return Ok(());
+        };
+
+        match matrix.crosspoint(self.row, self.column) {
+            Some(crosspoint) => {
+                self.patchbay.remove_connection(&crosspoint.id)?;
+                self.status = format!("Removed connection {}", crosspoint.id);
+            }
+            None => {
+                if source.0 != sink.0 {
+                    return Err(anyhow!(
+                        "Cannot connect {} to {}, connections stay on one host",
+                        matrix.label(source),
+                        matrix.label(sink)
+                    ));
+                }
+                let connection = Connection::new(
+                    source.0.clone(),
+                    source.1.clone(),
+                    sink.1.clone(),
+                    source.2,
+                    sink.2,
+                    Mode::Single,
+                )?;
+                let id = self.patchbay.add_connection(connection, force)?;
+                self.status = format!("Created connection with id {}", id);
+            }
+        }
+        Ok(())
+    }
+
+    fn adjust_gain(&mut self, matrix: &Matrix, step_db: f32) -> Result<()> {
+        if let Some(crosspoint) = matrix.crosspoint(self.row, self.column) {
+            let gain_db = crosspoint.gain_db + step_db;
+            self.patchbay.set_gain(&crosspoint.id, gain_db)?;
+            self.status = format!("Gain {:+.1}dB", gain_db);
+        }
+        Ok(())
+    }
+
+    fn reset_gain(&mut self, matrix: &Matrix) -> Result<()> {
+        if let Some(crosspoint) = matrix.crosspoint(self.row, self.column) {
+            self.patchbay.set_gain(&crosspoint.id, 0.0)?;
+            self.status = "Gain +0.0dB".to_string();
+        }
+        Ok(())
+    }
+
+    fn toggle_mute(&mut self, matrix: &Matrix) -> Result<()> {
+        if let Some(crosspoint) = matrix.crosspoint(self.row, self.column) {
+            self.patchbay.set_muted(&crosspoint.id, !crosspoint.muted)?;
+            self.status = if crosspoint.muted { "Unmuted" } else { "Muted" }.to_string();
+        }
+        Ok(())
+    }
+
+    fn toggle_running(&mut self) -> Result<()> {
+        if self.patchbay.running() {
+            self.patchbay.halt()?;
+            self.status = "Stopped".to_string();
+        } else {
+            self.patchbay.run()?;
+            self.status = "Started".to_string();
+        }
+        Ok(())
+    }
+
+    fn draw(&self, frame: &mut Frame, matrix: &Matrix) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)])
+            .split(frame.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(25), Constraint::Percentage(75)])
+            .split(rows[0]);
+        let panels = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .split(columns[1]);
+
+        self.draw_devices(frame, columns[0]);
+        self.draw_matrix(frame, panels[0], matrix);
+        self.draw_connections(frame, panels[1]);
+        frame.render_widget(Paragraph::new(self.status.as_str()), rows[1]);
+    }
+
+    fn draw_devices(&self, frame: &mut Frame, area: Rect) {
+        // devices of other hosts are listed under their host
+        let items: Vec<ListItem> = self
+            .hosts
+            .iter()
+            .enumerate()
+            .flat_map(|(i, devices)| {
+                let header = (i > 0).then(|| format!("[{}]", devices.host_name));
+                header
+                    .into_iter()
+                    .chain(
+                        devices
+                            .inputs
+                            .iter()
+                            .map(|(name, channels)| format!("in  {} ({})", name, channels)),
+                    )
+                    .chain(
+                        devices
+                            .outputs
+                            .iter()
+                            .map(|(name, channels)| format!("out {} ({})", name, channels)),
+                    )
+            })
+            .map(ListItem::new)
+            .collect();
+
+        let title = format!(
+            "Host: {} [{}]",
+            self.patchbay.host(),
+            if self.patchbay.running() {
+                "running"
+            } else {
+                "stopped"
+            }
+        );
+        frame.render_widget(
+            List::new(items).block(Block::default().borders(Borders::ALL).title(title)),
+            area,
+        );
+    }
+
+    fn draw_matrix(&self, frame: &mut Frame, area: Rect, matrix: &Matrix) {
+        let label = |endpoint: &Endpoint| matrix.label(endpoint);
+        let label_width = matrix
+            .sources()
+            .iter()
+            .map(|s| label(s).len())
+            .max()
+            .unwrap_or(0) as u16;
+        let cell_width = 5_u16;
+
+        // scroll so that the selected crosspoint is always visible
+        let visible_rows = area.height.saturating_sub(3).max(1) as usize;
+        let visible_columns =
+            (area.width.saturating_sub(label_width + 3) / (cell_width + 1)).max(1) as usize;
+        let row_offset = self.row.saturating_sub(visible_rows - 1);
+        let column_offset = self.column.saturating_sub(visible_columns - 1);
+        let column_range = column_offset..matrix.sinks().len().min(column_offset + visible_columns);
+
+        let header = Row::new(
+            std::iter::once(Cell::from("")).chain(
+                column_range
+                    .clone()
+                    .map(|c| Cell::from(format!("{:>w$}", c + 1, w = cell_width as usize))),
+            ),
+        );
+
+        let rows: Vec<Row> = matrix
+            .sources()
+            .iter()
+            .enumerate()
+            .skip(row_offset)
+            .take(visible_rows)
+            .map(|(row, source)| {
+                let cells = column_range.clone().map(|column| {
+                    let marker = matrix
+                        .crosspoint(row, column)
+                        .map_or(".".to_string(), |c| c.marker());
+                    let style = if (row, column) == (self.row, self.column) {
+                        Style::default().add_modifier(Modifier::REVERSED)
+                    } else {
+                        Style::default()
+                    };
+                    Cell::from(format!("{:>w$}", marker, w = cell_width as usize)).style(style)
+                });
+                Row::new(std::iter::once(Cell::from(label(source))).chain(cells))
+            })
+            .collect();
+
+        let widths: Vec<Constraint> = std::iter::once(Constraint::Length(label_width))
+            .chain(column_range.map(|_| Constraint::Length(cell_width)))
+            .collect();
+
+        let title = match (
+            matrix.sources().get(self.row),
+            matrix.sinks().get(self.column),
+        ) {
+            (Some(source), Some(sink)) => format!("Matrix: {} -> {}", label(source), label(sink)),
+            _ => "Matrix".to_string(),
+        };
+
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(header)
+                .block(Block::default().borders(Borders::ALL).title(title)),
+            area,
+        );
+    }
+
+    fn draw_connections(&self, frame: &mut Frame, area: Rect) {
+        let mut connections: Vec<_> = self.patchbay.connections().collect();
+        connections.sort_by_key(|(_, c)| {
+            (
+                c.source_name().to_owned(),
+                c.source_channel(),
+                c.sink_name().to_owned(),
+                c.sink_channel(),
+            )
+        });
+
+        let rows: Vec<Row> = connections
+            .iter()
+            .map(|(id, c)| {
+                let stats = c.stats();
+                Row::new(vec![
+                    Cell::from(id.to_string()[..8].to_string()),
+                    Cell::from(format!(
+                        "{}({}) -> {}({})",
+                        c.source_name(),
+                        c.source_channel(),
+                        c.sink_name(),
+                        c.sink_channel()
+                    )),
+                    Cell::from(if c.muted() {
+                        "muted".to_string()
+                    } else {
+                        format!("{:+.1}dB", c.gain_db())
+                    }),
+                    Cell::from(meter(stats.source_peak)),
+                    Cell::from(meter(stats.sink_peak)),
+                    Cell::from(format!("{}/{}", stats.overruns, stats.underruns)),
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Length(8),
+            Constraint::Min(20),
+            Constraint::Length(8),
+            Constraint::Length(METER_WIDTH as u16),
+            Constraint::Length(METER_WIDTH as u16),
+            Constraint::Length(9),
+        ];
+        let header = Row::new(vec!["Id", "Route", "Gain", "In", "Out", "Over/Under"]);
+
+        frame.render_widget(
+            Table::new(rows, widths).header(header).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(Line::from("Connections")),
+            ),
+            area,
+        );
+    }
+}
+
+fn meter(peak: f32) -> String {
+    let db = 20.0 * peak.max(f32::MIN_POSITIVE).log10();
+    let filled = ((1.0 - db / METER_FLOOR_DB).clamp(0.0, 1.0) * METER_WIDTH as f32) as usize;
+    format!("{}{}", "|".repeat(filled), " ".repeat(METER_WIDTH - filled))
+}